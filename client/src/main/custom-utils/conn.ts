@@ -14,7 +14,8 @@ interface SPTFError {
  * will **return** the description
  */
 function handleNonOkHttpResponse(dataBuffer: Buffer, statusCode: number) {
-    if (statusCode != 500) {
+    // 401 when the credential is missing or no longer valid
+    if (statusCode != 500 && statusCode != 401) {
         return "未知错误";
     }
     const data = dataBuffer.toString();
@@ -30,16 +31,64 @@ function handleNonOkHttpResponse(dataBuffer: Buffer, statusCode: number) {
     return handleErrorCode(response_json.errorCode);
 }
 
+/** Either an auth token, or a pending token to complete with a second factor */
+interface LoginResult {
+    authToken?: string,
+    pendingToken?: string,
+}
+
 /** This method may throw */
-async function login(username: string, password: string): Promise<string> {
+async function login(username: string, password: string, rememberMe: boolean): Promise<LoginResult> {
     return new Promise(((resolve, reject) => {
         const req = net.request({
             method: "POST",
             url: `${SERVER_DOMAIN}/login`,
-            session: session.defaultSession
+            session: session.defaultSession,
+            // Keep the cookie the server sets, with the attributes it sets
+            useSessionCookies: true
         });
         req.setHeader("Content-Type", "application/json");
-        req.write(JSON.stringify({username: username, password: password}));
+        req.write(JSON.stringify({username: username, password: password, rememberMe: rememberMe}));
+        req.on("response", (response) => {
+            if (response.statusCode !== 200) {
+                response.on("data", (data) => {
+                    const returnError = handleNonOkHttpResponse(data, response.statusCode);
+                    reject(returnError);
+                })
+            } else {
+                response.on("data", (data) => {
+                    let response;
+                    try {
+                        response = JSON.parse(data.toString());
+                        resolve({authToken: response.authToken, pendingToken: response.pendingToken});
+                    } catch {
+                        reject("网络错误");
+                    }
+                })
+            }
+            response.on("error", () => {
+                reject("网络错误");
+            });
+        });
+        req.on("error", () => {
+          reject("网络错误");
+        })
+        req.end();
+    }));
+}
+
+/** Complete a login with a TOTP code or recovery code, this method may throw */
+async function loginSecondFactor(pendingToken: string, code: string, rememberMe: boolean): Promise<string> {
+    return new Promise(((resolve, reject) => {
+        const req = net.request({
+            method: "POST",
+            url: `${SERVER_DOMAIN}/login/second_factor`,
+            session: session.defaultSession,
+            // Keep the cookie the server sets, with the attributes it sets
+            useSessionCookies: true
+        });
+        req.setHeader("Content-Type", "application/json");
+        req.write(JSON.stringify({pendingToken: pendingToken, code: code, rememberMe: rememberMe}));
         req.on("response", (response) => {
             if (response.statusCode !== 200) {
                 response.on("data", (data) => {
@@ -207,4 +256,4 @@ async function makeDirectory(directoryPath: string): Promise<void> {
     }));
 }
 
-export { login, loginWithCookie, logout, signup, uploadFiles, makeDirectory };
+export { login, loginSecondFactor, loginWithCookie, logout, signup, uploadFiles, makeDirectory };
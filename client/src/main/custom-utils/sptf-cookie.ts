@@ -16,20 +16,8 @@ async function getCookie(): Promise<string | null> {
     }
 }
 
-async function setCookie(authToken: string) {
-    const EXPIRATION_DATE = new Date(2200, 1).getTime() / 1000;
-    const cookie = {
-        name: COOKIE_NAME,
-        value: authToken,
-        url: DESTINATION_URL,
-        session: false,
-        expirationDate: EXPIRATION_DATE
-    };
-    await session.defaultSession.cookies.set(cookie);
-}
-
 async function removeCookie() {
     await session.defaultSession.cookies.remove(DESTINATION_URL, COOKIE_NAME);
 }
 
-export { getCookie, setCookie, removeCookie };
+export { getCookie, removeCookie };
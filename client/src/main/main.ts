@@ -14,8 +14,8 @@ import { autoUpdater } from 'electron-updater';
 import log from 'electron-log';
 import MenuBuilder from './menu';
 import { resolveHtmlPath } from './util';
-import { getCookie, setCookie, removeCookie } from './custom-utils/sptf-cookie';
-import { login, loginWithCookie, logout, signup, uploadFiles, makeDirectory } from './custom-utils/conn';
+import { getCookie, removeCookie } from './custom-utils/sptf-cookie';
+import { login, loginSecondFactor, loginWithCookie, logout, signup, uploadFiles, makeDirectory } from './custom-utils/conn';
 
 const electronDl = require('electron-dl');
 const { download } = require('electron-dl');
@@ -146,12 +146,12 @@ app
   .whenReady()
   .then(() => {
     handleWithCustomErrors('sptf:getCookie', getCookie);
-    handleWithCustomErrors('sptf:setCookie', async (event: any, authToken: string) => {
-      return setCookie(authToken);
-    });
     handleWithCustomErrors('sptf:removeCookie', removeCookie);
-    handleWithCustomErrors('sptf:login', async (event: any, username: string, password: string) => {
-      return login(username, password);
+    handleWithCustomErrors('sptf:login', async (event: any, username: string, password: string, rememberMe: boolean) => {
+      return login(username, password, rememberMe);
+    });
+    handleWithCustomErrors('sptf:loginSecondFactor', async (event: any, pendingToken: string, code: string, rememberMe: boolean) => {
+      return loginSecondFactor(pendingToken, code, rememberMe);
     });
     handleWithCustomErrors('sptf:loginWithCookie', loginWithCookie);
     handleWithCustomErrors('sptf:logout', logout);
@@ -15,9 +15,9 @@ const invokeWithCustomErrors = async (...args) => {
 
 contextBridge.exposeInMainWorld('sptfAPI', {
   getCookie: () => invokeWithCustomErrors('sptf:getCookie'),
-  setCookie: (authToken) => invokeWithCustomErrors('sptf:setCookie', authToken),
   removeCookie: () => invokeWithCustomErrors('sptf:removeCookie'),
-  login: (username, password) => invokeWithCustomErrors('sptf:login', username, password),
+  login: (username, password, rememberMe) => invokeWithCustomErrors('sptf:login', username, password, rememberMe),
+  loginSecondFactor: (pendingToken, code, rememberMe) => invokeWithCustomErrors('sptf:loginSecondFactor', pendingToken, code, rememberMe),
   loginWithCookie: () => invokeWithCustomErrors('sptf:loginWithCookie'),
   logout: () => invokeWithCustomErrors('sptf:logout'),
   signup: (username, password) => invokeWithCustomErrors('sptf:signup', username, password),
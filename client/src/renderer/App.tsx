@@ -25,15 +25,13 @@ const Index = () => {
     setHomepageComponentStatus(HomepageComponentStatus.Login);
   };
   const childComponentSetAuthTokenAndToFileBrowser = (authToken: string) => {
+    // The server already set its cookie, kept in the session by the main process
     setAuthToken(authToken);
-    document.cookie = `SPTF_AUTH=${authToken};samesite=none;expires=${new Date(2200, 1).toUTCString}`;
-    window.sptfAPI.setCookie(authToken);
     setHomepageComponentStatus(HomepageComponentStatus.Filebrowser);
   }
   const onFileBrowserAuthFailed = () => {
     setLoginShouldUseCookie(false);
     window.sptfAPI.removeCookie();
-    document.cookie = `SPTF_AUTH=; Max-Age=0`;
     setAuthToken(null);
     setHomepageComponentStatus(HomepageComponentStatus.Login);
   };
@@ -102,10 +102,40 @@ const waitForOpenConnection = (socket: WebSocket) => {
 }
 
 const WEBSOCKET_URL = "wss://evian-workstation.local:8766";
+const PROTOCOL_VERSION = 2;
 
+/** Echoed by the server in its reply, so replies can be told apart from push notifications */
+let lastRequestId = 0;
+
+function nextRequestId(): number {
+    lastRequestId += 1;
+    return lastRequestId;
+}
+
+/** Token is sent in the first message rather than the url, keeping it out of access logs */
 async function createWebsocket(authToken: string): Promise<WebSocket> {
-    let websocket = new WebSocket(`${WEBSOCKET_URL}/ws?authToken=${authToken}`);
+    let websocket = new WebSocket(`${WEBSOCKET_URL}/ws`);
     await waitForOpenConnection(websocket);
+    const authenticated = new Promise<void>((resolve, reject) => {
+        websocket.onmessage = async (event) => {
+            websocket.onmessage = null;
+            try {
+                await handleWebsocketData(event.data);
+                resolve();
+            } catch (reason) {
+                reject(reason);
+            }
+        };
+    });
+    const data = sptf.BasicIncomingMessage.encode({
+        version: PROTOCOL_VERSION,
+        requestId: nextRequestId(),
+        AuthenticateMessage: {
+            authToken: authToken
+        }
+    }).finish();
+    websocket.send(data);
+    await authenticated;
     return websocket;
 }
 
@@ -120,21 +150,32 @@ async function handleWebsocketData(data: Blob): Promise<sptf.IListDirectoryRespo
     }
     if (basicOutcomingResponse.ListDirectoryResponse) {
         return Promise.resolve(basicOutcomingResponse.ListDirectoryResponse);
+    } else if (basicOutcomingResponse.PushNotification) {
+        const pushNotification = basicOutcomingResponse.PushNotification;
+        if (pushNotification.DirectoryChanged) {
+            return Promise.resolve(pushNotification.DirectoryChanged);
+        } else if (pushNotification.SessionError) {
+            throw handleErrorCode(pushNotification.SessionError.errorCode);
+        }
     } else if (basicOutcomingResponse.GeneralError) {
         const generalError = basicOutcomingResponse.GeneralError;
         throw handleErrorCode(generalError.errorCode);
     }
 }
 
-function requestChangeDir(websocket: WebSocket, target_dir_path: string) {
+/** Returns the request id the response will carry */
+function requestChangeDir(websocket: WebSocket, target_dir_path: string): number {
+    const requestId = nextRequestId();
     const data = sptf.BasicIncomingMessage.encode({
-        version: 1,
+        version: PROTOCOL_VERSION,
+        requestId: requestId,
         ListDirectoryMessage: {
             path: target_dir_path
         }
     }).finish();
 
     websocket.send(data);
+    return requestId;
 }
 
 async function downloadFiles(authToken: string, filePaths: string[]) {
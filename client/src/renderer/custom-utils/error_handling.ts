@@ -9,6 +9,55 @@ function handleErrorCode(error_code: number): string {
         case 0x6: return "您的权限不够";
         case 0x7: return "文件传输格式错误";
         case 0x8: return "用户名已存在";
+        case 0x9: return "用户名或密码不正确";
+        case 0xa: return "登录尝试过于频繁，请稍后再试";
+        case 0xb: return "账户已被临时锁定";
+        case 0xc: return "需要管理员权限";
+        case 0xd: return "验证码不正确";
+        case 0xe: return "两步验证已启用";
+        case 0xf: return "两步验证未启用";
+        case 0x10: return "登录已过期，请重新输入密码";
+        case 0x11: return "令牌权限范围无效";
+        case 0x12: return "令牌权限范围不足";
+        case 0x13: return "令牌无权访问该路径";
+        case 0x14: return "该操作需要登录";
+        case 0x15: return "令牌不存在";
+        case 0x16: return "令牌已过期";
+        case 0x17: return "会话不存在";
+        case 0x18: return "用户不存在";
+        case 0x19: return "重置链接无效或已过期";
+        case 0x1a: return "只读用户无法修改文件";
+        case 0x1b: return "账户已被禁用";
+        case 0x1c: return "角色无效";
+        case 0x1d: return "不能修改自己的账户";
+        case 0x1e: return "注册已关闭";
+        case 0x1f: return "邀请码无效或已用完";
+        case 0x20: return "邀请码不存在";
+        case 0x21: return "超出存储配额";
+        case 0x22: return "用户名长度不符合要求";
+        case 0x23: return "用户名包含不允许的字符";
+        case 0x24: return "密码长度不符合要求";
+        case 0x25: return "密码强度不足";
+        case 0x26: return "该密码已在泄露密码库中，请更换";
+        case 0x28: return "未配置统一身份认证";
+        case 0x29: return "登录请求已过期，请重试";
+        case 0x2a: return "身份提供方返回错误";
+        case 0x2b: return "身份令牌无效";
+        case 0x2c: return "该身份尚未关联账户";
+        case 0x2d: return "该身份已关联其他账户";
+        case 0x2e: return "目录服务不可用";
+        case 0x2f: return "该用户不属于任何有权限的组";
+        case 0x30: return "未启用客户端证书认证";
+        case 0x31: return "未提供客户端证书";
+        case 0x32: return "客户端证书中没有用户名";
+        case 0x33: return "客户端证书与用户不匹配";
+        case 0x34: return "需要同时提供证书和密码";
+        case 0x35: return "请求来源不受信任";
+        case 0x36: return "文件过大";
+        case 0x37: return "文件不存在";
+        case 0x38: return "路径无效";
+        case 0x39: return "目标已存在";
+        case 0x3a: return "订阅的目录过多";
         default: return "未知错误";
     }
 }
@@ -21,7 +70,8 @@ interface SPTFError {
  * will **throw** the description
  */
 async function handleNonOkHttpResponse(response: Response) {
-    if (response.status != 500) {
+    // 401 when the credential is missing or no longer valid
+    if (response.status != 500 && response.status != 401) {
         throw "未知错误";
     }
     let response_json: SPTFError;
@@ -3,10 +3,11 @@ import {
   Form,
   Input,
   Button,
+  Checkbox,
   Modal,
   message
 } from 'antd';
-import { UserOutlined, LockOutlined } from '@ant-design/icons';
+import { UserOutlined, LockOutlined, SafetyOutlined } from '@ant-design/icons';
 
 const { Item: FormItem } = Form;
 
@@ -25,12 +26,14 @@ enum LoginValidationStatus {
 function Login(props: LoginProps) {
   const [validating, setValidating] = useState(LoginValidationStatus.NoLogin);
   const [loginForm] = Form.useForm();
+  // Set once the password is accepted but a second factor is required
+  const [pendingToken, setPendingToken] = useState<string | null>(null);
+  const [secondFactorForm] = Form.useForm();
   useEffect(() => {
     if (props.loginShouldUseCookie) {
       window.sptfAPI.getCookie()
         .then((authToken) => {
           if (authToken) {
-            document.cookie = `SPTF_AUTH=${authToken};samesite=none;expires=${new Date(2200, 1).toUTCString}`;
             setValidating(LoginValidationStatus.Validating);
             window.sptfAPI.loginWithCookie()
               .then((isSuccess) => {
@@ -54,17 +57,49 @@ function Login(props: LoginProps) {
     setValidating(LoginValidationStatus.Validating);
     const username = loginForm.getFieldValue("username");
     const password = loginForm.getFieldValue("password");
-    window.sptfAPI.login(username, password)
+    const rememberMe = Boolean(loginForm.getFieldValue("rememberMe"));
+    window.sptfAPI.login(username, password, rememberMe)
+      .then(({authToken, pendingToken}) => {
+        setValidating(LoginValidationStatus.NoLogin);
+        if (authToken) {
+          props.setAuthTokenAndToFileBrowser(authToken);
+        } else if (pendingToken) {
+          setPendingToken(pendingToken);
+        } else {
+          setValidating(LoginValidationStatus.Invalid);
+        }
+      })
+      .catch((reason) => {
+        setValidating(LoginValidationStatus.Invalid);
+        message.error(reason);
+      });
+  }
+
+  function onSecondFactorFinish() {
+    if (!pendingToken) {
+      return;
+    }
+    setValidating(LoginValidationStatus.Validating);
+    const code = secondFactorForm.getFieldValue("code");
+    const rememberMe = Boolean(loginForm.getFieldValue("rememberMe"));
+    window.sptfAPI.loginSecondFactor(pendingToken, code, rememberMe)
       .then((authToken) => {
         setValidating(LoginValidationStatus.NoLogin);
+        setPendingToken(null);
         props.setAuthTokenAndToFileBrowser(authToken);
       })
       .catch((reason) => {
-        setValidating(LoginValidationStatus.Invalid);
+        setValidating(LoginValidationStatus.NoLogin);
+        secondFactorForm.resetFields();
         message.error(reason);
       });
   }
 
+  function onSecondFactorCancelled() {
+    setPendingToken(null);
+    secondFactorForm.resetFields();
+  }
+
   function onGoToSignupPageButtonPressed() {
     props.toSignup();
   }
@@ -95,6 +130,10 @@ function Login(props: LoginProps) {
           />
         </Form.Item>
 
+        <Form.Item name="rememberMe" valuePropName="checked">
+          <Checkbox>记住我</Checkbox>
+        </Form.Item>
+
         <Form.Item>
           <Button type="primary" htmlType="submit" style={{width: "100%"}}>
             登录
@@ -103,6 +142,29 @@ function Login(props: LoginProps) {
         </Form.Item>
       </Form>
 
+      <Modal
+        visible={pendingToken !== null && validating === LoginValidationStatus.NoLogin}
+        onOk={() => secondFactorForm.submit()}
+        onCancel={onSecondFactorCancelled}
+        centered
+        okText={"确认"}
+        cancelText={"取消"}
+        maskClosable={false}
+      >
+        <Form layout='vertical' form={secondFactorForm} onFinish={onSecondFactorFinish}>
+          <FormItem
+            name="code"
+            label="请输入验证器中的验证码，或一个恢复码"
+            rules={[{ required: true, message: '验证码不得为空' }]}
+          >
+            <Input
+              prefix={<SafetyOutlined className="site-form-item-icon" />}
+              autoComplete="one-time-code"
+              placeholder="验证码"
+            />
+          </FormItem>
+        </Form>
+      </Modal>
       <Modal
         visible={validating === LoginValidationStatus.Validating}
         centered
@@ -2,9 +2,9 @@ declare global {
     interface Window {
         sptfAPI: {
             getCookie: () => Promise<string | null>,
-            setCookie: (authToken: string) => Promise<void>,
             removeCookie: () => Promise<void>,
-            login: (username: string, password: string) => Promise<string>,
+            login: (username: string, password: string, rememberMe: boolean) => Promise<{authToken?: string, pendingToken?: string}>,
+            loginSecondFactor: (pendingToken: string, code: string, rememberMe: boolean) => Promise<string>,
             loginWithCookie: () => Promise<boolean>,
             logout: () => Promise<void>,
             signup: (username: string, password: string) => Promise<string>,
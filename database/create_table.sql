@@ -1,7 +0,0 @@
-CREATE TABLE Users
-(
-id UUID,
-username varchar(64),
-salt bytea,
-password bytea
-)
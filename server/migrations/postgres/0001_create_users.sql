@@ -0,0 +1,13 @@
+CREATE TABLE Users
+(
+id UUID PRIMARY KEY,
+username varchar(64) NOT NULL,
+salt bytea NOT NULL,
+password bytea NOT NULL,
+role varchar(16) NOT NULL DEFAULT 'member',
+disabled boolean NOT NULL DEFAULT false,
+quota_bytes bigint,
+used_bytes bigint NOT NULL DEFAULT 0
+);
+
+CREATE UNIQUE INDEX users_username_key ON Users (lower(username));
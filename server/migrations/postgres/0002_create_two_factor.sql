@@ -0,0 +1,12 @@
+CREATE TABLE TotpSecrets
+(
+user_id UUID,
+secret bytea,
+confirmed boolean
+);
+
+CREATE TABLE RecoveryCodes
+(
+user_id UUID,
+code_hash bytea
+);
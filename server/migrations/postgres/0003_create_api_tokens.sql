@@ -0,0 +1,12 @@
+CREATE TABLE ApiTokens
+(
+id UUID PRIMARY KEY,
+user_id UUID NOT NULL,
+name varchar(64),
+token_hash bytea NOT NULL UNIQUE,
+scopes varchar(16)[],
+path_prefix text,
+created_at bigint,
+expires_at bigint,
+last_used_at bigint
+);
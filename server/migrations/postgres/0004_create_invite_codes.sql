@@ -0,0 +1,11 @@
+CREATE TABLE InviteCodes
+(
+id UUID PRIMARY KEY,
+code_hash bytea NOT NULL UNIQUE,
+created_by UUID,
+role varchar(16),
+quota_bytes bigint,
+max_uses integer,
+uses integer NOT NULL DEFAULT 0,
+created_at bigint
+);
@@ -0,0 +1,8 @@
+CREATE TABLE OidcIdentities
+(
+issuer text NOT NULL,
+subject text NOT NULL,
+user_id UUID NOT NULL,
+created_at bigint,
+PRIMARY KEY (issuer, subject)
+);
@@ -0,0 +1 @@
+ALTER TABLE Users ADD COLUMN auth_source varchar(16) NOT NULL DEFAULT 'local';
@@ -0,0 +1,14 @@
+CREATE TABLE AuditLog
+(
+id bigserial PRIMARY KEY,
+user_id UUID,
+username varchar(64),
+ip varchar(45) NOT NULL,
+action varchar(64) NOT NULL,
+path text,
+outcome varchar(16) NOT NULL,
+error_code integer,
+created_at bigint NOT NULL
+);
+CREATE INDEX AuditLog_created_at ON AuditLog (created_at);
+CREATE INDEX AuditLog_username ON AuditLog (lower(username), created_at);
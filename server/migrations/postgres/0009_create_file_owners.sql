@@ -0,0 +1,7 @@
+CREATE TABLE FileOwners
+(
+path text PRIMARY KEY,
+user_id UUID NOT NULL,
+size_bytes bigint NOT NULL
+);
+CREATE INDEX FileOwners_user_id ON FileOwners (user_id);
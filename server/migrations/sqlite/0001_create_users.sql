@@ -0,0 +1,13 @@
+CREATE TABLE Users
+(
+id TEXT PRIMARY KEY,
+username TEXT NOT NULL,
+salt BLOB NOT NULL,
+password BLOB NOT NULL,
+role TEXT NOT NULL DEFAULT 'member',
+disabled INTEGER NOT NULL DEFAULT 0,
+quota_bytes INTEGER,
+used_bytes INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE UNIQUE INDEX users_username_key ON Users (lower(username));
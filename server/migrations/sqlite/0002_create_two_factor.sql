@@ -0,0 +1,12 @@
+CREATE TABLE TotpSecrets
+(
+user_id TEXT,
+secret BLOB,
+confirmed INTEGER
+);
+
+CREATE TABLE RecoveryCodes
+(
+user_id TEXT,
+code_hash BLOB
+);
@@ -0,0 +1,13 @@
+CREATE TABLE ApiTokens
+(
+id TEXT PRIMARY KEY,
+user_id TEXT NOT NULL,
+name TEXT,
+token_hash BLOB NOT NULL UNIQUE,
+-- JSON array of scope names
+scopes TEXT,
+path_prefix TEXT,
+created_at INTEGER,
+expires_at INTEGER,
+last_used_at INTEGER
+);
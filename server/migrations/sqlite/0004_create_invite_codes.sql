@@ -0,0 +1,11 @@
+CREATE TABLE InviteCodes
+(
+id TEXT PRIMARY KEY,
+code_hash BLOB NOT NULL UNIQUE,
+created_by TEXT,
+role TEXT,
+quota_bytes INTEGER,
+max_uses INTEGER,
+uses INTEGER NOT NULL DEFAULT 0,
+created_at INTEGER
+);
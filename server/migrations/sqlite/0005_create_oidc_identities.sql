@@ -0,0 +1,8 @@
+CREATE TABLE OidcIdentities
+(
+issuer TEXT NOT NULL,
+subject TEXT NOT NULL,
+user_id TEXT NOT NULL,
+created_at INTEGER,
+PRIMARY KEY (issuer, subject)
+);
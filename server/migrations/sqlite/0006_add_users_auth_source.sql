@@ -0,0 +1 @@
+ALTER TABLE Users ADD COLUMN auth_source TEXT NOT NULL DEFAULT 'local';
@@ -0,0 +1,14 @@
+CREATE TABLE AuditLog
+(
+id INTEGER PRIMARY KEY AUTOINCREMENT,
+user_id TEXT,
+username TEXT,
+ip TEXT NOT NULL,
+action TEXT NOT NULL,
+path TEXT,
+outcome TEXT NOT NULL,
+error_code INTEGER,
+created_at INTEGER NOT NULL
+);
+CREATE INDEX AuditLog_created_at ON AuditLog (created_at);
+CREATE INDEX AuditLog_username ON AuditLog (lower(username), created_at);
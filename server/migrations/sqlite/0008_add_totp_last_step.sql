@@ -0,0 +1 @@
+ALTER TABLE TotpSecrets ADD COLUMN last_step INTEGER;
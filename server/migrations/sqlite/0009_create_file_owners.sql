@@ -0,0 +1,7 @@
+CREATE TABLE FileOwners
+(
+path TEXT PRIMARY KEY,
+user_id TEXT NOT NULL,
+size_bytes INTEGER NOT NULL
+);
+CREATE INDEX FileOwners_user_id ON FileOwners (user_id);
@@ -0,0 +1 @@
+ALTER TABLE InviteCodes ADD COLUMN last_used_by TEXT;
@@ -0,0 +1,210 @@
+use crate::auth::{Credential, Scope};
+use crate::common::unix_timestamp;
+use crate::database::{Database, DatabaseError};
+use crate::error::{ApiTokenError, SPTFError, UnexpectedError};
+use log::error;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Every API token starts with this, which tells them apart from auth tokens
+pub const API_TOKEN_PREFIX: &str = "sptf_";
+
+/// API token as shown to its owner, without the secret part
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub path_prefix: Option<String>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+}
+
+/// Tokens are random enough that a plain hash suffices
+fn hash_token(token: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(token);
+    hasher.finalize().to_vec()
+}
+
+fn parse_scopes(scopes: &[String]) -> Result<Vec<Scope>, Box<dyn SPTFError>> {
+    scopes
+        .iter()
+        .map(|scope| {
+            Scope::from_name(scope).ok_or_else(|| ApiTokenError::InvalidScope.to_boxed_self())
+        })
+        .collect()
+}
+
+/// Create an API token for given user.
+///
+/// Return the token, which is only stored hashed and cannot be shown again
+pub async fn create_api_token(
+    database: &dyn Database,
+    user_id: Uuid,
+    name: &str,
+    scopes: &[String],
+    path_prefix: Option<&str>,
+    expires_at: Option<i64>,
+) -> Result<String, Box<dyn SPTFError>> {
+    let scopes = parse_scopes(scopes)?
+        .into_iter()
+        .map(|scope| scope.name().to_owned())
+        .collect::<Vec<_>>();
+    if scopes.is_empty() {
+        return Err(ApiTokenError::InvalidScope.to_boxed_self());
+    }
+    let path_prefix = match path_prefix {
+        Some(path_prefix) => Some(
+            crate::auth::normalize_path(&PathBuf::from(path_prefix))
+                .ok_or_else(|| ApiTokenError::PathNotAllowed.to_boxed_self())?
+                .to_string_lossy()
+                .to_string(),
+        ),
+        None => None,
+    };
+    let id = Uuid::new_v4();
+    let token = format!(
+        "{}{}{}",
+        API_TOKEN_PREFIX,
+        Uuid::new_v4().to_simple(),
+        Uuid::new_v4().to_simple()
+    );
+    database
+        .execute(
+            "INSERT INTO ApiTokens (id, user_id, name, token_hash, scopes, path_prefix, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            &[
+                id.into(),
+                user_id.into(),
+                name.into(),
+                hash_token(&token).into(),
+                scopes.into(),
+                path_prefix.into(),
+                unix_timestamp().into(),
+                expires_at.into(),
+            ],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to create api token for {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    Ok(token)
+}
+
+/// List API tokens of given user
+pub async fn list_api_tokens(
+    database: &dyn Database,
+    user_id: Uuid,
+) -> Result<Vec<ApiTokenInfo>, Box<dyn SPTFError>> {
+    let rows = database
+        .query(
+            "SELECT id, name, scopes, path_prefix, created_at, expires_at, last_used_at FROM ApiTokens WHERE user_id=$1 ORDER BY created_at",
+            &[user_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Query api tokens of {} failed: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    rows.iter()
+        .map(|row| {
+            let id: Uuid = row.try_get(0)?;
+            Ok(ApiTokenInfo {
+                id: id.to_string(),
+                name: row.try_get(1)?,
+                scopes: row.try_get(2)?,
+                path_prefix: row.try_get(3)?,
+                created_at: row.try_get(4)?,
+                expires_at: row.try_get(5)?,
+                last_used_at: row.try_get(6)?,
+            })
+        })
+        .collect::<Result<Vec<_>, DatabaseError>>()
+        .map_err(|err| {
+            error!("Fetch api token fields failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        })
+}
+
+/// Revoke API token of given user
+pub async fn revoke_api_token(
+    database: &dyn Database,
+    user_id: Uuid,
+    token_id_str: &str,
+) -> Result<Uuid, Box<dyn SPTFError>> {
+    let token_id =
+        Uuid::parse_str(token_id_str).map_err(|_| ApiTokenError::NotFound.to_boxed_self())?;
+    let revoked = database
+        .execute(
+            "DELETE FROM ApiTokens WHERE id=$1 AND user_id=$2",
+            &[token_id.into(), user_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to revoke api token {}: {}", token_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    if revoked == 0 {
+        return Err(ApiTokenError::NotFound.to_boxed_self());
+    }
+    Ok(token_id)
+}
+
+/// Validate user given API token
+///
+/// Return user-id and the credential of the token
+pub async fn validate_api_token(
+    database: &dyn Database,
+    token: &str,
+) -> Result<(Uuid, Credential), Box<dyn SPTFError>> {
+    let row = database
+        .query_opt(
+            "SELECT id, user_id, scopes, path_prefix, expires_at FROM ApiTokens WHERE token_hash=$1",
+            &[hash_token(token).into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Query api token failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?
+        .ok_or_else(|| ApiTokenError::NotFound.to_boxed_self())?;
+    let fetch_error = |err: DatabaseError| {
+        error!("Fetch api token fields failed: {}", err);
+        UnexpectedError.to_boxed_self()
+    };
+    let token_id: Uuid = row.try_get(0).map_err(fetch_error)?;
+    let user_id: Uuid = row.try_get(1).map_err(fetch_error)?;
+    let scopes: Vec<String> = row.try_get(2).map_err(fetch_error)?;
+    let path_prefix: Option<String> = row.try_get(3).map_err(fetch_error)?;
+    let expires_at: Option<i64> = row.try_get(4).map_err(fetch_error)?;
+    let now = unix_timestamp();
+    if matches!(expires_at, Some(expires_at) if expires_at <= now) {
+        return Err(ApiTokenError::Expired.to_boxed_self());
+    }
+    database
+        .execute(
+            "UPDATE ApiTokens SET last_used_at=$1 WHERE id=$2",
+            &[now.into(), token_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!(
+                "Failed to update last use of api token {}: {}",
+                token_id, err
+            );
+            UnexpectedError.to_boxed_self()
+        })?;
+    Ok((
+        user_id,
+        Credential::ApiToken {
+            token_id,
+            scopes: parse_scopes(&scopes)?,
+            path_prefix: path_prefix.map(PathBuf::from),
+        },
+    ))
+}
@@ -0,0 +1,430 @@
+use crate::common::{
+    unix_timestamp, AUDIT_LOG_DEFAULT_LIMIT, AUDIT_LOG_MAX_LIMIT, USERNAME_MAX_LENGTH,
+};
+use crate::database::{escape_like, Database, DatabaseError, SqlValue};
+use crate::error::{ResponseErrorCode, SPTFError, UnexpectedError};
+use crate::{auth::AuthenticatedUser, AppData};
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error, HttpMessage, HttpRequest,
+};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Routes not recorded by `AuditLog`, because what happens there is
+/// recorded elsewhere
+const UNAUDITED_ROUTES: &[&str] = &[
+    // Websocket sessions record their own requests
+    "/ws",
+];
+
+/// Actions recorded for requests of websockets, named like the matching
+/// routes where there are any
+pub const LIST_DIRECTORY_ACTION: &str = "list_directory";
+pub const MAKE_DIRECTORY_ACTION: &str = "make_directory";
+pub const STAT_ACTION: &str = "stat";
+pub const UPLOAD_ACTION: &str = "upload";
+pub const READ_FILE_ACTION: &str = "read_file";
+pub const DELETE_ACTION: &str = "delete";
+pub const RENAME_ACTION: &str = "rename";
+pub const SUBSCRIBE_ACTION: &str = "subscribe";
+pub const UNSUBSCRIBE_ACTION: &str = "unsubscribe";
+
+/// One action, as written to the audit log
+pub struct AuditEntry {
+    /// None if the user is not known, like on a failed login
+    pub user_id: Option<Uuid>,
+    /// Looked up by user id if None
+    pub username: Option<String>,
+    pub ip: String,
+    /// Route of the request without the leading slash, like "upload"
+    pub action: String,
+    /// File path the action is about, if any
+    pub path: Option<String>,
+    /// None if the action succeeded
+    pub error_code: Option<usize>,
+}
+
+/// Recorded action as shown to admins
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    pub id: i64,
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+    pub ip: String,
+    pub action: String,
+    pub path: Option<String>,
+    /// Either "success" or "failure"
+    pub outcome: String,
+    pub error_code: Option<i32>,
+    pub created_at: i64,
+}
+
+/// Which records to list, all given conditions must hold
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AuditFilter {
+    pub username: Option<String>,
+    /// Only actions on this path or below
+    pub path_prefix: Option<String>,
+    pub action: Option<String>,
+    /// Unix timestamp, inclusive
+    pub from: Option<i64>,
+    /// Unix timestamp, exclusive
+    pub to: Option<i64>,
+    /// Id of the last record of the previous page, as records are listed
+    /// newest first
+    pub before_id: Option<i64>,
+    /// AUDIT_LOG_DEFAULT_LIMIT if omitted, capped at AUDIT_LOG_MAX_LIMIT
+    pub limit: Option<i64>,
+}
+
+/// Write an action to the audit log.
+///
+/// Failing to do so is logged but does not fail the action itself
+pub async fn record(database: &dyn Database, entry: AuditEntry) {
+    let username = entry.username.map(|username| {
+        username
+            .chars()
+            .take(USERNAME_MAX_LENGTH)
+            .collect::<String>()
+    });
+    let outcome = if entry.error_code.is_some() {
+        "failure"
+    } else {
+        "success"
+    };
+    if let Err(err) = database
+        .execute(
+            "INSERT INTO AuditLog (user_id, username, ip, action, path, outcome, error_code, created_at) VALUES ($1, COALESCE($2, (SELECT username FROM Users WHERE id=$1)), $3, $4, $5, $6, $7, $8)",
+            &[
+                entry.user_id.into(),
+                username.into(),
+                entry.ip.into(),
+                entry.action.as_str().into(),
+                entry.path.into(),
+                outcome.into(),
+                entry.error_code.map(|error_code| error_code as i64).into(),
+                unix_timestamp().into(),
+            ],
+        )
+        .await
+    {
+        error!("Failed to record {} in audit log: {}", entry.action, err);
+    }
+}
+
+/// List records matching given filter, newest first
+pub async fn list_records(
+    database: &dyn Database,
+    filter: &AuditFilter,
+) -> Result<Vec<AuditRecord>, Box<dyn SPTFError>> {
+    let mut conditions = Vec::new();
+    let mut params = Vec::<SqlValue>::new();
+    // Each `?` of the condition stands for the next of its params
+    let mut add_condition = |condition: &str, condition_params: Vec<SqlValue>| {
+        let mut condition = condition.to_owned();
+        for param in condition_params {
+            params.push(param);
+            condition = condition.replacen('?', &format!("${}", params.len()), 1);
+        }
+        conditions.push(condition);
+    };
+    if let Some(username) = &filter.username {
+        add_condition(
+            "lower(username)=lower(?)",
+            vec![crate::policy::normalize_username(username).into()],
+        );
+    }
+    if let Some(path_prefix) = &filter.path_prefix {
+        // "/a" covers "/a" and "/a/b" but not "/ab", and "/" covers everything
+        let path_prefix = path_prefix.trim_end_matches('/');
+        add_condition(
+            "(path=? OR path LIKE ? ESCAPE '\\')",
+            vec![
+                path_prefix.into(),
+                format!("{}/%", escape_like(path_prefix)).into(),
+            ],
+        );
+    }
+    if let Some(action) = &filter.action {
+        add_condition("action=?", vec![action.as_str().into()]);
+    }
+    if let Some(from) = filter.from {
+        add_condition("created_at>=?", vec![from.into()]);
+    }
+    if let Some(to) = filter.to {
+        add_condition("created_at<?", vec![to.into()]);
+    }
+    if let Some(before_id) = filter.before_id {
+        add_condition("id<?", vec![before_id.into()]);
+    }
+    let limit = filter
+        .limit
+        .unwrap_or(AUDIT_LOG_DEFAULT_LIMIT)
+        .clamp(1, AUDIT_LOG_MAX_LIMIT);
+    let where_clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    let rows = database
+        .query(
+            &format!(
+                "SELECT id, user_id, username, ip, action, path, outcome, error_code, created_at FROM AuditLog {} ORDER BY id DESC LIMIT {}",
+                where_clause, limit
+            ),
+            &params,
+        )
+        .await
+        .map_err(|err| {
+            error!("Query audit log failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    rows.iter()
+        .map(|row| {
+            let user_id: Option<Uuid> = row.try_get(1)?;
+            Ok(AuditRecord {
+                id: row.try_get(0)?,
+                user_id: user_id.map(|user_id| user_id.to_string()),
+                username: row.try_get(2)?,
+                ip: row.try_get(3)?,
+                action: row.try_get(4)?,
+                path: row.try_get(5)?,
+                outcome: row.try_get(6)?,
+                error_code: row.try_get(7)?,
+                created_at: row.try_get(8)?,
+            })
+        })
+        .collect::<Result<Vec<_>, DatabaseError>>()
+        .map_err(|err| {
+            error!("Fetch audit log fields failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        })
+}
+
+/// Delete records older than given number of days
+pub async fn prune(database: &dyn Database, retention_in_days: u32) {
+    let oldest_kept = unix_timestamp() - i64::from(retention_in_days) * 24 * 60 * 60;
+    match database
+        .execute(
+            "DELETE FROM AuditLog WHERE created_at<$1",
+            &[oldest_kept.into()],
+        )
+        .await
+    {
+        Ok(0) => {}
+        Ok(pruned) => info!("Pruned {} audit log records", pruned),
+        Err(err) => error!("Failed to prune audit log: {}", err),
+    }
+}
+
+/// Details of an action the handler knows better than `AuditLog`
+#[derive(Default)]
+struct AuditDetails {
+    user_id: Option<Uuid>,
+    username: Option<String>,
+    path: Option<String>,
+}
+
+fn with_details(req: &HttpRequest, update: impl FnOnce(&mut AuditDetails)) {
+    let mut extensions = req.extensions_mut();
+    if extensions.get::<AuditDetails>().is_none() {
+        extensions.insert(AuditDetails::default());
+    }
+    if let Some(details) = extensions.get_mut::<AuditDetails>() {
+        update(details);
+    }
+}
+
+/// Record the file path given request acts on
+pub fn set_path(req: &HttpRequest, path: impl Into<String>) {
+    let path = path.into();
+    with_details(req, |details| details.path = Some(path));
+}
+
+/// Record who a request on a public route acts as, once known
+pub fn set_user(req: &HttpRequest, user_id: Uuid) {
+    with_details(req, |details| details.user_id = Some(user_id));
+}
+
+/// Record the username a request on a public route claims, like the one
+/// of a login attempt
+pub fn set_username(req: &HttpRequest, username: &str) {
+    let username = username.to_owned();
+    with_details(req, |details| details.username = Some(username));
+}
+
+/// Records actions outside of requests, like those of websockets
+#[derive(Clone)]
+pub struct AuditRecorder {
+    database: Arc<dyn Database>,
+    ip: String,
+}
+
+impl AuditRecorder {
+    pub fn new(database: Arc<dyn Database>, ip: String) -> Self {
+        Self { database, ip }
+    }
+
+    /// Record in the background, without holding up the caller
+    pub fn record(
+        &self,
+        user_id: Uuid,
+        action: &str,
+        path: Option<String>,
+        error_code: Option<usize>,
+    ) {
+        let database = self.database.clone();
+        let entry = AuditEntry {
+            user_id: Some(user_id),
+            username: None,
+            ip: self.ip.clone(),
+            action: action.to_owned(),
+            path,
+            error_code,
+        };
+        actix_web::rt::spawn(async move { record(database.as_ref(), entry).await });
+    }
+}
+
+/// Middleware recording every request to the audit log once handled, with
+/// its user, outcome and details left by the handler
+pub struct AuditLog;
+
+impl<S, B> Transform<S, ServiceRequest> for AuditLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AuditLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuditLogMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuditLogMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuditLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            if UNAUDITED_ROUTES.contains(&req.path()) {
+                return service.call(req).await;
+            }
+            let res = service.call(req).await?;
+            let req = res.request();
+            let app_data = match req.app_data::<web::Data<AppData>>() {
+                Some(app_data) => app_data,
+                None => {
+                    error!("App data is missing");
+                    return Ok(res);
+                }
+            };
+            let details = req
+                .extensions_mut()
+                .remove::<AuditDetails>()
+                .unwrap_or_default();
+            let user_id = details.user_id.or_else(|| {
+                req.extensions()
+                    .get::<Rc<AuthenticatedUser>>()
+                    .map(|authenticated_user| authenticated_user.user_id)
+            });
+            let error_code = match res.response().extensions().get::<ResponseErrorCode>() {
+                Some(ResponseErrorCode(error_code)) => Some(*error_code),
+                None if res.status().is_success() || res.status().is_redirection() => None,
+                // Rejected by actix itself, like a malformed body
+                None => Some(UnexpectedError.error_code()),
+            };
+            let action = req.match_pattern().unwrap_or_else(|| req.path().to_owned());
+            record(
+                app_data.database.as_ref(),
+                AuditEntry {
+                    user_id,
+                    username: details.username,
+                    ip: crate::client_ip(req),
+                    action: action.trim_start_matches('/').to_owned(),
+                    path: details.path,
+                    error_code,
+                },
+            )
+            .await;
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::{list_records, record, AuditEntry, AuditFilter};
+    use crate::database::Database;
+    use crate::sqlite_database::SqliteDatabase;
+    use std::path::Path;
+
+    #[actix_web::test]
+    async fn path_prefix_matches_whole_path_components() {
+        let database = SqliteDatabase::new(Path::new(":memory:"));
+        database.run_migrations().await.unwrap();
+        for path in ["/a", "/a/b", "/ab", "/a_c", "/%"] {
+            record(
+                &database,
+                AuditEntry {
+                    user_id: None,
+                    username: Some("alice".to_owned()),
+                    ip: "127.0.0.1".to_owned(),
+                    action: "upload".to_owned(),
+                    path: Some(path.to_owned()),
+                    error_code: None,
+                },
+            )
+            .await;
+        }
+        let paths_below = |path_prefix: &str| {
+            let filter = AuditFilter {
+                path_prefix: Some(path_prefix.to_owned()),
+                ..Default::default()
+            };
+            let database = &database;
+            async move {
+                let mut paths = list_records(database, &filter)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|record| record.path.unwrap())
+                    .collect::<Vec<_>>();
+                paths.sort();
+                paths
+            }
+        };
+        assert_eq!(paths_below("/a").await, ["/a", "/a/b"]);
+        assert_eq!(paths_below("/a/").await, ["/a", "/a/b"]);
+        assert_eq!(paths_below("/a_").await, Vec::<String>::new());
+        assert_eq!(paths_below("/%").await, ["/%"]);
+        assert_eq!(paths_below("/").await.len(), 5);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::error::{ApiTokenError, SPTFError, ValidateError};
+use std::path::{Component, Path, PathBuf};
+use uuid::Uuid;
+
+/// Permission granted to an API token
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// List and download files
+    Read,
+    /// Upload files and make directories
+    Write,
+    /// Administrate the server, if the user is an admin
+    Admin,
+}
+
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(Scope::Read),
+            "write" => Some(Scope::Write),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Role of a user, deciding what it may do at all
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Everything, including managing users
+    Admin,
+    /// Read and write files
+    Member,
+    /// Only list and download files
+    ReadOnly,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Member => "member",
+            Role::ReadOnly => "read-only",
+        }
+    }
+
+    pub fn from_name(role: &str) -> Option<Self> {
+        match role {
+            "admin" => Some(Role::Admin),
+            "member" => Some(Role::Member),
+            "read-only" => Some(Role::ReadOnly),
+            _ => None,
+        }
+    }
+
+    /// Make sure this role may do what given scope covers
+    pub fn require_scope(&self, scope: Scope) -> Result<(), Box<dyn SPTFError>> {
+        match (self, scope) {
+            (_, Scope::Read) | (Role::Admin, _) | (Role::Member, Scope::Write) => Ok(()),
+            (_, Scope::Admin) => Err(ValidateError::NotAdmin.to_boxed_self()),
+            (Role::ReadOnly, Scope::Write) => Err(ValidateError::ReadOnly.to_boxed_self()),
+        }
+    }
+}
+
+/// Where the password of a user is checked
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AuthSource {
+    /// Password hash stored in Users
+    Local,
+    /// Shadow user of an LDAP directory
+    Ldap,
+    /// Provisioned from an OpenID Connect provider, without a password
+    Oidc,
+}
+
+impl AuthSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthSource::Local => "local",
+            AuthSource::Ldap => "ldap",
+            AuthSource::Oidc => "oidc",
+        }
+    }
+}
+
+/// How a request proved who it is
+pub enum Credential {
+    /// Auth token returned by /login, granting everything the user can do
+    Session { auth_token: String },
+    /// Personal access token, restricted to its scopes and path
+    ApiToken {
+        token_id: Uuid,
+        scopes: Vec<Scope>,
+        path_prefix: Option<PathBuf>,
+    },
+}
+
+/// User of a validated request
+pub struct AuthenticatedUser {
+    pub user_id: Uuid,
+    pub role: Role,
+    pub credential: Credential,
+}
+
+impl AuthenticatedUser {
+    /// Auth token of the login session.
+    ///
+    /// Fails for API tokens, which must not manage credentials
+    pub fn require_session(&self) -> Result<&str, Box<dyn SPTFError>> {
+        match &self.credential {
+            Credential::Session { auth_token } => Ok(auth_token),
+            Credential::ApiToken { .. } => Err(ApiTokenError::SessionRequired.to_boxed_self()),
+        }
+    }
+
+    /// Auth token of a login session or id of an API token, telling apart
+    /// the credentials of the user
+    pub fn credential_id(&self) -> String {
+        match &self.credential {
+            Credential::Session { auth_token } => auth_token.clone(),
+            Credential::ApiToken { token_id, .. } => token_id.to_string(),
+        }
+    }
+
+    /// Make sure both the role of the user and the credential allow given scope
+    pub fn require_scope(&self, scope: Scope) -> Result<(), Box<dyn SPTFError>> {
+        self.role.require_scope(scope)?;
+        match &self.credential {
+            Credential::Session { .. } => Ok(()),
+            Credential::ApiToken { scopes, .. } if scopes.contains(&scope) => Ok(()),
+            Credential::ApiToken { .. } => Err(ApiTokenError::InsufficientScope.to_boxed_self()),
+        }
+    }
+
+    /// Path the credential is restricted to, if any
+    pub fn path_prefix(&self) -> Option<&Path> {
+        match &self.credential {
+            Credential::Session { .. } => None,
+            Credential::ApiToken { path_prefix, .. } => path_prefix.as_deref(),
+        }
+    }
+
+    /// Make sure given user-aware path is accessible with this credential
+    pub fn require_path(&self, path: &Path) -> Result<(), Box<dyn SPTFError>> {
+        if is_path_allowed(self.path_prefix(), path) {
+            Ok(())
+        } else {
+            Err(ApiTokenError::PathNotAllowed.to_boxed_self())
+        }
+    }
+}
+
+/// Normalize user-aware path to be relative to root.
+///
+/// Return None if path tries to escape with `..`
+pub fn normalize_path(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(component) => normalized.push(component),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Whether given user-aware path lies inside given prefix
+pub fn is_path_allowed(path_prefix: Option<&Path>, path: &Path) -> bool {
+    match path_prefix {
+        None => true,
+        Some(path_prefix) => normalize_path(path)
+            .map(|path| path.starts_with(path_prefix))
+            .unwrap_or(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_path_allowed, normalize_path};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn normalize_path_is_relative_to_root() {
+        assert_eq!(normalize_path(Path::new("/")), Some(PathBuf::new()));
+        assert_eq!(
+            normalize_path(Path::new("/a/./b/")),
+            Some(PathBuf::from("a/b"))
+        );
+        assert_eq!(
+            normalize_path(Path::new("a//b")),
+            Some(PathBuf::from("a/b"))
+        );
+        assert_eq!(normalize_path(Path::new("/a/../b")), None);
+        assert_eq!(normalize_path(Path::new("..")), None);
+    }
+
+    #[test]
+    fn is_path_allowed_compares_whole_components() {
+        let prefix = Some(Path::new("photos"));
+        assert!(is_path_allowed(None, Path::new("/anything")));
+        assert!(is_path_allowed(prefix, Path::new("/photos")));
+        assert!(is_path_allowed(prefix, Path::new("/photos/2020/a.jpg")));
+        assert!(is_path_allowed(prefix, Path::new("photos/./a.jpg")));
+        assert!(!is_path_allowed(prefix, Path::new("/photos-private")));
+        assert!(!is_path_allowed(prefix, Path::new("/photos/../secrets")));
+        assert!(!is_path_allowed(prefix, Path::new("/")));
+    }
+}
@@ -0,0 +1,19 @@
+use crate::database::Database;
+use crate::error::SPTFError;
+use async_trait::async_trait;
+use uuid::Uuid;
+
+/// Source of truth for passwords, tried in order by `user::validate_user`
+#[async_trait(?Send)]
+pub trait Authenticator: Send + Sync {
+    /// Check password of given user.
+    ///
+    /// Return None if this source does not know the user or the password does
+    /// not match, so that the next source is tried
+    async fn authenticate(
+        &self,
+        database: &dyn Database,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Uuid>, Box<dyn SPTFError>>;
+}
@@ -0,0 +1,61 @@
+use crate::config::CertificateIdentity;
+use actix_tls::accept::rustls::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use rustls::Certificate;
+use std::any::Any;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// Leaf certificate the client presented during the TLS handshake
+#[derive(Clone)]
+pub struct PeerCertificate(Certificate);
+
+/// Remember the client certificate of a new connection, so that handlers
+/// can read it with `conn_data`
+pub fn extract_peer_certificate(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(tls_stream) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        let (_, session) = tls_stream.get_ref();
+        if let Some(certificate) = session
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+        {
+            data.insert(PeerCertificate(certificate.clone()));
+        }
+    }
+}
+
+/// Username held by given certificate at the configured place
+pub fn certificate_username(
+    peer_certificate: &PeerCertificate,
+    identity: CertificateIdentity,
+) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(&peer_certificate.0 .0).ok()?;
+    let alternative_names = || {
+        certificate
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|extension| extension.value.general_names.clone())
+            .unwrap_or_default()
+    };
+    match identity {
+        CertificateIdentity::CommonName => certificate
+            .subject()
+            .iter_common_name()
+            .next()?
+            .as_str()
+            .ok()
+            .map(str::to_owned),
+        CertificateIdentity::SanEmail => {
+            alternative_names().into_iter().find_map(|name| match name {
+                GeneralName::RFC822Name(email) => Some(email.to_owned()),
+                _ => None,
+            })
+        }
+        CertificateIdentity::SanDns => {
+            alternative_names().into_iter().find_map(|name| match name {
+                GeneralName::DNSName(dns_name) => Some(dns_name.to_owned()),
+                _ => None,
+            })
+        }
+    }
+}
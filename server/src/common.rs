@@ -1,8 +1,90 @@
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub const PROTOCOL_VERSION: u32 = 1;
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Oldest websocket protocol version still accepted
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// First protocol version getting server-initiated messages as push
+/// notifications, older clients get them as if they had asked
+pub const PUSH_NOTIFICATION_PROTOCOL_VERSION: u32 = 2;
+/// First protocol version getting directory changes as deltas, older
+/// clients get the whole directory again
+pub const DIRECTORY_DELTA_PROTOCOL_VERSION: u32 = 3;
 pub const FILEWATCHER_DEBOUNCE_DURATION: Duration = Duration::from_secs(1);
 pub const MAX_FILE_UPLOAD_SIZE: usize = 64 * 1024 * 1024;
+/// Files read or uploaded over websockets are at most 8 MiB, larger ones
+/// go through /download and /upload
+pub const WEBSOCKET_MAX_FILE_SIZE: usize = 8 * 1024 * 1024;
+/// Leaves room for the rest of a message carrying a file
+pub const WEBSOCKET_MAX_FRAME_SIZE: usize = WEBSOCKET_MAX_FILE_SIZE + 64 * 1024;
+/// Directories one websocket may subscribe to, besides the one listed last
+pub const MAX_SUBSCRIPTIONS_PER_SESSION: usize = 64;
+/// Changes of a directory are sent once none came for 500 millis...
+pub const NOTIFICATION_COALESCE_WINDOW_IN_MILLISECONDS: u64 = 500;
+/// ...or 3 secs after the first of them, however many keep coming
+pub const NOTIFICATION_MAX_DELAY_IN_MILLISECONDS: u64 = 3000;
+/// Directories with more changes waiting are sent whole instead
+pub const MAX_PENDING_DELTAS_PER_DIRECTORY: usize = 1000;
 pub const COOKIE_AUTH_TOKEN_NAME: &str = "SPTF_AUTH";
-/// Redis cache expires in 30 mins
-pub const REDIS_CACHE_EXPIRATION_IN_SECONDS: usize = 30 * 60;
+/// Binds an OIDC flow to the browser that started it
+pub const COOKIE_OIDC_STATE_NAME: &str = "SPTF_OIDC_STATE";
+/// Query parameters holding credentials, hidden from access logs
+pub const REDACTED_QUERY_PARAMETERS: &[&str] = &["authToken", "auth_token", "code"];
+/// Login sessions end after 30 mins unused...
+pub const SESSION_IDLE_TIMEOUT_IN_SECONDS: usize = 30 * 60;
+/// ...or 12 hours after login, however much they are used
+pub const SESSION_MAX_LIFETIME_IN_SECONDS: usize = 12 * 60 * 60;
+/// Sessions logged in with "remember me" end after 14 days unused...
+pub const REMEMBER_ME_IDLE_TIMEOUT_IN_SECONDS: usize = 14 * 24 * 60 * 60;
+/// ...or 30 days after login
+pub const REMEMBER_ME_MAX_LIFETIME_IN_SECONDS: usize = 30 * 24 * 60 * 60;
+/// How often websockets check that their credential is still valid
+pub const SESSION_REVALIDATION_INTERVAL: Duration = Duration::from_secs(60);
+/// In-memory session store drops expired entries and writes its file every 30 secs
+pub const SESSION_STORE_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// Failed logins are counted within a sliding 15 mins window
+pub const LOGIN_ATTEMPT_WINDOW_IN_SECONDS: usize = 15 * 60;
+/// Failed logins allowed before backoff starts
+pub const LOGIN_BACKOFF_FREE_ATTEMPTS: usize = 3;
+/// Backoff doubles from 1 second on each further failure...
+pub const LOGIN_BACKOFF_BASE_IN_SECONDS: usize = 1;
+/// ...up to 5 mins
+pub const LOGIN_BACKOFF_MAX_IN_SECONDS: usize = 5 * 60;
+/// Failed logins of one account before it is locked
+pub const ACCOUNT_LOCKOUT_THRESHOLD: usize = 10;
+/// Failed logins from one ip before it is locked
+pub const IP_LOCKOUT_THRESHOLD: usize = 50;
+/// Locked accounts and ips are released after 15 mins
+pub const LOGIN_LOCKOUT_DURATION_IN_SECONDS: usize = 15 * 60;
+/// Pending logins waiting for a second factor expire in 5 mins
+pub const PENDING_LOGIN_EXPIRATION_IN_SECONDS: usize = 5 * 60;
+/// Issuer shown in authenticator apps
+pub const TOTP_ISSUER: &str = "SPTF";
+/// TOTP secret length in bytes, as recommended by RFC 4226
+pub const TOTP_SECRET_LENGTH: usize = 20;
+pub const TOTP_STEP_IN_SECONDS: u64 = 30;
+pub const TOTP_DIGITS: u32 = 6;
+/// Accept codes of the previous and next time step
+pub const TOTP_ALLOWED_SKEW_STEPS: u64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+pub const RECOVERY_CODE_LENGTH: usize = 10;
+/// Length of the username column
+pub const USERNAME_MAX_LENGTH: usize = 64;
+/// Password reset tokens expire in 1 hour
+pub const PASSWORD_RESET_EXPIRATION_IN_SECONDS: usize = 60 * 60;
+/// Users have 10 mins to log in at the identity provider
+pub const OIDC_STATE_EXPIRATION_IN_SECONDS: usize = 10 * 60;
+/// Audit log records are kept for 90 days unless configured otherwise
+pub const AUDIT_LOG_DEFAULT_RETENTION_IN_DAYS: u32 = 90;
+/// How often audit log records past retention are deleted
+pub const AUDIT_LOG_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Records listed per page if the admin does not say
+pub const AUDIT_LOG_DEFAULT_LIMIT: i64 = 100;
+pub const AUDIT_LOG_MAX_LIMIT: i64 = 1000;
+
+/// Seconds since unix epoch
+pub fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
@@ -1,3 +1,9 @@
+use crate::common::{
+    AUDIT_LOG_DEFAULT_RETENTION_IN_DAYS, NOTIFICATION_COALESCE_WINDOW_IN_MILLISECONDS,
+    NOTIFICATION_MAX_DELAY_IN_MILLISECONDS, REMEMBER_ME_IDLE_TIMEOUT_IN_SECONDS,
+    REMEMBER_ME_MAX_LIFETIME_IN_SECONDS, SESSION_IDLE_TIMEOUT_IN_SECONDS,
+    SESSION_MAX_LIFETIME_IN_SECONDS, USERNAME_MAX_LENGTH,
+};
 use rustls::{Certificate, PrivateKey};
 use rustls_pemfile::{certs, Item};
 use serde::Deserialize;
@@ -5,6 +11,276 @@ use std::fs::{self, File};
 use std::io::BufReader;
 use std::iter;
 use std::path::PathBuf;
+use std::time::Duration;
+
+/// Who may create an account through /signup
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignupMode {
+    /// Anyone, an invite code is optional
+    #[default]
+    Open,
+    /// Only holders of an invite code
+    Invite,
+    /// Nobody, only admins create users
+    Disabled,
+}
+
+/// Rules usernames and passwords must follow
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CredentialPolicyConfig {
+    pub username_min_length: usize,
+    /// Capped at USERNAME_MAX_LENGTH
+    pub username_max_length: usize,
+    /// Allowed in usernames besides letters and digits
+    pub username_allowed_symbols: String,
+    pub password_min_length: usize,
+    pub password_max_length: usize,
+    /// How many of lowercase, uppercase, digits and other characters a password must mix
+    pub password_min_character_classes: usize,
+    /// File of known breached passwords, one per line
+    pub breached_passwords_file_path: Option<String>,
+}
+
+impl Default for CredentialPolicyConfig {
+    fn default() -> Self {
+        Self {
+            username_min_length: 3,
+            username_max_length: USERNAME_MAX_LENGTH,
+            username_allowed_symbols: "._-".to_owned(),
+            password_min_length: 8,
+            password_max_length: 256,
+            password_min_character_classes: 2,
+            breached_passwords_file_path: None,
+        }
+    }
+}
+
+/// OpenID Connect identity provider to log in with
+#[derive(Deserialize, Clone)]
+pub struct OidcConfig {
+    /// Issuer url, where /.well-known/openid-configuration is served
+    pub issuer_url: String,
+    pub client_id: String,
+    /// Not needed by public clients, which rely on PKCE alone
+    pub client_secret: Option<String>,
+    /// Url of our /oidc/callback as registered at the provider
+    pub redirect_url: String,
+    /// Create a user on first login of an unknown subject
+    #[serde(default)]
+    pub auto_provision: bool,
+    /// Claim of the ID token used as username of provisioned users
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+}
+
+fn default_username_claim() -> String {
+    "preferred_username".to_owned()
+}
+
+/// LDAP directory to check passwords against besides local ones
+#[derive(Deserialize, Clone)]
+pub struct LdapConfig {
+    /// Like ldaps://ldap.example.com
+    pub url: String,
+    /// DN users bind as, with `{username}` replaced.
+    ///
+    /// Users are searched for with the search account if None
+    pub user_dn_template: Option<String>,
+    pub search_bind_dn: Option<String>,
+    pub search_bind_password: Option<String>,
+    pub user_search_base: Option<String>,
+    /// `{username}` is replaced
+    #[serde(default = "default_ldap_user_filter")]
+    pub user_filter: String,
+    /// Groups are searched for as the user after binding
+    pub group_search_base: String,
+    /// `{user_dn}` is replaced
+    #[serde(default = "default_ldap_group_filter")]
+    pub group_filter: String,
+    /// DNs of groups granting the role, the highest role wins
+    #[serde(default)]
+    pub admin_groups: Vec<String>,
+    #[serde(default)]
+    pub member_groups: Vec<String>,
+    #[serde(default)]
+    pub read_only_groups: Vec<String>,
+    /// Role of users in none of the groups, who cannot log in if None
+    pub default_role: Option<String>,
+}
+
+fn default_ldap_user_filter() -> String {
+    "(uid={username})".to_owned()
+}
+
+fn default_ldap_group_filter() -> String {
+    "(member={user_dn})".to_owned()
+}
+
+/// Part of a client certificate holding the username
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CertificateIdentity {
+    /// Common name of the subject
+    #[default]
+    CommonName,
+    /// First email in subject alternative names
+    SanEmail,
+    /// First DNS name in subject alternative names
+    SanDns,
+}
+
+/// Client certificates accepted during the TLS handshake
+#[derive(Deserialize, Clone)]
+pub struct ClientCertificateConfig {
+    /// PEM bundle of CAs client certificates must be issued by
+    pub ca_file_path: String,
+    #[serde(default)]
+    pub identity: CertificateIdentity,
+    /// Require the certificate together with the password on /login,
+    /// instead of accepting it alone on /login/certificate
+    #[serde(default)]
+    pub require_password: bool,
+}
+
+/// SameSite attribute of the auth cookie
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CookieSameSite {
+    #[default]
+    Strict,
+    Lax,
+    /// Needed if the client is served from another site than the server
+    None,
+}
+
+/// Auth cookie set on login and the origins trusted to use it
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AuthCookieConfig {
+    pub same_site: CookieSameSite,
+    /// Origins besides our own allowed to send cookie-authenticated
+    /// requests that change state, like `https://files.example.com`
+    pub allowed_origins: Vec<String>,
+}
+
+/// How long a login session lasts
+#[derive(Deserialize, Clone, Copy)]
+pub struct SessionLifetime {
+    /// Session ends if unused for this long
+    pub idle_timeout_in_seconds: usize,
+    /// Session ends this long after login, however much it is used
+    pub max_lifetime_in_seconds: usize,
+}
+
+/// Lifetimes of login sessions, depending on "remember me" at login
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct SessionLifetimeConfig {
+    pub regular: SessionLifetime,
+    pub remember_me: SessionLifetime,
+}
+
+impl Default for SessionLifetimeConfig {
+    fn default() -> Self {
+        Self {
+            regular: SessionLifetime {
+                idle_timeout_in_seconds: SESSION_IDLE_TIMEOUT_IN_SECONDS,
+                max_lifetime_in_seconds: SESSION_MAX_LIFETIME_IN_SECONDS,
+            },
+            remember_me: SessionLifetime {
+                idle_timeout_in_seconds: REMEMBER_ME_IDLE_TIMEOUT_IN_SECONDS,
+                max_lifetime_in_seconds: REMEMBER_ME_MAX_LIFETIME_IN_SECONDS,
+            },
+        }
+    }
+}
+
+impl SessionLifetimeConfig {
+    pub fn lifetime(&self, remember_me: bool) -> SessionLifetime {
+        if remember_me {
+            self.remember_me
+        } else {
+            self.regular
+        }
+    }
+}
+
+/// How long the audit log keeps what users did
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct AuditLogConfig {
+    /// Records older than this are pruned, never if 0
+    pub retention_in_days: u32,
+}
+
+impl Default for AuditLogConfig {
+    fn default() -> Self {
+        Self {
+            retention_in_days: AUDIT_LOG_DEFAULT_RETENTION_IN_DAYS,
+        }
+    }
+}
+
+/// How websockets coalesce changes of watched directories before sending
+/// them
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct NotificationConfig {
+    /// Changes are held until none came for this long...
+    pub coalesce_window_in_milliseconds: u64,
+    /// ...but no longer than this after the first of them
+    pub max_delay_in_milliseconds: u64,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            coalesce_window_in_milliseconds: NOTIFICATION_COALESCE_WINDOW_IN_MILLISECONDS,
+            max_delay_in_milliseconds: NOTIFICATION_MAX_DELAY_IN_MILLISECONDS,
+        }
+    }
+}
+
+impl NotificationConfig {
+    pub fn coalesce_window(&self) -> Duration {
+        Duration::from_millis(self.coalesce_window_in_milliseconds)
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay_in_milliseconds)
+    }
+}
+
+/// Where users and their metadata are kept
+#[derive(Deserialize, Default)]
+#[serde(tag = "backend", rename_all = "kebab-case")]
+pub enum DatabaseConfig {
+    /// Postgres at `database_port`
+    #[default]
+    Postgres,
+    /// Single SQLite file, so no Postgres is needed. Requires the `sqlite`
+    /// cargo feature.
+    ///
+    /// Usernames are told apart ignoring the case of ASCII letters only,
+    /// while Postgres ignores the case of any letter
+    Sqlite { file_path: String },
+}
+
+/// Where auth tokens and other short-lived state are kept
+#[derive(Deserialize, Default)]
+#[serde(tag = "backend", rename_all = "kebab-case")]
+pub enum SessionStoreConfig {
+    /// Redis at `redis_port`, shared by servers using it
+    #[default]
+    Redis,
+    /// Inside the server process, so no Redis is needed
+    Memory {
+        /// Sessions survive restarts if set
+        file_path: Option<String>,
+    },
+}
 
 /// Raw Config in file format
 #[derive(Deserialize)]
@@ -17,12 +293,39 @@ struct RawConfig {
     private_key_file_path: String,
     /// Path for our server to serve files in
     sptf_path: String,
-    database_port: u16,
-    database_username: String,
-    database_password: String,
-    redis_port: u16,
-    redis_username: String,
-    redis_password: String,
+    #[serde(default)]
+    database: DatabaseConfig,
+    /// Only required by the postgres database
+    database_port: Option<u16>,
+    database_username: Option<String>,
+    database_password: Option<String>,
+    /// Only required by the redis session store
+    redis_port: Option<u16>,
+    redis_username: Option<String>,
+    redis_password: Option<String>,
+    #[serde(default)]
+    session_store: SessionStoreConfig,
+    #[serde(default)]
+    session_lifetime: SessionLifetimeConfig,
+    /// Users promoted to admin on startup
+    #[serde(default)]
+    admin_usernames: Vec<String>,
+    #[serde(default)]
+    signup_mode: SignupMode,
+    #[serde(default)]
+    credential_policy: CredentialPolicyConfig,
+    /// OpenID Connect login is disabled if None
+    oidc: Option<OidcConfig>,
+    /// Only local passwords are checked if None
+    ldap: Option<LdapConfig>,
+    /// Client certificates are not requested if None
+    client_certificate: Option<ClientCertificateConfig>,
+    #[serde(default)]
+    auth_cookie: AuthCookieConfig,
+    #[serde(default)]
+    audit_log: AuditLogConfig,
+    #[serde(default)]
+    notifications: NotificationConfig,
 }
 
 /// Config file after processing raw config
@@ -39,12 +342,27 @@ pub struct Config {
     pub private_key_file_path: String,
     /// Path for our server to serve files in
     pub sptf_path: PathBuf,
-    pub database_port: u16,
-    pub database_username: String,
-    pub database_password: String,
-    pub redis_port: u16,
-    pub redis_username: String,
-    pub redis_password: String,
+    pub database: DatabaseConfig,
+    pub database_port: Option<u16>,
+    pub database_username: Option<String>,
+    pub database_password: Option<String>,
+    pub redis_port: Option<u16>,
+    pub redis_username: Option<String>,
+    pub redis_password: Option<String>,
+    pub session_store: SessionStoreConfig,
+    pub session_lifetime: SessionLifetimeConfig,
+    /// Users promoted to admin on startup
+    pub admin_usernames: Vec<String>,
+    pub signup_mode: SignupMode,
+    pub credential_policy: CredentialPolicyConfig,
+    pub oidc: Option<OidcConfig>,
+    pub ldap: Option<LdapConfig>,
+    pub client_certificate: Option<ClientCertificateConfig>,
+    pub auth_cookie: AuthCookieConfig,
+    pub audit_log: AuditLogConfig,
+    pub notifications: NotificationConfig,
+    /// CAs of client certificates, empty if they are not requested
+    pub client_ca_certificates: Vec<Certificate>,
 }
 
 const CONFIG_FILE_PATH: &str = "./config.toml";
@@ -58,20 +376,31 @@ pub fn get_config() -> Config {
         cert_file_path,
         private_key_file_path,
         sptf_path,
+        database,
         database_port,
         database_username,
         database_password,
         redis_port,
         redis_username,
         redis_password,
+        session_store,
+        session_lifetime,
+        admin_usernames,
+        signup_mode,
+        credential_policy,
+        oidc,
+        ldap,
+        client_certificate,
+        auth_cookie,
+        audit_log,
+        notifications,
     } = toml::from_str::<RawConfig>(&fs::read_to_string(CONFIG_FILE_PATH).unwrap()).unwrap();
 
-    let cert_file = &mut BufReader::new(File::open(&cert_file_path).unwrap());
-    let certificate_chain = certs(cert_file)
-        .unwrap()
-        .into_iter()
-        .map(Certificate)
-        .collect();
+    let certificate_chain = read_certificates(&cert_file_path);
+    let client_ca_certificates = client_certificate
+        .as_ref()
+        .map(|client_certificate| read_certificates(&client_certificate.ca_file_path))
+        .unwrap_or_default();
     let mut private_key = None;
     let mut reader = BufReader::new(File::open(&private_key_file_path).unwrap());
     for item in iter::from_fn(|| rustls_pemfile::read_one(&mut reader).transpose()) {
@@ -91,15 +420,34 @@ pub fn get_config() -> Config {
         cert_file_path,
         private_key_file_path,
         sptf_path: PathBuf::from(sptf_path),
+        database,
         database_port,
         database_username,
         database_password,
         redis_port,
         redis_username,
         redis_password,
+        session_store,
+        session_lifetime,
+        admin_usernames,
+        signup_mode,
+        credential_policy,
+        oidc,
+        ldap,
+        client_certificate,
+        auth_cookie,
+        audit_log,
+        notifications,
+        client_ca_certificates,
     }
 }
 
+/// Read all certificates of a PEM file
+fn read_certificates(file_path: &str) -> Vec<Certificate> {
+    let file = &mut BufReader::new(File::open(file_path).unwrap());
+    certs(file).unwrap().into_iter().map(Certificate).collect()
+}
+
 /// Remove config file at CONFIG_FILE_PATH
 pub fn remove_config_file() {
     std::fs::remove_file(CONFIG_FILE_PATH).unwrap();
@@ -0,0 +1,92 @@
+use crate::error::{CsrfError, SPTFError};
+use actix_web::{http::header, HttpRequest};
+use log::warn;
+
+/// Scheme, host and port of given url, like `https://example.com:8766`
+fn url_origin(url: &str) -> Option<&str> {
+    let scheme_end = url.find("://")? + "://".len();
+    let origin_end = url[scheme_end..]
+        .find(['/', '?', '#'])
+        .map_or(url.len(), |index| scheme_end + index);
+    Some(&url[..origin_end])
+}
+
+/// Origin a browser request comes from, falling back to its referer
+fn request_origin(req: &HttpRequest) -> Option<String> {
+    let headers = req.headers();
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        return Some(origin.to_str().unwrap_or_default().to_owned());
+    }
+    let referer = headers.get(header::REFERER)?.to_str().ok()?;
+    Some(url_origin(referer).unwrap_or_default().to_owned())
+}
+
+/// Make sure a cookie-authenticated request comes from our own origin or an
+/// allowed one, unless it cannot change state
+pub fn check_origin(
+    req: &HttpRequest,
+    allowed_origins: &[String],
+) -> Result<(), Box<dyn SPTFError>> {
+    if req.method().is_safe() {
+        return Ok(());
+    }
+    check_request_origin(req, allowed_origins)
+}
+
+/// Make sure a cookie-authenticated request comes from our own origin or an
+/// allowed one, whatever its method, as for websocket handshakes.
+///
+/// Requests with neither origin nor referer come from non-browser clients,
+/// which cannot be forged by another site, so they pass
+pub fn check_request_origin(
+    req: &HttpRequest,
+    allowed_origins: &[String],
+) -> Result<(), Box<dyn SPTFError>> {
+    let origin = match request_origin(req) {
+        Some(origin) => origin,
+        None => return Ok(()),
+    };
+    let connection_info = req.connection_info();
+    let own_origin = format!("{}://{}", connection_info.scheme(), connection_info.host());
+    if origin.eq_ignore_ascii_case(&own_origin)
+        || allowed_origins
+            .iter()
+            .any(|allowed_origin| origin.eq_ignore_ascii_case(allowed_origin.trim_end_matches('/')))
+    {
+        Ok(())
+    } else {
+        warn!(
+            "Rejected {} {} from origin {}",
+            req.method(),
+            req.path(),
+            origin
+        );
+        Err(CsrfError::OriginNotAllowed.to_boxed_self())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::url_origin;
+
+    #[test]
+    fn url_origin_keeps_scheme_host_and_port() {
+        assert_eq!(
+            url_origin("https://example.com:8766/files?path=/#top"),
+            Some("https://example.com:8766")
+        );
+        assert_eq!(
+            url_origin("https://example.com?next=/"),
+            Some("https://example.com")
+        );
+        assert_eq!(
+            url_origin("https://example.com#top"),
+            Some("https://example.com")
+        );
+        assert_eq!(
+            url_origin("https://example.com"),
+            Some("https://example.com")
+        );
+        assert_eq!(url_origin("example.com/files"), None);
+    }
+}
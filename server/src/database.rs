@@ -0,0 +1,279 @@
+use crate::error::SPTFError;
+use async_trait::async_trait;
+use std::fmt;
+use uuid::Uuid;
+
+/// Value bound to or read from a statement, whatever the backend.
+///
+/// Statements use `$1`, `$2`... placeholders on every backend
+#[derive(Clone, Debug)]
+pub enum SqlValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Text(String),
+    Bytes(Vec<u8>),
+    Uuid(Uuid),
+    /// Native array on Postgres, JSON text on SQLite
+    TextArray(Vec<String>),
+}
+
+impl From<bool> for SqlValue {
+    fn from(value: bool) -> Self {
+        SqlValue::Bool(value)
+    }
+}
+
+impl From<i32> for SqlValue {
+    fn from(value: i32) -> Self {
+        SqlValue::Int(value.into())
+    }
+}
+
+impl From<i64> for SqlValue {
+    fn from(value: i64) -> Self {
+        SqlValue::Int(value)
+    }
+}
+
+impl From<&str> for SqlValue {
+    fn from(value: &str) -> Self {
+        SqlValue::Text(value.to_owned())
+    }
+}
+
+impl From<String> for SqlValue {
+    fn from(value: String) -> Self {
+        SqlValue::Text(value)
+    }
+}
+
+impl From<&[u8]> for SqlValue {
+    fn from(value: &[u8]) -> Self {
+        SqlValue::Bytes(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for SqlValue {
+    fn from(value: Vec<u8>) -> Self {
+        SqlValue::Bytes(value)
+    }
+}
+
+impl From<Uuid> for SqlValue {
+    fn from(value: Uuid) -> Self {
+        SqlValue::Uuid(value)
+    }
+}
+
+impl From<Vec<String>> for SqlValue {
+    fn from(value: Vec<String>) -> Self {
+        SqlValue::TextArray(value)
+    }
+}
+
+impl<T: Into<SqlValue>> From<Option<T>> for SqlValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(SqlValue::Null, Into::into)
+    }
+}
+
+/// Escape given value for a LIKE pattern ending in `ESCAPE '\'`, so that
+/// its `%` and `_` match only themselves
+pub fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for character in value.chars() {
+        if matches!(character, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+/// Error of a statement, with only what callers need to tell apart
+#[derive(Debug)]
+pub struct DatabaseError {
+    message: String,
+    unique_violation: bool,
+}
+
+impl DatabaseError {
+    pub fn new(message: impl fmt::Display) -> Self {
+        Self {
+            message: message.to_string(),
+            unique_violation: false,
+        }
+    }
+
+    pub fn unique_violation(message: impl fmt::Display) -> Self {
+        Self {
+            message: message.to_string(),
+            unique_violation: true,
+        }
+    }
+
+    /// Whether the statement broke a unique constraint, like a taken username
+    pub fn is_unique_violation(&self) -> bool {
+        self.unique_violation
+    }
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Rust type a column converts to
+pub trait FromSqlValue: Sized {
+    fn from_sql_value(value: &SqlValue) -> Option<Self>;
+}
+
+impl FromSqlValue for bool {
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Bool(value) => Some(*value),
+            // SQLite has no boolean type
+            SqlValue::Int(value) => Some(*value != 0),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqlValue for i32 {
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Int(value) => (*value).try_into().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqlValue for i64 {
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqlValue for String {
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Text(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqlValue for Vec<u8> {
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Bytes(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqlValue for Uuid {
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Uuid(value) => Some(*value),
+            // SQLite keeps uuids as text
+            SqlValue::Text(value) => Uuid::parse_str(value).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqlValue for Vec<String> {
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::TextArray(value) => Some(value.clone()),
+            SqlValue::Text(value) => serde_json::from_str(value).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromSqlValue> FromSqlValue for Option<T> {
+    fn from_sql_value(value: &SqlValue) -> Option<Self> {
+        match value {
+            SqlValue::Null => Some(None),
+            value => T::from_sql_value(value).map(Some),
+        }
+    }
+}
+
+/// Row returned by a query, with columns in the order selected
+pub struct Row {
+    values: Vec<SqlValue>,
+}
+
+impl Row {
+    pub fn new(values: Vec<SqlValue>) -> Self {
+        Self { values }
+    }
+
+    pub fn try_get<T: FromSqlValue>(&self, index: usize) -> Result<T, DatabaseError> {
+        let value = self
+            .values
+            .get(index)
+            .ok_or_else(|| DatabaseError::new(format!("No column {}", index)))?;
+        T::from_sql_value(value).ok_or_else(|| {
+            DatabaseError::new(format!(
+                "Cannot convert column {} of {:?} to {}",
+                index,
+                value,
+                std::any::type_name::<T>()
+            ))
+        })
+    }
+}
+
+/// Relational storage of users and their metadata.
+///
+/// Statements are written once in the SQL both backends understand, and each
+/// backend ships its own migrations creating the same tables
+#[async_trait(?Send)]
+pub trait Database: Send + Sync {
+    /// Bring the schema up to date, see `migrate`
+    async fn run_migrations(&self) -> Result<(), Box<dyn SPTFError>>;
+
+    /// Run a statement, returning the number of rows changed
+    async fn execute(&self, statement: &str, params: &[SqlValue]) -> Result<u64, DatabaseError>;
+
+    async fn query(&self, statement: &str, params: &[SqlValue]) -> Result<Vec<Row>, DatabaseError>;
+
+    /// Run statements in one transaction, returning the number of rows each
+    /// changed, or rolling all of them back on error
+    async fn transaction(
+        &self,
+        statements: &[(&str, &[SqlValue])],
+    ) -> Result<Vec<u64>, DatabaseError>;
+
+    /// Query at most one row
+    async fn query_opt(
+        &self,
+        statement: &str,
+        params: &[SqlValue],
+    ) -> Result<Option<Row>, DatabaseError> {
+        let mut rows = self.query(statement, params).await?;
+        match rows.len() {
+            0 | 1 => Ok(rows.pop()),
+            row_count => Err(DatabaseError::new(format!(
+                "Query returns {} rows instead of at most one",
+                row_count
+            ))),
+        }
+    }
+
+    /// Query exactly one row
+    async fn query_one(&self, statement: &str, params: &[SqlValue]) -> Result<Row, DatabaseError> {
+        self.query_opt(statement, params)
+            .await?
+            .ok_or_else(|| DatabaseError::new("Query returns no row"))
+    }
+}
@@ -22,9 +22,13 @@ pub trait SPTFError {
     }
 
     fn to_http_response(&self) -> HttpResponse {
-        HttpResponse::InternalServerError()
+        let mut response = HttpResponse::InternalServerError()
             .content_type(ContentType::json())
-            .body(self.to_json_string())
+            .body(self.to_json_string());
+        response
+            .extensions_mut()
+            .insert(ResponseErrorCode(self.error_code()));
+        response
     }
 
     fn to_proto_error(&self) -> ErrorResponse {
@@ -42,6 +46,15 @@ pub trait SPTFError {
     }
 }
 
+impl std::fmt::Debug for dyn SPTFError + '_ {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SPTFError({:#x})", self.error_code())
+    }
+}
+
+/// Error code of an error response, read back by the audit log
+pub struct ResponseErrorCode(pub usize);
+
 pub struct UnexpectedError;
 
 impl SPTFError for UnexpectedError {
@@ -51,18 +64,25 @@ impl SPTFError for UnexpectedError {
 }
 
 pub enum ValidateError {
-    NoUsername,
-    UnmatchedPassword,
+    /// Username does not exist or password does not match.
+    ///
+    /// Deliberately not telling which, so usernames cannot be enumerated.
+    InvalidCredentials,
     WrongCookie,
+    NotAdmin,
+    ReadOnly,
+    UserDisabled,
 }
 
 impl SPTFError for ValidateError {
     fn error_code(&self) -> usize {
         use ValidateError::*;
         match self {
-            NoUsername => VALIDATE_ERROR_NO_USERNAME_ERROR_CODE,
-            UnmatchedPassword => VALIDATE_ERROR_UNMATCHED_PASSWORD_ERROR_CODE,
+            InvalidCredentials => VALIDATE_ERROR_INVALID_CREDENTIALS_ERROR_CODE,
             WrongCookie => VALIDATE_ERROR_WRONG_COOKIE_ERROR_CODE,
+            NotAdmin => VALIDATE_ERROR_NOT_ADMIN_ERROR_CODE,
+            ReadOnly => VALIDATE_ERROR_READ_ONLY_ERROR_CODE,
+            UserDisabled => VALIDATE_ERROR_USER_DISABLED_ERROR_CODE,
         }
     }
 }
@@ -84,6 +104,13 @@ impl SPTFError for RedisCacheError {
 
 pub enum FileError {
     PermissionDenied,
+    QuotaExceeded,
+    /// Larger than websockets carry, use /download or /upload instead
+    TooLarge,
+    NotFound,
+    /// Escapes the root with `..`, or is the root where that is not allowed
+    InvalidPath,
+    AlreadyExists,
 }
 
 impl SPTFError for FileError {
@@ -91,6 +118,25 @@ impl SPTFError for FileError {
         use FileError::*;
         match self {
             PermissionDenied => FILE_ERROR_PERMISSION_DENIED_ERROR_CODE,
+            QuotaExceeded => FILE_ERROR_QUOTA_EXCEEDED_ERROR_CODE,
+            TooLarge => FILE_ERROR_TOO_LARGE_ERROR_CODE,
+            NotFound => FILE_ERROR_NOT_FOUND_ERROR_CODE,
+            InvalidPath => FILE_ERROR_INVALID_PATH_ERROR_CODE,
+            AlreadyExists => FILE_ERROR_ALREADY_EXISTS_ERROR_CODE,
+        }
+    }
+}
+
+pub enum SubscriptionError {
+    /// Session already watches MAX_SUBSCRIPTIONS_PER_SESSION directories
+    TooMany,
+}
+
+impl SPTFError for SubscriptionError {
+    fn error_code(&self) -> usize {
+        use SubscriptionError::*;
+        match self {
+            TooMany => SUBSCRIPTION_ERROR_TOO_MANY_ERROR_CODE,
         }
     }
 }
@@ -110,6 +156,7 @@ impl SPTFError for ProtobufError {
 
 pub enum SignupError {
     UsernameExist,
+    Disabled,
 }
 
 impl SPTFError for SignupError {
@@ -117,16 +164,281 @@ impl SPTFError for SignupError {
         use SignupError::*;
         match self {
             UsernameExist => SIGNUP_ERROR_USER_NAME_EXIST_ERROR_CODE,
+            Disabled => SIGNUP_ERROR_DISABLED_ERROR_CODE,
+        }
+    }
+}
+
+pub enum InviteError {
+    Invalid,
+    NotFound,
+}
+
+impl SPTFError for InviteError {
+    fn error_code(&self) -> usize {
+        use InviteError::*;
+        match self {
+            Invalid => INVITE_ERROR_INVALID_ERROR_CODE,
+            NotFound => INVITE_ERROR_NOT_FOUND_ERROR_CODE,
+        }
+    }
+}
+
+pub enum PolicyError {
+    UsernameLength,
+    UsernameCharacters,
+    PasswordLength,
+    PasswordTooWeak,
+    PasswordBreached,
+}
+
+impl SPTFError for PolicyError {
+    fn error_code(&self) -> usize {
+        use PolicyError::*;
+        match self {
+            UsernameLength => POLICY_ERROR_USERNAME_LENGTH_ERROR_CODE,
+            UsernameCharacters => POLICY_ERROR_USERNAME_CHARACTERS_ERROR_CODE,
+            PasswordLength => POLICY_ERROR_PASSWORD_LENGTH_ERROR_CODE,
+            PasswordTooWeak => POLICY_ERROR_PASSWORD_TOO_WEAK_ERROR_CODE,
+            PasswordBreached => POLICY_ERROR_PASSWORD_BREACHED_ERROR_CODE,
+        }
+    }
+}
+
+pub enum MigrationError {
+    /// Database has been migrated by a newer server
+    SchemaTooNew,
+}
+
+impl SPTFError for MigrationError {
+    fn error_code(&self) -> usize {
+        use MigrationError::*;
+        match self {
+            SchemaTooNew => MIGRATION_ERROR_SCHEMA_TOO_NEW_ERROR_CODE,
+        }
+    }
+}
+
+pub enum OidcError {
+    NotConfigured,
+    InvalidState,
+    ProviderError,
+    InvalidIdToken,
+    /// Identity is not linked to any user and auto-provisioning is off
+    NotLinked,
+    AlreadyLinked,
+}
+
+impl SPTFError for OidcError {
+    fn error_code(&self) -> usize {
+        use OidcError::*;
+        match self {
+            NotConfigured => OIDC_ERROR_NOT_CONFIGURED_ERROR_CODE,
+            InvalidState => OIDC_ERROR_INVALID_STATE_ERROR_CODE,
+            ProviderError => OIDC_ERROR_PROVIDER_ERROR_ERROR_CODE,
+            InvalidIdToken => OIDC_ERROR_INVALID_ID_TOKEN_ERROR_CODE,
+            NotLinked => OIDC_ERROR_NOT_LINKED_ERROR_CODE,
+            AlreadyLinked => OIDC_ERROR_ALREADY_LINKED_ERROR_CODE,
+        }
+    }
+}
+
+pub enum LdapError {
+    Unavailable,
+    /// User is in none of the groups mapped to a role
+    NoRole,
+}
+
+impl SPTFError for LdapError {
+    fn error_code(&self) -> usize {
+        use LdapError::*;
+        match self {
+            Unavailable => LDAP_ERROR_UNAVAILABLE_ERROR_CODE,
+            NoRole => LDAP_ERROR_NO_ROLE_ERROR_CODE,
+        }
+    }
+}
+
+pub enum CertificateError {
+    NotConfigured,
+    Missing,
+    /// Certificate holds no username at the configured place
+    Unmapped,
+    /// Certificate belongs to another user than the one logging in
+    Mismatch,
+    PasswordRequired,
+}
+
+impl SPTFError for CertificateError {
+    fn error_code(&self) -> usize {
+        use CertificateError::*;
+        match self {
+            NotConfigured => CERTIFICATE_ERROR_NOT_CONFIGURED_ERROR_CODE,
+            Missing => CERTIFICATE_ERROR_MISSING_ERROR_CODE,
+            Unmapped => CERTIFICATE_ERROR_UNMAPPED_ERROR_CODE,
+            Mismatch => CERTIFICATE_ERROR_MISMATCH_ERROR_CODE,
+            PasswordRequired => CERTIFICATE_ERROR_PASSWORD_REQUIRED_ERROR_CODE,
+        }
+    }
+}
+
+pub enum CsrfError {
+    /// Cookie-authenticated request changing state comes from a foreign origin
+    OriginNotAllowed,
+}
+
+impl SPTFError for CsrfError {
+    fn error_code(&self) -> usize {
+        use CsrfError::*;
+        match self {
+            OriginNotAllowed => CSRF_ERROR_ORIGIN_NOT_ALLOWED_ERROR_CODE,
+        }
+    }
+}
+
+pub enum ThrottleError {
+    TooManyAttempts,
+    Locked,
+}
+
+impl SPTFError for ThrottleError {
+    fn error_code(&self) -> usize {
+        use ThrottleError::*;
+        match self {
+            TooManyAttempts => THROTTLE_ERROR_TOO_MANY_ATTEMPTS_ERROR_CODE,
+            Locked => THROTTLE_ERROR_LOCKED_ERROR_CODE,
+        }
+    }
+}
+
+pub enum TotpError {
+    InvalidCode,
+    AlreadyEnabled,
+    NotEnrolled,
+    PendingLoginExpired,
+}
+
+impl SPTFError for TotpError {
+    fn error_code(&self) -> usize {
+        use TotpError::*;
+        match self {
+            InvalidCode => TOTP_ERROR_INVALID_CODE_ERROR_CODE,
+            AlreadyEnabled => TOTP_ERROR_ALREADY_ENABLED_ERROR_CODE,
+            NotEnrolled => TOTP_ERROR_NOT_ENROLLED_ERROR_CODE,
+            PendingLoginExpired => TOTP_ERROR_PENDING_LOGIN_EXPIRED_ERROR_CODE,
+        }
+    }
+}
+
+pub enum ApiTokenError {
+    InvalidScope,
+    InsufficientScope,
+    PathNotAllowed,
+    SessionRequired,
+    NotFound,
+    Expired,
+}
+
+impl SPTFError for ApiTokenError {
+    fn error_code(&self) -> usize {
+        use ApiTokenError::*;
+        match self {
+            InvalidScope => API_TOKEN_ERROR_INVALID_SCOPE_ERROR_CODE,
+            InsufficientScope => API_TOKEN_ERROR_INSUFFICIENT_SCOPE_ERROR_CODE,
+            PathNotAllowed => API_TOKEN_ERROR_PATH_NOT_ALLOWED_ERROR_CODE,
+            SessionRequired => API_TOKEN_ERROR_SESSION_REQUIRED_ERROR_CODE,
+            NotFound => API_TOKEN_ERROR_NOT_FOUND_ERROR_CODE,
+            Expired => API_TOKEN_ERROR_EXPIRED_ERROR_CODE,
+        }
+    }
+}
+
+pub enum SessionError {
+    NotFound,
+}
+
+impl SPTFError for SessionError {
+    fn error_code(&self) -> usize {
+        use SessionError::*;
+        match self {
+            NotFound => SESSION_ERROR_NOT_FOUND_ERROR_CODE,
+        }
+    }
+}
+
+pub enum UserError {
+    NotFound,
+    InvalidResetToken,
+    InvalidRole,
+    CannotModifySelf,
+}
+
+impl SPTFError for UserError {
+    fn error_code(&self) -> usize {
+        use UserError::*;
+        match self {
+            NotFound => USER_ERROR_NOT_FOUND_ERROR_CODE,
+            InvalidResetToken => USER_ERROR_INVALID_RESET_TOKEN_ERROR_CODE,
+            InvalidRole => USER_ERROR_INVALID_ROLE_ERROR_CODE,
+            CannotModifySelf => USER_ERROR_CANNOT_MODIFY_SELF_ERROR_CODE,
         }
     }
 }
 
 const UNEXPECTED_ERROR_CODE: usize = 0x0;
-const VALIDATE_ERROR_NO_USERNAME_ERROR_CODE: usize = 0x1;
-const VALIDATE_ERROR_UNMATCHED_PASSWORD_ERROR_CODE: usize = 0x2;
 const VALIDATE_ERROR_WRONG_COOKIE_ERROR_CODE: usize = 0x3;
 const REDIS_CACHE_ERROR_UPDATE_AUTH_TOKEN_FAILED_ERROR_CODE: usize = 0x4;
 const REDIS_CACHE_ERROR_VALIDATE_AUTH_TOKEN_FAILED_ERROR_CODE: usize = 0x5;
 const FILE_ERROR_PERMISSION_DENIED_ERROR_CODE: usize = 0x6;
 const PROTOBUF_ERROR_WRONG_FORMAT_ERROR_CODE: usize = 0x7;
 const SIGNUP_ERROR_USER_NAME_EXIST_ERROR_CODE: usize = 0x8;
+const VALIDATE_ERROR_INVALID_CREDENTIALS_ERROR_CODE: usize = 0x9;
+const THROTTLE_ERROR_TOO_MANY_ATTEMPTS_ERROR_CODE: usize = 0xa;
+const THROTTLE_ERROR_LOCKED_ERROR_CODE: usize = 0xb;
+const VALIDATE_ERROR_NOT_ADMIN_ERROR_CODE: usize = 0xc;
+const TOTP_ERROR_INVALID_CODE_ERROR_CODE: usize = 0xd;
+const TOTP_ERROR_ALREADY_ENABLED_ERROR_CODE: usize = 0xe;
+const TOTP_ERROR_NOT_ENROLLED_ERROR_CODE: usize = 0xf;
+const TOTP_ERROR_PENDING_LOGIN_EXPIRED_ERROR_CODE: usize = 0x10;
+const API_TOKEN_ERROR_INVALID_SCOPE_ERROR_CODE: usize = 0x11;
+const API_TOKEN_ERROR_INSUFFICIENT_SCOPE_ERROR_CODE: usize = 0x12;
+const API_TOKEN_ERROR_PATH_NOT_ALLOWED_ERROR_CODE: usize = 0x13;
+const API_TOKEN_ERROR_SESSION_REQUIRED_ERROR_CODE: usize = 0x14;
+const API_TOKEN_ERROR_NOT_FOUND_ERROR_CODE: usize = 0x15;
+const API_TOKEN_ERROR_EXPIRED_ERROR_CODE: usize = 0x16;
+const SESSION_ERROR_NOT_FOUND_ERROR_CODE: usize = 0x17;
+const USER_ERROR_NOT_FOUND_ERROR_CODE: usize = 0x18;
+const USER_ERROR_INVALID_RESET_TOKEN_ERROR_CODE: usize = 0x19;
+const VALIDATE_ERROR_READ_ONLY_ERROR_CODE: usize = 0x1a;
+const VALIDATE_ERROR_USER_DISABLED_ERROR_CODE: usize = 0x1b;
+const USER_ERROR_INVALID_ROLE_ERROR_CODE: usize = 0x1c;
+const USER_ERROR_CANNOT_MODIFY_SELF_ERROR_CODE: usize = 0x1d;
+const SIGNUP_ERROR_DISABLED_ERROR_CODE: usize = 0x1e;
+const INVITE_ERROR_INVALID_ERROR_CODE: usize = 0x1f;
+const INVITE_ERROR_NOT_FOUND_ERROR_CODE: usize = 0x20;
+const FILE_ERROR_QUOTA_EXCEEDED_ERROR_CODE: usize = 0x21;
+const POLICY_ERROR_USERNAME_LENGTH_ERROR_CODE: usize = 0x22;
+const POLICY_ERROR_USERNAME_CHARACTERS_ERROR_CODE: usize = 0x23;
+const POLICY_ERROR_PASSWORD_LENGTH_ERROR_CODE: usize = 0x24;
+const POLICY_ERROR_PASSWORD_TOO_WEAK_ERROR_CODE: usize = 0x25;
+const POLICY_ERROR_PASSWORD_BREACHED_ERROR_CODE: usize = 0x26;
+const MIGRATION_ERROR_SCHEMA_TOO_NEW_ERROR_CODE: usize = 0x27;
+const OIDC_ERROR_NOT_CONFIGURED_ERROR_CODE: usize = 0x28;
+const OIDC_ERROR_INVALID_STATE_ERROR_CODE: usize = 0x29;
+const OIDC_ERROR_PROVIDER_ERROR_ERROR_CODE: usize = 0x2a;
+const OIDC_ERROR_INVALID_ID_TOKEN_ERROR_CODE: usize = 0x2b;
+const OIDC_ERROR_NOT_LINKED_ERROR_CODE: usize = 0x2c;
+const OIDC_ERROR_ALREADY_LINKED_ERROR_CODE: usize = 0x2d;
+const LDAP_ERROR_UNAVAILABLE_ERROR_CODE: usize = 0x2e;
+const LDAP_ERROR_NO_ROLE_ERROR_CODE: usize = 0x2f;
+const CERTIFICATE_ERROR_NOT_CONFIGURED_ERROR_CODE: usize = 0x30;
+const CERTIFICATE_ERROR_MISSING_ERROR_CODE: usize = 0x31;
+const CERTIFICATE_ERROR_UNMAPPED_ERROR_CODE: usize = 0x32;
+const CERTIFICATE_ERROR_MISMATCH_ERROR_CODE: usize = 0x33;
+const CERTIFICATE_ERROR_PASSWORD_REQUIRED_ERROR_CODE: usize = 0x34;
+const CSRF_ERROR_ORIGIN_NOT_ALLOWED_ERROR_CODE: usize = 0x35;
+const FILE_ERROR_TOO_LARGE_ERROR_CODE: usize = 0x36;
+const FILE_ERROR_NOT_FOUND_ERROR_CODE: usize = 0x37;
+const FILE_ERROR_INVALID_PATH_ERROR_CODE: usize = 0x38;
+const FILE_ERROR_ALREADY_EXISTS_ERROR_CODE: usize = 0x39;
+const SUBSCRIPTION_ERROR_TOO_MANY_ERROR_CODE: usize = 0x3a;
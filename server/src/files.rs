@@ -1,3 +1,5 @@
+use crate::auth::normalize_path;
+use crate::database::{escape_like, Database, DatabaseError, SqlValue};
 use crate::error::{FileError, SPTFError, UnexpectedError};
 use crate::protos::sptf::{
     DirectoryLayout, DirectoryLayout_File, DirectoryLayout_FileMetadata,
@@ -7,9 +9,10 @@ use flate2::{write::GzEncoder, Compression};
 use log::{error, warn};
 use std::fs::{self, File};
 use std::io;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
+use uuid::Uuid;
 
 /// Compose root path and user-aware path
 ///
@@ -26,7 +29,7 @@ pub fn real_path(root_path: &Path, path: &Path) -> PathBuf {
 }
 
 /// Extract user-aware path from root path and real path
-fn user_aware_path(root_path: &Path, real_path: &Path) -> Option<PathBuf> {
+pub fn user_aware_path(root_path: &Path, real_path: &Path) -> Option<PathBuf> {
     real_path
         .strip_prefix(root_path)
         .ok()
@@ -41,11 +44,12 @@ fn user_aware_path(root_path: &Path, real_path: &Path) -> Option<PathBuf> {
 }
 
 pub fn list_dir(root_path: &Path, user_aware_path: &Path) -> ListDirectoryResponse {
-    let real_path = real_path(root_path, user_aware_path);
     let mut list_directory_response = ListDirectoryResponse::default();
 
     list_directory_response.set_directory_path((*user_aware_path.to_string_lossy()).into());
-    match list_dir_internal(&root_path, &real_path) {
+    match checked_real_path(root_path, user_aware_path)
+        .and_then(|real_path| list_dir_internal(root_path, &real_path))
+    {
         Ok(directory_layout) => {
             list_directory_response.set_DirectoryLayout(directory_layout);
         }
@@ -208,7 +212,7 @@ pub async fn compress_files(
         };
         let mut temp_file = temp_dir.path().to_path_buf();
         temp_file.push(file_name);
-        let real_file_path = real_path(&root_path, &user_aware_file_path);
+        let real_file_path = checked_real_path(root_path, &user_aware_file_path)?;
         if let Err(err) = tokio::fs::copy(&real_file_path, temp_file).await {
             error!("Failed to copy {:?}: {}", real_file_path, err);
             return Err(FileError::PermissionDenied.to_boxed_self());
@@ -230,17 +234,47 @@ pub async fn compress_files(
     Ok(temp_compressed_file)
 }
 
+/// Whether given name names a file inside a directory, rather than a path
+fn is_plain_file_name(file_name: &str) -> bool {
+    !file_name.contains(['/', '\\'])
+        && matches!(
+            Path::new(file_name).components().collect::<Vec<_>>()[..],
+            [Component::Normal(_)]
+        )
+}
+
+/// Real paths to write the uploaded files to, in order
+fn upload_real_paths(
+    root_path: &Path,
+    file_upload_request: &FileUploadRequest,
+) -> Result<Vec<PathBuf>, Box<dyn SPTFError>> {
+    let real_dir_path =
+        checked_real_path(root_path, Path::new(file_upload_request.get_dir_path()))?;
+    file_upload_request
+        .get_uploaded_file()
+        .iter()
+        .map(|file| {
+            if is_plain_file_name(file.get_file_name()) {
+                Ok(real_dir_path.join(file.get_file_name()))
+            } else {
+                warn!("Refused to upload file named {:?}", file.get_file_name());
+                Err(FileError::InvalidPath.to_boxed_self())
+            }
+        })
+        .collect()
+}
+
 pub async fn upload_files(
     root_path: &Path,
     file_upload_request: FileUploadRequest,
 ) -> Result<(), Box<dyn SPTFError>> {
-    let dir_path = file_upload_request.get_dir_path();
+    let real_file_paths = upload_real_paths(root_path, &file_upload_request)?;
     let mut result = Ok(());
-    for file in file_upload_request.get_uploaded_file() {
-        let file_name = file.get_file_name();
-        let mut user_aware_file_path = PathBuf::from(&dir_path);
-        user_aware_file_path.push(file_name);
-        let real_file_path = real_path(&root_path, &user_aware_file_path);
+    for (file, real_file_path) in file_upload_request
+        .get_uploaded_file()
+        .iter()
+        .zip(real_file_paths)
+    {
         let content = file.get_content();
         if let Err(err) = tokio::fs::write(&real_file_path, content).await {
             error!("Failed to write to {:?}: {}", real_file_path, err);
@@ -253,10 +287,493 @@ pub async fn upload_files(
 }
 
 pub async fn make_directory(root_path: &Path, dir_path: &Path) -> Result<(), Box<dyn SPTFError>> {
-    let real_dir_path = real_path(root_path, dir_path);
+    let real_dir_path = checked_real_path(root_path, dir_path)?;
     if let Err(err) = tokio::fs::create_dir_all(&real_dir_path).await {
         error!("Failed to create dir at {:?}: {}", real_dir_path, err);
         return Err(FileError::PermissionDenied.to_boxed_self());
     }
     Ok(())
 }
+
+/// Error of a failed file system call, telling missing files apart
+fn io_error(err: &io::Error) -> Box<dyn SPTFError> {
+    if err.kind() == io::ErrorKind::NotFound {
+        FileError::NotFound.to_boxed_self()
+    } else {
+        FileError::PermissionDenied.to_boxed_self()
+    }
+}
+
+/// Real path of given user-aware path, which must not escape the root
+pub fn checked_real_path(root_path: &Path, path: &Path) -> Result<PathBuf, Box<dyn SPTFError>> {
+    normalize_path(path)
+        .map(|normalized_path| real_path(root_path, &normalized_path))
+        .ok_or_else(|| FileError::InvalidPath.to_boxed_self())
+}
+
+/// Real path of something below the root, which must not be the root itself
+fn real_path_below_root(root_path: &Path, path: &Path) -> Result<PathBuf, Box<dyn SPTFError>> {
+    match normalize_path(path) {
+        Some(normalized_path) if !normalized_path.as_os_str().is_empty() => {
+            Ok(real_path(root_path, &normalized_path))
+        }
+        _ => Err(FileError::InvalidPath.to_boxed_self()),
+    }
+}
+
+/// Key of given user-aware path in FileOwners
+fn owner_key(path: &Path) -> Result<String, Box<dyn SPTFError>> {
+    normalize_path(path)
+        .map(|normalized_path| normalized_path.to_string_lossy().into_owned())
+        .ok_or_else(|| FileError::InvalidPath.to_boxed_self())
+}
+
+/// FileOwners rows of the key in $1 and everything below it, given the
+/// params of `owned_below_params`
+const OWNED_BELOW_CONDITION: &str = "(path=$1 OR path LIKE $2 ESCAPE '\\')";
+
+fn owned_below_params(key: &str) -> [SqlValue; 2] {
+    [key.into(), format!("{}/%", escape_like(key)).into()]
+}
+
+/// Give the bytes of files at and below given key back to their owners,
+/// then forget the files, as statements of a transaction
+fn release_owned_below_statements() -> [String; 2] {
+    [
+        format!(
+            "UPDATE Users SET used_bytes=CASE WHEN used_bytes>owned.size_bytes THEN used_bytes-owned.size_bytes ELSE 0 END FROM (SELECT user_id, SUM(size_bytes) AS size_bytes FROM FileOwners WHERE {} GROUP BY user_id) AS owned WHERE Users.id=owned.user_id",
+            OWNED_BELOW_CONDITION
+        ),
+        format!("DELETE FROM FileOwners WHERE {}", OWNED_BELOW_CONDITION),
+    ]
+}
+
+fn owner_error(err: DatabaseError) -> Box<dyn SPTFError> {
+    error!("Failed to update file owners: {}", err);
+    UnexpectedError.to_boxed_self()
+}
+
+/// Uploader and size of the file at given key, if uploaded through us
+async fn file_owner(
+    database: &dyn Database,
+    key: &str,
+) -> Result<Option<(Uuid, i64)>, Box<dyn SPTFError>> {
+    let row = database
+        .query_opt(
+            "SELECT user_id, size_bytes FROM FileOwners WHERE path=$1",
+            &[key.into()],
+        )
+        .await
+        .map_err(owner_error)?;
+    row.map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+        .transpose()
+        .map_err(owner_error)
+}
+
+/// Upload files, counting them against the quota of given user, who then
+/// owns them.
+///
+/// Overwriting a file of one's own only counts the bytes it grows by, and
+/// overwriting one of another user gives its bytes back to that user
+pub async fn upload_files_within_quota(
+    database: &dyn Database,
+    root_path: &Path,
+    user_id: Uuid,
+    file_upload_request: FileUploadRequest,
+) -> Result<(), Box<dyn SPTFError>> {
+    upload_real_paths(root_path, &file_upload_request)?;
+    let dir_path = Path::new(file_upload_request.get_dir_path());
+    let mut uploads = Vec::new();
+    let mut upload_bytes = 0;
+    for file in file_upload_request.get_uploaded_file() {
+        let key = owner_key(&dir_path.join(file.get_file_name()))?;
+        let size_bytes = file.get_content().len() as i64;
+        let previous_owner = file_owner(database, &key).await?;
+        upload_bytes += size_bytes;
+        if let Some((owner_id, previous_size_bytes)) = previous_owner {
+            if owner_id == user_id {
+                upload_bytes -= previous_size_bytes;
+            }
+        }
+        uploads.push((key, size_bytes, previous_owner));
+    }
+    if upload_bytes > 0 {
+        crate::user::reserve_quota(database, user_id, upload_bytes).await?;
+    }
+    if let Err(err) = upload_files(root_path, file_upload_request).await {
+        if upload_bytes > 0 {
+            if let Err(err) = crate::user::release_quota(database, user_id, upload_bytes).await {
+                error!(
+                    "Failed to release quota after failed upload, error code {}",
+                    err.error_code()
+                );
+            }
+        }
+        return Err(err);
+    }
+
+    let mut statements = Vec::new();
+    for (key, size_bytes, previous_owner) in uploads {
+        if let Some((owner_id, previous_size_bytes)) = previous_owner {
+            if owner_id != user_id {
+                statements.push((
+                    crate::user::RELEASE_QUOTA_STATEMENT,
+                    vec![previous_size_bytes.into(), owner_id.into()],
+                ));
+            }
+        }
+        statements.push((
+            "INSERT INTO FileOwners (path, user_id, size_bytes) VALUES ($1, $2, $3) ON CONFLICT (path) DO UPDATE SET user_id=excluded.user_id, size_bytes=excluded.size_bytes",
+            vec![key.into(), user_id.into(), size_bytes.into()],
+        ));
+    }
+    if upload_bytes < 0 {
+        statements.push((
+            crate::user::RELEASE_QUOTA_STATEMENT,
+            vec![(-upload_bytes).into(), user_id.into()],
+        ));
+    }
+    database
+        .transaction(
+            &statements
+                .iter()
+                .map(|(statement, params)| (*statement, &params[..]))
+                .collect::<Vec<_>>(),
+        )
+        .await
+        .map_err(owner_error)?;
+    Ok(())
+}
+
+/// Name and metadata of a single file or directory
+pub async fn stat(
+    root_path: &Path,
+    path: &Path,
+) -> Result<DirectoryLayout_File, Box<dyn SPTFError>> {
+    let real_path = checked_real_path(root_path, path)?;
+    let metadata = tokio::fs::metadata(&real_path).await.map_err(|err| {
+        error!("Failed to stat {:?}: {}", real_path, err);
+        io_error(&err)
+    })?;
+    file_entry(path, &metadata)
+}
+
+/// Name and metadata of a file or directory at given real path, as found
+/// in directory listings
+pub fn file_entry_at(
+    root_path: &Path,
+    real_path: &Path,
+) -> Result<DirectoryLayout_File, Box<dyn SPTFError>> {
+    let path = user_aware_path(root_path, real_path).ok_or_else(|| {
+        error!("Unexpected error here: cannot convert real path to user aware path");
+        UnexpectedError.to_boxed_self()
+    })?;
+    let metadata = fs::metadata(real_path).map_err(|err| {
+        error!("Failed to stat {:?}: {}", real_path, err);
+        io_error(&err)
+    })?;
+    file_entry(&path, &metadata)
+}
+
+fn file_entry(
+    path: &Path,
+    metadata: &fs::Metadata,
+) -> Result<DirectoryLayout_File, Box<dyn SPTFError>> {
+    let mut file_metadata = DirectoryLayout_FileMetadata::default();
+    file_metadata.set_file_type(if metadata.is_dir() {
+        DirectoryLayout_FileMetadata_FileType::DIRECTORY
+    } else {
+        DirectoryLayout_FileMetadata_FileType::NORMAL_FILE
+    });
+    file_metadata.set_size(metadata.len());
+    file_metadata.set_modified_timestamp(retrieve_timestamp(metadata.modified())?);
+    file_metadata.set_accessed_timestamp(retrieve_timestamp(metadata.accessed())?);
+    file_metadata.set_created_timestamp(retrieve_timestamp(metadata.created())?);
+    let mut file = DirectoryLayout_File::default();
+    file.set_path((*path.to_string_lossy()).into());
+    file.set_file_name(
+        path.file_name()
+            .map(|file_name| file_name.to_string_lossy().to_string())
+            .unwrap_or_default()
+            .into(),
+    );
+    file.set_metadata(file_metadata);
+    Ok(file)
+}
+
+/// Read a whole file, which must not be larger than given size
+pub async fn read_file(
+    root_path: &Path,
+    path: &Path,
+    max_size: usize,
+) -> Result<Vec<u8>, Box<dyn SPTFError>> {
+    let real_path = checked_real_path(root_path, path)?;
+    let metadata = tokio::fs::metadata(&real_path).await.map_err(|err| {
+        error!("Failed to stat {:?}: {}", real_path, err);
+        io_error(&err)
+    })?;
+    if !metadata.is_file() {
+        return Err(FileError::PermissionDenied.to_boxed_self());
+    }
+    if metadata.len() > max_size as u64 {
+        return Err(FileError::TooLarge.to_boxed_self());
+    }
+    tokio::fs::read(&real_path).await.map_err(|err| {
+        error!("Failed to read {:?}: {}", real_path, err);
+        io_error(&err)
+    })
+}
+
+/// Real path of an existing directory, to be watched for changes
+pub fn watched_directory(root_path: &Path, path: &Path) -> Result<PathBuf, Box<dyn SPTFError>> {
+    let real_path = checked_real_path(root_path, path)?;
+    match fs::metadata(&real_path) {
+        Ok(metadata) if metadata.is_dir() => Ok(real_path),
+        Ok(_) => Err(FileError::InvalidPath.to_boxed_self()),
+        Err(err) => Err(io_error(&err)),
+    }
+}
+
+/// Delete a file, or a directory with everything inside, giving the bytes
+/// of the files back to the users who uploaded them
+pub async fn delete(
+    database: &dyn Database,
+    root_path: &Path,
+    path: &Path,
+) -> Result<(), Box<dyn SPTFError>> {
+    let real_path = real_path_below_root(root_path, path)?;
+    let key = owner_key(path)?;
+    let metadata = tokio::fs::symlink_metadata(&real_path)
+        .await
+        .map_err(|err| {
+            error!("Failed to stat {:?}: {}", real_path, err);
+            io_error(&err)
+        })?;
+    let delete_result = if metadata.is_dir() {
+        tokio::fs::remove_dir_all(&real_path).await
+    } else {
+        tokio::fs::remove_file(&real_path).await
+    };
+    delete_result.map_err(|err| {
+        error!("Failed to delete {:?}: {}", real_path, err);
+        io_error(&err)
+    })?;
+
+    let [release_statement, forget_statement] = release_owned_below_statements();
+    let params = owned_below_params(&key);
+    database
+        .transaction(&[(&release_statement, &params), (&forget_statement, &params)])
+        .await
+        .map_err(owner_error)?;
+    Ok(())
+}
+
+/// Move a file or directory, never replacing what is at the destination.
+///
+/// Files keep their owners
+pub async fn rename(
+    database: &dyn Database,
+    root_path: &Path,
+    from_path: &Path,
+    to_path: &Path,
+) -> Result<(), Box<dyn SPTFError>> {
+    let real_from_path = real_path_below_root(root_path, from_path)?;
+    let real_to_path = real_path_below_root(root_path, to_path)?;
+    let (from_key, to_key) = (owner_key(from_path)?, owner_key(to_path)?);
+    if real_to_path.starts_with(&real_from_path) {
+        // Moving a directory into itself
+        return Err(FileError::InvalidPath.to_boxed_self());
+    }
+    if tokio::fs::symlink_metadata(&real_to_path).await.is_ok() {
+        return Err(FileError::AlreadyExists.to_boxed_self());
+    }
+    tokio::fs::rename(&real_from_path, &real_to_path)
+        .await
+        .map_err(|err| {
+            error!(
+                "Failed to rename {:?} to {:?}: {}",
+                real_from_path, real_to_path, err
+            );
+            io_error(&err)
+        })?;
+
+    // Owners left at the destination are of files removed behind our back
+    let [release_statement, forget_statement] = release_owned_below_statements();
+    let to_params = owned_below_params(&to_key);
+    let [from_key_param, from_pattern_param] = owned_below_params(&from_key);
+    let move_params = [
+        from_key_param,
+        from_pattern_param,
+        to_key.into(),
+        (from_key.chars().count() as i64 + 1).into(),
+    ];
+    let move_statement = format!(
+        "UPDATE FileOwners SET path=CAST($3 AS TEXT) || substr(path, $4) WHERE {}",
+        OWNED_BELOW_CONDITION
+    );
+    database
+        .transaction(&[
+            (&release_statement, &to_params),
+            (&forget_statement, &to_params),
+            (&move_statement, &move_params),
+        ])
+        .await
+        .map_err(owner_error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checked_real_path, is_plain_file_name};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn plain_file_names_only() {
+        assert!(is_plain_file_name("notes.txt"));
+        assert!(is_plain_file_name("..notes"));
+        for file_name in [
+            "",
+            ".",
+            "..",
+            "../notes",
+            "a/b",
+            "/notes",
+            "a\\b",
+            "..\\notes",
+        ] {
+            assert!(!is_plain_file_name(file_name), "{:?}", file_name);
+        }
+    }
+
+    #[test]
+    fn checked_real_path_stays_below_root() {
+        let root_path = Path::new("/srv/files");
+        assert_eq!(
+            checked_real_path(root_path, Path::new("/a/./b")).ok(),
+            Some(PathBuf::from("/srv/files/a/b"))
+        );
+        assert!(checked_real_path(root_path, Path::new("/a/../b")).is_err());
+        assert!(checked_real_path(root_path, Path::new("../etc")).is_err());
+        assert!(checked_real_path(root_path, Path::new("/a/../../etc")).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    mod owners {
+        use crate::database::Database;
+        use crate::files::{delete, rename, upload_files_within_quota};
+        use crate::protos::sptf::{FileUploadRequest, FileUploadRequest_UploadedFile};
+        use crate::sqlite_database::SqliteDatabase;
+        use std::path::Path;
+        use tempfile::TempDir;
+        use uuid::Uuid;
+
+        async fn add_user(database: &dyn Database, username: &str) -> Uuid {
+            crate::user::signup_user(
+                database,
+                username,
+                "password",
+                crate::auth::Role::Member,
+                Some(100),
+            )
+            .await
+            .unwrap()
+        }
+
+        async fn used_bytes(database: &dyn Database, user_id: Uuid) -> i64 {
+            database
+                .query_one(
+                    "SELECT used_bytes FROM Users WHERE id=$1",
+                    &[user_id.into()],
+                )
+                .await
+                .unwrap()
+                .try_get(0)
+                .unwrap()
+        }
+
+        async fn upload(
+            database: &dyn Database,
+            root_path: &Path,
+            user_id: Uuid,
+            dir_path: &str,
+            file_name: &str,
+            size: usize,
+        ) -> bool {
+            let mut file = FileUploadRequest_UploadedFile::new();
+            file.set_file_name(file_name.into());
+            file.set_content(vec![0; size].into());
+            let mut file_upload_request = FileUploadRequest::new();
+            file_upload_request.set_dir_path(dir_path.into());
+            file_upload_request.mut_uploaded_file().push(file);
+            upload_files_within_quota(database, root_path, user_id, file_upload_request)
+                .await
+                .is_ok()
+        }
+
+        #[actix_web::test]
+        async fn deleting_gives_bytes_back_to_uploaders() {
+            let root = TempDir::new().unwrap();
+            let root_path = root.path();
+            let database = SqliteDatabase::new(Path::new(":memory:"));
+            database.run_migrations().await.unwrap();
+            let (alice, bob) = (
+                add_user(&database, "alice").await,
+                add_user(&database, "bob").await,
+            );
+            std::fs::create_dir_all(root_path.join("a/b")).unwrap();
+            std::fs::create_dir_all(root_path.join("ab")).unwrap();
+
+            assert!(upload(&database, root_path, alice, "/a", "x", 60).await);
+            assert!(!upload(&database, root_path, alice, "/a", "y", 60).await);
+            // Overwriting one's own file only counts the growth
+            assert!(upload(&database, root_path, alice, "/a", "x", 90).await);
+            assert_eq!(used_bytes(&database, alice).await, 90);
+            assert!(upload(&database, root_path, alice, "/a", "x", 30).await);
+            assert_eq!(used_bytes(&database, alice).await, 30);
+
+            // Overwriting a file of another user moves its bytes over
+            assert!(upload(&database, root_path, bob, "/a", "x", 20).await);
+            assert_eq!(used_bytes(&database, alice).await, 0);
+            assert_eq!(used_bytes(&database, bob).await, 20);
+
+            assert!(upload(&database, root_path, alice, "/a/b", "y", 40).await);
+            assert!(upload(&database, root_path, alice, "/ab", "z", 50).await);
+            assert_eq!(used_bytes(&database, alice).await, 90);
+            rename(&database, root_path, Path::new("/a"), Path::new("/c"))
+                .await
+                .unwrap();
+            // Only what is inside the directory, not what starts like it
+            delete(&database, root_path, Path::new("/c")).await.unwrap();
+            assert_eq!(used_bytes(&database, alice).await, 50);
+            assert_eq!(used_bytes(&database, bob).await, 0);
+
+            delete(&database, root_path, Path::new("/ab/z"))
+                .await
+                .unwrap();
+            assert_eq!(used_bytes(&database, alice).await, 0);
+            // The whole quota is free again
+            assert!(upload(&database, root_path, alice, "/", "x", 100).await);
+        }
+
+        #[actix_web::test]
+        async fn wildcards_in_paths_match_only_themselves() {
+            let root = TempDir::new().unwrap();
+            let root_path = root.path();
+            let database = SqliteDatabase::new(Path::new(":memory:"));
+            database.run_migrations().await.unwrap();
+            let alice = add_user(&database, "alice").await;
+            for dir_path in ["%", "_b", "A"] {
+                std::fs::create_dir_all(root_path.join(dir_path)).unwrap();
+            }
+            std::fs::create_dir_all(root_path.join("a")).unwrap();
+
+            assert!(upload(&database, root_path, alice, "/_b", "x", 10).await);
+            assert!(upload(&database, root_path, alice, "/A", "x", 20).await);
+            assert!(upload(&database, root_path, alice, "/%", "x", 30).await);
+            delete(&database, root_path, Path::new("/a")).await.unwrap();
+            assert_eq!(used_bytes(&database, alice).await, 60);
+            delete(&database, root_path, Path::new("/%")).await.unwrap();
+            assert_eq!(used_bytes(&database, alice).await, 30);
+        }
+    }
+}
@@ -1,10 +1,26 @@
 use crate::messages::*;
 use actix::prelude::*;
-use log::{error, info};
+use log::{error, info, warn};
 use notify::DebouncedEvent;
 use std::path::PathBuf;
 use std::sync::mpsc::Receiver;
 
+/// What happened to a file, as told by the watcher
+#[derive(Clone)]
+pub enum FileChange {
+    Created(PathBuf),
+    /// Content or permissions changed
+    Modified(PathBuf),
+    Removed(PathBuf),
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Changes at given path and below may have been missed, or anywhere
+    /// if None, like when the watcher errored or its queue overflowed
+    Unreliable(Option<PathBuf>),
+}
+
 pub struct FileWatcherActor {
     manager_address: Addr<crate::manager::SessionManager>,
     rx: Receiver<DebouncedEvent>,
@@ -33,50 +49,60 @@ impl Actor for FileWatcherActor {
 impl Handler<StartWatchingFiles> for FileWatcherActor {
     type Result = ();
     fn handle(&mut self, _msg: StartWatchingFiles, _ctx: &mut Self::Context) -> Self::Result {
-        let mut changed_paths = vec![];
+        let mut changes = vec![];
         while let Ok(debounced_event) = self.rx.recv() {
-            retrieve_changed_paths_and_append_to_vec(debounced_event, &mut changed_paths);
+            retrieve_changes_and_append_to_vec(debounced_event, &mut changes);
             while let Ok(debounced_event) = self.rx.try_recv() {
-                retrieve_changed_paths_and_append_to_vec(debounced_event, &mut changed_paths);
+                retrieve_changes_and_append_to_vec(debounced_event, &mut changes);
+            }
+            if !changes.is_empty() {
+                self.manager_address
+                    .do_send(FilePathHasSomethingChanged { changes });
             }
-            self.manager_address.do_send(FilePathHasSomethingChanged {
-                paths: changed_paths,
-            });
-            changed_paths = vec![];
+            changes = vec![];
         }
     }
 }
 
-/// Use this pattern since there may be more than one retrieved path for one debounced event
-fn retrieve_changed_paths_and_append_to_vec(
+/// Use this pattern since debounced events may come in batches
+fn retrieve_changes_and_append_to_vec(
     debounced_event: DebouncedEvent,
-    changed_paths: &mut Vec<PathBuf>,
+    changes: &mut Vec<FileChange>,
 ) {
     use DebouncedEvent::*;
     match debounced_event {
         Create(path) => {
             info!("Detect file created at {:?}", path);
-            changed_paths.push(path);
+            changes.push(FileChange::Created(path));
         }
         Write(path) => {
             info!("Detect file written at {:?}", path);
-            changed_paths.push(path);
+            changes.push(FileChange::Modified(path));
+        }
+        Chmod(path) => {
+            info!("Detect file permissions changed at {:?}", path);
+            changes.push(FileChange::Modified(path));
         }
         Remove(path) => {
             info!("Detect file removed at {:?}", path);
-            changed_paths.push(path);
+            changes.push(FileChange::Removed(path));
         }
         Rename(from, to) => {
             info!("Detect file renamed from {:?} to {:?}", from, to);
-            changed_paths.push(from);
-            changed_paths.push(to);
+            changes.push(FileChange::Renamed { from, to });
+        }
+        Rescan => {
+            warn!("File watcher lost track of changes, rescanning");
+            changes.push(FileChange::Unreliable(None));
         }
         Error(error, path) => {
             error!(
                 "Error detected in file watcher at path {:?}: {}",
                 path, error
             );
+            changes.push(FileChange::Unreliable(path));
         }
-        _ => {}
+        // Followed by the matching write or remove
+        NoticeWrite(_) | NoticeRemove(_) => {}
     }
 }
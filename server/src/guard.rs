@@ -0,0 +1,241 @@
+use crate::auth::{AuthenticatedUser, Credential, Scope};
+use crate::error::{SPTFError, UnexpectedError, ValidateError};
+use crate::user::SessionUse;
+use crate::{api_token, common, csrf, user, AppData};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    error::InternalError,
+    http::{header, StatusCode},
+    web, Error, FromRequest, HttpMessage, HttpRequest,
+};
+use log::{error, info};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+/// Routes reachable without credential, every other route is rejected by
+/// `Authentication` unless the request is authenticated
+pub const PUBLIC_ROUTES: &[&str] = &[
+    "/login",
+    "/login/certificate",
+    "/login/second_factor",
+    "/signup",
+    "/reset_password",
+    "/oidc/login",
+    "/oidc/callback",
+    // Clears the auth cookie even if it is no longer valid
+    "/logout",
+    // Also accepts the credential in the first websocket message
+    "/ws",
+];
+
+pub fn is_public_route(path: &str) -> bool {
+    PUBLIC_ROUTES.contains(&path)
+}
+
+/// Token carried by `Authorization: Bearer` header
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Validate either an auth token or an API token
+pub async fn validate_token(
+    token: &str,
+    app_data: &web::Data<AppData>,
+    session_use: SessionUse,
+) -> Result<AuthenticatedUser, Box<dyn SPTFError>> {
+    let (user_id, credential) = if token.starts_with(api_token::API_TOKEN_PREFIX) {
+        api_token::validate_api_token(app_data.database.as_ref(), token).await?
+    } else {
+        let user_id =
+            user::validate_auth_token(app_data.session_store.as_ref(), token, session_use).await?;
+        (
+            user_id,
+            Credential::Session {
+                auth_token: token.to_owned(),
+            },
+        )
+    };
+    // Looked up on every request, so role changes and disabling apply at once
+    let role = user::get_role(app_data.database.as_ref(), user_id).await?;
+    Ok(AuthenticatedUser {
+        user_id,
+        role,
+        credential,
+    })
+}
+
+/// Validate bearer header, or auth cookie if there is no such header.
+///
+/// Cookie-authenticated requests changing state must pass the origin check
+pub async fn authenticate_request(
+    req: &HttpRequest,
+    app_data: &web::Data<AppData>,
+) -> Result<AuthenticatedUser, Box<dyn SPTFError>> {
+    let token = if let Some(token) = bearer_token(req) {
+        token.to_owned()
+    } else if let Some(cookie) = req.cookie(common::COOKIE_AUTH_TOKEN_NAME) {
+        // Browsers attach cookies to requests forged by other sites, bearer headers not
+        csrf::check_origin(req, &app_data.auth_cookie.allowed_origins)?;
+        cookie.value().to_owned()
+    } else {
+        return Err(ValidateError::WrongCookie.to_boxed_self());
+    };
+    let authenticated_user = validate_token(&token, app_data, SessionUse::Touch).await?;
+    match &authenticated_user.credential {
+        Credential::Session { .. } => info!(
+            "User with id {} succesfully validated",
+            authenticated_user.user_id
+        ),
+        Credential::ApiToken { token_id, .. } => info!(
+            "User with id {} succesfully validated with api token {}",
+            authenticated_user.user_id, token_id
+        ),
+    }
+    Ok(authenticated_user)
+}
+
+/// Middleware authenticating every request to a route not in PUBLIC_ROUTES,
+/// leaving the user in request extensions for the extractors below.
+///
+/// Requests without a valid credential get 401 with the error as usual
+pub struct Authentication;
+
+impl<S, B> Transform<S, ServiceRequest> for Authentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            if !is_public_route(req.path()) {
+                let authenticate_result = match req.app_data::<web::Data<AppData>>() {
+                    Some(app_data) => authenticate_request(req.request(), app_data).await,
+                    None => {
+                        error!("App data is missing");
+                        Err(UnexpectedError.to_boxed_self())
+                    }
+                };
+                match authenticate_result {
+                    Ok(authenticated_user) => {
+                        req.extensions_mut().insert(Rc::new(authenticated_user));
+                    }
+                    Err(err) => {
+                        let mut response = err.to_http_response();
+                        // Failing to check a credential is not the client's fault
+                        if err.error_code() != UnexpectedError.error_code() {
+                            *response.status_mut() = StatusCode::UNAUTHORIZED;
+                        }
+                        return Ok(req.into_response(response).map_into_right_body());
+                    }
+                }
+            }
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+/// User left by `Authentication`, failing if it does not pass given check
+fn authenticated_user_of(
+    req: &HttpRequest,
+    check: impl FnOnce(&AuthenticatedUser) -> Result<(), Box<dyn SPTFError>>,
+) -> Result<Rc<AuthenticatedUser>, Error> {
+    let authenticated_user = req
+        .extensions()
+        .get::<Rc<AuthenticatedUser>>()
+        .cloned()
+        .ok_or_else(|| {
+            // Extracted on a public route, which is a programming error
+            error!("No authenticated user on {}", req.path());
+            ValidateError::WrongCookie.to_boxed_self()
+        })
+        .and_then(|authenticated_user| {
+            check(&authenticated_user)?;
+            Ok(authenticated_user)
+        });
+    authenticated_user
+        .map_err(|err| InternalError::from_response("", err.to_http_response()).into())
+}
+
+/// Any authenticated user
+pub struct AnyUser(pub Rc<AuthenticatedUser>);
+
+impl FromRequest for AnyUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticated_user_of(req, |_| Ok(())).map(AnyUser))
+    }
+}
+
+/// User authenticated with a login session rather than an API token
+pub struct SessionUser(pub Rc<AuthenticatedUser>);
+
+impl FromRequest for SessionUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            authenticated_user_of(req, |authenticated_user| {
+                authenticated_user.require_session().map(|_| ())
+            })
+            .map(SessionUser),
+        )
+    }
+}
+
+/// User allowed to administrate the server
+pub struct AdminUser(pub Rc<AuthenticatedUser>);
+
+impl FromRequest for AdminUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            authenticated_user_of(req, |authenticated_user| {
+                authenticated_user.require_scope(Scope::Admin)
+            })
+            .map(AdminUser),
+        )
+    }
+}
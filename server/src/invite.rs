@@ -0,0 +1,112 @@
+use crate::auth::Role;
+use crate::common::unix_timestamp;
+use crate::database::{Database, DatabaseError};
+use crate::error::{InviteError, SPTFError, UnexpectedError};
+use log::error;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Invite as shown to admins, without the code itself
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InviteInfo {
+    pub id: String,
+    pub role: Option<String>,
+    pub quota_bytes: Option<i64>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub created_at: i64,
+}
+
+/// Invite codes are only stored hashed, see `user::signup_invited_user`
+pub fn hash_code(code: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(code);
+    hasher.finalize().to_vec()
+}
+
+/// Create an invite code.
+///
+/// Invite is usable for any number of times if `max_uses` is None.
+/// Return the code, which is only stored hashed and cannot be shown again
+pub async fn create_invite(
+    database: &dyn Database,
+    created_by: Uuid,
+    role: Option<Role>,
+    quota_bytes: Option<i64>,
+    max_uses: Option<i32>,
+) -> Result<String, Box<dyn SPTFError>> {
+    let id = Uuid::new_v4();
+    let code = Uuid::new_v4().to_simple().to_string();
+    database
+        .execute(
+            "INSERT INTO InviteCodes (id, code_hash, created_by, role, quota_bytes, max_uses, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                id.into(),
+                hash_code(&code).into(),
+                created_by.into(),
+                role.map(|role| role.name()).into(),
+                quota_bytes.into(),
+                max_uses.into(),
+                unix_timestamp().into(),
+            ],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to create invite: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    Ok(code)
+}
+
+/// List all invites
+pub async fn list_invites(database: &dyn Database) -> Result<Vec<InviteInfo>, Box<dyn SPTFError>> {
+    let rows = database
+        .query(
+            "SELECT id, role, quota_bytes, max_uses, uses, created_at FROM InviteCodes ORDER BY created_at",
+            &[],
+        )
+        .await
+        .map_err(|err| {
+            error!("Query invites failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    rows.iter()
+        .map(|row| {
+            let id: Uuid = row.try_get(0)?;
+            Ok(InviteInfo {
+                id: id.to_string(),
+                role: row.try_get(1)?,
+                quota_bytes: row.try_get(2)?,
+                max_uses: row.try_get(3)?,
+                uses: row.try_get(4)?,
+                created_at: row.try_get(5)?,
+            })
+        })
+        .collect::<Result<Vec<_>, DatabaseError>>()
+        .map_err(|err| {
+            error!("Fetch invite fields failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        })
+}
+
+/// Revoke invite with given id
+pub async fn revoke_invite(
+    database: &dyn Database,
+    invite_id_str: &str,
+) -> Result<(), Box<dyn SPTFError>> {
+    let invite_id =
+        Uuid::parse_str(invite_id_str).map_err(|_| InviteError::NotFound.to_boxed_self())?;
+    let revoked = database
+        .execute("DELETE FROM InviteCodes WHERE id=$1", &[invite_id.into()])
+        .await
+        .map_err(|err| {
+            error!("Failed to revoke invite {}: {}", invite_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    if revoked == 0 {
+        return Err(InviteError::NotFound.to_boxed_self());
+    }
+    Ok(())
+}
@@ -0,0 +1,399 @@
+use crate::auth::{AuthSource, Role};
+use crate::authenticator::Authenticator;
+use crate::config::LdapConfig;
+use crate::database::Database;
+use crate::error::{LdapError, SPTFError, SignupError};
+use async_trait::async_trait;
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, Scope as LdapScope, SearchEntry};
+use log::{error, warn};
+use uuid::Uuid;
+
+/// LDAP result code of a bind with wrong credentials
+const INVALID_CREDENTIALS_RESULT_CODE: u32 = 49;
+
+fn unavailable(err: ldap3::LdapError) -> Box<dyn SPTFError> {
+    error!("LDAP request failed: {}", err);
+    LdapError::Unavailable.to_boxed_self()
+}
+
+/// Authenticate by binding to an LDAP directory as the user, keeping a
+/// shadow user in Users for everything else
+pub struct LdapAuthenticator {
+    config: LdapConfig,
+    default_role: Option<Role>,
+}
+
+impl LdapAuthenticator {
+    /// Will panic if default role in config is not a role
+    pub fn new(config: LdapConfig) -> Self {
+        let default_role = config
+            .default_role
+            .as_deref()
+            .map(|role| Role::from_name(role).expect("Unknown LDAP default role"));
+        Self {
+            config,
+            default_role,
+        }
+    }
+
+    /// Find DN of given user, searching with the search account if there is no template
+    async fn user_dn(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+    ) -> Result<Option<String>, Box<dyn SPTFError>> {
+        if let Some(user_dn_template) = &self.config.user_dn_template {
+            return Ok(Some(
+                user_dn_template.replace("{username}", &dn_escape(username)),
+            ));
+        }
+        if let (Some(search_bind_dn), Some(search_bind_password)) = (
+            &self.config.search_bind_dn,
+            &self.config.search_bind_password,
+        ) {
+            ldap.simple_bind(search_bind_dn, search_bind_password)
+                .await
+                .and_then(|result| result.success())
+                .map_err(unavailable)?;
+        }
+        let (entries, _) = ldap
+            .search(
+                self.config.user_search_base.as_deref().unwrap_or_default(),
+                LdapScope::Subtree,
+                &self
+                    .config
+                    .user_filter
+                    .replace("{username}", &ldap_escape(username)),
+                vec!["1.1"],
+            )
+            .await
+            .and_then(|result| result.success())
+            .map_err(unavailable)?;
+        match &entries[..] {
+            [entry] => Ok(Some(SearchEntry::construct(entry.clone()).dn)),
+            [] => Ok(None),
+            _ => {
+                warn!("LDAP search for user {} returns multiple entries", username);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Highest role granted by the groups of given user
+    async fn role(&self, ldap: &mut Ldap, user_dn: &str) -> Result<Role, Box<dyn SPTFError>> {
+        let (entries, _) = ldap
+            .search(
+                &self.config.group_search_base,
+                LdapScope::Subtree,
+                &self
+                    .config
+                    .group_filter
+                    .replace("{user_dn}", &ldap_escape(user_dn)),
+                vec!["1.1"],
+            )
+            .await
+            .and_then(|result| result.success())
+            .map_err(unavailable)?;
+        let group_dns = entries
+            .into_iter()
+            .map(|entry| SearchEntry::construct(entry).dn.to_lowercase())
+            .collect::<Vec<_>>();
+        let in_any = |groups: &[String]| {
+            groups
+                .iter()
+                .any(|group| group_dns.contains(&group.to_lowercase()))
+        };
+        if in_any(&self.config.admin_groups) {
+            Ok(Role::Admin)
+        } else if in_any(&self.config.member_groups) {
+            Ok(Role::Member)
+        } else if in_any(&self.config.read_only_groups) {
+            Ok(Role::ReadOnly)
+        } else {
+            self.default_role
+                .ok_or_else(|| LdapError::NoRole.to_boxed_self())
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Authenticator for LdapAuthenticator {
+    async fn authenticate(
+        &self,
+        database: &dyn Database,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Uuid>, Box<dyn SPTFError>> {
+        // An empty password would make an unauthenticated bind, which succeeds
+        if password.is_empty() {
+            return Ok(None);
+        }
+        let (connection, mut ldap) = LdapConnAsync::new(&self.config.url)
+            .await
+            .map_err(unavailable)?;
+        ldap3::drive!(connection);
+
+        let user_dn = match self.user_dn(&mut ldap, username).await? {
+            Some(user_dn) => user_dn,
+            None => return Ok(None),
+        };
+        let bind_result = ldap
+            .simple_bind(&user_dn, password)
+            .await
+            .map_err(unavailable)?;
+        if bind_result.rc == INVALID_CREDENTIALS_RESULT_CODE {
+            return Ok(None);
+        }
+        bind_result.success().map_err(unavailable)?;
+        let role = self.role(&mut ldap, &user_dn).await?;
+        let _ = ldap.unbind().await;
+
+        match crate::user::sync_shadow_user(database, username, role, AuthSource::Ldap).await {
+            Ok(user_id) => Ok(Some(user_id)),
+            Err(err) if err.error_code() == SignupError::UsernameExist.error_code() => {
+                warn!(
+                    "LDAP user {} clashes with a user of another source",
+                    username
+                );
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Run against the `ldap` service of docker-compose.yml with
+/// `docker compose --profile ldap-test up -d` and `cargo test -- --ignored`.
+///
+/// Each run seeds users and groups under an organizational unit of its own
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::LdapAuthenticator;
+    use crate::auth::Role;
+    use crate::authenticator::Authenticator;
+    use crate::config::LdapConfig;
+    use crate::database::Database;
+    use crate::error::{LdapError, SPTFError};
+    use crate::sqlite_database::SqliteDatabase;
+    use ldap3::{Ldap, LdapConnAsync, Mod};
+    use std::collections::HashSet;
+    use std::path::Path;
+    use uuid::Uuid;
+
+    const BASE_DN: &str = "dc=sptf,dc=local";
+    const ADMIN_DN: &str = "cn=admin,dc=sptf,dc=local";
+    /// LDAP result code of adding a value an attribute already has
+    const VALUE_EXISTS_RESULT_CODE: u32 = 20;
+
+    fn url() -> String {
+        let port = std::env::var("LDAP_HOST_PORT").expect("LDAP_HOST_PORT is not set");
+        format!("ldap://localhost:{}", port)
+    }
+
+    fn admin_password() -> String {
+        std::env::var("LDAP_ADMIN_PASSWORD").expect("LDAP_ADMIN_PASSWORD is not set")
+    }
+
+    async fn bind(dn: &str, password: &str) -> Ldap {
+        let (connection, mut ldap) = LdapConnAsync::new(&url()).await.unwrap();
+        ldap3::drive!(connection);
+        ldap.simple_bind(dn, password)
+            .await
+            .and_then(|result| result.success())
+            .unwrap();
+        ldap
+    }
+
+    async fn add(ldap: &mut Ldap, dn: &str, attributes: &[(&str, &[&str])]) {
+        ldap.add(
+            dn,
+            attributes
+                .iter()
+                .map(|(name, values)| (*name, values.iter().copied().collect::<HashSet<_>>()))
+                .collect(),
+        )
+        .await
+        .and_then(|result| result.success())
+        .unwrap();
+    }
+
+    /// Let bound users read the directory, which the image only lets its
+    /// admin do, as groups are searched for as the user
+    async fn allow_users_to_read() {
+        let config_password =
+            std::env::var("LDAP_CONFIG_PASSWORD").unwrap_or_else(|_| "config".to_owned());
+        let mut ldap = bind("cn=admin,cn=config", &config_password).await;
+        let result = ldap
+            .modify(
+                "olcDatabase={1}mdb,cn=config",
+                vec![Mod::Add(
+                    "olcAccess",
+                    HashSet::from(["{2}to * by self read by users read by * break"]),
+                )],
+            )
+            .await
+            .unwrap();
+        if result.rc != VALUE_EXISTS_RESULT_CODE {
+            result.success().unwrap();
+        }
+        let _ = ldap.unbind().await;
+    }
+
+    /// Users alice in the admins group, bob in the members group and carol
+    /// in none, all with password "secret", under returned base DN
+    async fn seed_directory() -> String {
+        allow_users_to_read().await;
+        let mut ldap = bind(ADMIN_DN, &admin_password()).await;
+        let run_dn = format!("ou={},{}", Uuid::new_v4(), BASE_DN);
+        add(
+            &mut ldap,
+            &run_dn,
+            &[("objectClass", &["organizationalUnit"])],
+        )
+        .await;
+        for username in ["alice", "bob", "carol"] {
+            add(
+                &mut ldap,
+                &format!("uid={},{}", username, run_dn),
+                &[
+                    ("objectClass", &["inetOrgPerson"]),
+                    ("uid", &[username]),
+                    ("cn", &[username]),
+                    ("sn", &[username]),
+                    ("userPassword", &["secret"]),
+                ],
+            )
+            .await;
+        }
+        for (group, username) in [("admins", "alice"), ("members", "bob")] {
+            add(
+                &mut ldap,
+                &format!("cn={},{}", group, run_dn),
+                &[
+                    ("objectClass", &["groupOfNames"]),
+                    ("cn", &[group]),
+                    ("member", &[&format!("uid={},{}", username, run_dn)]),
+                ],
+            )
+            .await;
+        }
+        let _ = ldap.unbind().await;
+        run_dn
+    }
+
+    fn test_config(run_dn: &str) -> LdapConfig {
+        LdapConfig {
+            url: url(),
+            user_dn_template: Some(format!("uid={{username}},{}", run_dn)),
+            search_bind_dn: None,
+            search_bind_password: None,
+            user_search_base: None,
+            user_filter: "(uid={username})".to_owned(),
+            group_search_base: run_dn.to_owned(),
+            group_filter: "(member={user_dn})".to_owned(),
+            admin_groups: vec![format!("cn=admins,{}", run_dn)],
+            member_groups: vec![format!("cn=members,{}", run_dn)],
+            read_only_groups: vec![],
+            default_role: None,
+        }
+    }
+
+    async fn test_database() -> SqliteDatabase {
+        let database = SqliteDatabase::new(Path::new(":memory:"));
+        database.run_migrations().await.unwrap();
+        database
+    }
+
+    /// Role of the shadow user given username logs in as
+    async fn login_role(
+        authenticator: &LdapAuthenticator,
+        database: &dyn Database,
+        username: &str,
+    ) -> Result<Option<Role>, Box<dyn SPTFError>> {
+        match authenticator
+            .authenticate(database, username, "secret")
+            .await?
+        {
+            Some(user_id) => Ok(Some(crate::user::get_role(database, user_id).await?)),
+            None => Ok(None),
+        }
+    }
+
+    #[ignore]
+    #[actix_web::test]
+    async fn bind_maps_groups_to_roles() {
+        let run_dn = seed_directory().await;
+        let database = test_database().await;
+        let authenticator = LdapAuthenticator::new(test_config(&run_dn));
+
+        assert!(
+            login_role(&authenticator, &database, "alice")
+                .await
+                .unwrap()
+                == Some(Role::Admin)
+        );
+        assert!(login_role(&authenticator, &database, "bob").await.unwrap() == Some(Role::Member));
+        assert_eq!(
+            login_role(&authenticator, &database, "carol")
+                .await
+                .err()
+                .unwrap()
+                .error_code(),
+            LdapError::NoRole.error_code()
+        );
+
+        assert!(authenticator
+            .authenticate(&database, "alice", "wrong")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(authenticator
+            .authenticate(&database, "alice", "")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(login_role(&authenticator, &database, "dave")
+            .await
+            .unwrap()
+            .is_none());
+        // Rebinding finds the shadow user created on first login
+        let user_id = authenticator
+            .authenticate(&database, "alice", "secret")
+            .await
+            .unwrap();
+        assert_eq!(
+            authenticator
+                .authenticate(&database, "alice", "secret")
+                .await
+                .unwrap(),
+            user_id
+        );
+    }
+
+    #[ignore]
+    #[actix_web::test]
+    async fn search_finds_users_and_default_role_applies() {
+        let run_dn = seed_directory().await;
+        let database = test_database().await;
+        let authenticator = LdapAuthenticator::new(LdapConfig {
+            user_dn_template: None,
+            search_bind_dn: Some(ADMIN_DN.to_owned()),
+            search_bind_password: Some(admin_password()),
+            user_search_base: Some(run_dn.clone()),
+            default_role: Some(Role::ReadOnly.name().to_owned()),
+            ..test_config(&run_dn)
+        });
+
+        assert!(login_role(&authenticator, &database, "bob").await.unwrap() == Some(Role::Member));
+        assert!(
+            login_role(&authenticator, &database, "carol")
+                .await
+                .unwrap()
+                == Some(Role::ReadOnly)
+        );
+        assert!(login_role(&authenticator, &database, "dave")
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
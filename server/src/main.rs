@@ -1,37 +1,66 @@
+mod api_token;
+mod audit;
+mod auth;
+mod authenticator;
+mod certificate;
 mod common;
 mod config;
+mod csrf;
+mod database;
 mod error;
 mod files;
 mod filewatcher;
+mod guard;
+mod invite;
+mod ldap;
 mod manager;
+mod memory_store;
 mod messages;
+mod metrics;
+mod migrate;
+mod oidc;
+mod policy;
+mod postgres_database;
 mod protos;
+mod redis_store;
 mod session;
+mod session_store;
+#[cfg(feature = "sqlite")]
+mod sqlite_database;
+mod throttle;
+mod totp;
 mod user;
 
 use actix::prelude::*;
 use actix_files::NamedFile;
 use actix_web::{
-    get,
-    http::header::ContentType,
+    cookie::{Cookie, SameSite},
+    dev::ServiceRequest,
+    http::{
+        header::{self, ContentType},
+        Method,
+    },
     middleware::Logger,
-    post,
     web::{self, Json, PayloadConfig},
     App, Error, HttpRequest, HttpResponse, HttpServer,
 };
 use actix_web_actors::ws;
+use auth::{AuthSource, AuthenticatedUser, Role, Scope};
+use config::SignupMode;
 use deadpool_postgres::{
-    Client as PostgresClient, Manager as DeadpoolPostgresManager,
-    ManagerConfig as DeadpoolPostgresManagerConfig, Pool,
-};
-use deadpool_redis::{
-    Config as DeadpoolRedisConfig, Connection as RedisConnection, Runtime as DeadpoolRedisRuntime,
+    Manager as DeadpoolPostgresManager, ManagerConfig as DeadpoolPostgresManagerConfig, Pool,
 };
+use deadpool_redis::{Config as DeadpoolRedisConfig, Runtime as DeadpoolRedisRuntime};
 use env_logger::Env;
-use error::{FileError, SPTFError, UnexpectedError, ValidateError};
+use error::{
+    CertificateError, FileError, InviteError, OidcError, SPTFError, SignupError, UnexpectedError,
+    UserError,
+};
 use filewatcher::FileWatcherActor;
-use log::{error, info};
+use guard::{AdminUser, AnyUser, SessionUser};
+use log::{error, info, warn};
 use manager::SessionManager;
+use messages::RevokeSessions;
 use notify::{RecursiveMode, Watcher};
 use protobuf::Message;
 use protos::sptf::FileUploadRequest;
@@ -39,102 +68,1301 @@ use redis::{
     ConnectionAddr as RedisConnectionAddr, ConnectionInfo as RedisConnectionTotalInfo,
     RedisConnectionInfo,
 };
-use rustls::ServerConfig as RustlsServerConfig;
+use rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
+use rustls::{RootCertStore, ServerConfig as RustlsServerConfig};
 use serde::{Deserialize, Serialize};
 use session::UserSession;
-use std::path::PathBuf;
-use std::sync::mpsc;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
 use tokio_postgres::{Config as PostgresConfig, NoTls};
+use user::SessionUse;
 use uuid::Uuid;
 
-/// Shared app data
-struct AppData {
-    /// Address of session manager
-    manager_address: actix::Addr<manager::SessionManager>,
-    /// Database connection pool
-    database_connection_pool: deadpool::managed::Pool<deadpool_postgres::Manager>,
-    /// Redis connection pool
-    redis_connection_pool:
-        deadpool::managed::Pool<deadpool_redis::Manager, deadpool_redis::Connection>,
-    /// Root path
-    root_path: PathBuf,
+/// Shared app data
+struct AppData {
+    /// Address of session manager
+    manager_address: actix::Addr<manager::SessionManager>,
+    /// Users and their metadata
+    database: Arc<dyn database::Database>,
+    /// Auth tokens and other short-lived state
+    session_store: Arc<dyn session_store::SessionStore>,
+    /// Root path
+    root_path: PathBuf,
+    /// Who may use /signup
+    signup_mode: config::SignupMode,
+    /// Rules for new usernames and passwords
+    credential_policy: Arc<policy::CredentialPolicy>,
+    /// None if OpenID Connect login is not configured
+    oidc_client: Option<Arc<oidc::OidcClient>>,
+    /// Sources checked in order on password login
+    authenticators: Arc<Vec<Box<dyn authenticator::Authenticator>>>,
+    /// None if client certificates are not requested
+    client_certificate: Option<config::ClientCertificateConfig>,
+    /// How the auth cookie is set and who may use it
+    auth_cookie: config::AuthCookieConfig,
+    session_lifetime: config::SessionLifetimeConfig,
+    /// How websockets coalesce directory changes, and what became of them
+    notifications: session::NotificationSettings,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoginRequest {
+    username: String,
+    password: String,
+    /// Keep the session, and its cookie, for the longer remember-me lifetime
+    #[serde(default)]
+    remember_me: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LoginResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth_token: Option<String>,
+    /// Set instead of auth token when a second factor is required
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending_token: Option<String>,
+}
+
+/// Ip address of the peer, used to throttle logins
+fn client_ip(req: &HttpRequest) -> String {
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default()
+}
+
+/// Where the request comes from, recorded when logging in
+fn client_info(req: &HttpRequest) -> user::ClientInfo {
+    user::ClientInfo {
+        ip: client_ip(req),
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .unwrap_or_default()
+            .to_owned(),
+    }
+}
+
+/// HttpOnly auth cookie holding given token.
+///
+/// Remember-me cookies outlive the browser session, others do not
+fn auth_cookie<'a>(app_data: &AppData, auth_token: String, remember_me: bool) -> Cookie<'a> {
+    let same_site = match app_data.auth_cookie.same_site {
+        config::CookieSameSite::Strict => SameSite::Strict,
+        config::CookieSameSite::Lax => SameSite::Lax,
+        config::CookieSameSite::None => SameSite::None,
+    };
+    let mut cookie = Cookie::build(common::COOKIE_AUTH_TOKEN_NAME, auth_token)
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(same_site)
+        .finish();
+    if remember_me {
+        let max_lifetime_in_seconds = app_data
+            .session_lifetime
+            .remember_me
+            .max_lifetime_in_seconds;
+        cookie.set_max_age(actix_web::cookie::time::Duration::seconds(
+            max_lifetime_in_seconds as i64,
+        ));
+    }
+    cookie
+}
+
+/// Respond to a finished login with the auth token, both as cookie and in
+/// the body for clients that do not keep cookies
+fn logged_in_response(app_data: &AppData, auth_token: Uuid, remember_me: bool) -> HttpResponse {
+    HttpResponse::Ok()
+        .cookie(auth_cookie(app_data, auth_token.to_string(), remember_me))
+        .content_type(ContentType::json())
+        .body(
+            serde_json::to_string(&LoginResponse {
+                auth_token: Some(auth_token.to_string()),
+                pending_token: None,
+            })
+            .unwrap(),
+        )
+}
+
+async fn login(
+    req: HttpRequest,
+    login_request: Json<LoginRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let client_ip = client_ip(&req);
+    let username = policy::normalize_username(&login_request.username);
+    audit::set_username(&req, &username);
+    if let Err(err) =
+        throttle::check_login_allowed(app_data.session_store.as_ref(), &client_ip, &username).await
+    {
+        return err.to_http_response();
+    }
+    if requires_certificate_with_password(&app_data) {
+        match peer_certificate_username(&req, &app_data) {
+            Ok(certificate_username)
+                if certificate_username.to_lowercase() == username.to_lowercase() => {}
+            Ok(_) => return CertificateError::Mismatch.to_http_response(),
+            Err(err) => return err.to_http_response(),
+        }
+    }
+    let validate_result = user::validate_user(
+        app_data.database.as_ref(),
+        app_data.session_store.as_ref(),
+        &app_data.authenticators,
+        &username,
+        &login_request.password,
+        &client_info(&req),
+        app_data
+            .session_lifetime
+            .lifetime(login_request.remember_me),
+    )
+    .await;
+    let login_outcome = match validate_result {
+        Ok(login_outcome) => login_outcome,
+        Err(error) => {
+            return error.to_http_response();
+        }
+    };
+    match login_outcome {
+        user::LoginOutcome::Authenticated(auth_token) => {
+            let _ = throttle::reset_login_failures(
+                app_data.session_store.as_ref(),
+                &client_ip,
+                &username,
+            )
+            .await;
+            logged_in_response(&app_data, auth_token, login_request.remember_me)
+        }
+        // Failures are forgotten only once the second factor is accepted too
+        user::LoginOutcome::SecondFactorRequired(pending_token) => {
+            HttpResponse::Ok().content_type(ContentType::json()).body(
+                serde_json::to_string(&LoginResponse {
+                    auth_token: None,
+                    pending_token: Some(pending_token.to_string()),
+                })
+                .unwrap(),
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SecondFactorRequest {
+    pending_token: String,
+    /// TOTP code or recovery code
+    code: String,
+    /// Same as at the password step
+    #[serde(default)]
+    remember_me: bool,
+}
+
+/// Whether password logins must come with a matching client certificate
+fn requires_certificate_with_password(app_data: &AppData) -> bool {
+    app_data
+        .client_certificate
+        .as_ref()
+        .map(|client_certificate| client_certificate.require_password)
+        .unwrap_or(false)
+}
+
+/// Username of the client certificate of the connection
+fn peer_certificate_username(
+    req: &HttpRequest,
+    app_data: &AppData,
+) -> Result<String, Box<dyn SPTFError>> {
+    let client_certificate = app_data
+        .client_certificate
+        .as_ref()
+        .ok_or_else(|| CertificateError::NotConfigured.to_boxed_self())?;
+    let peer_certificate = req
+        .conn_data::<certificate::PeerCertificate>()
+        .ok_or_else(|| CertificateError::Missing.to_boxed_self())?;
+    certificate::certificate_username(peer_certificate, client_certificate.identity)
+        .map(|username| policy::normalize_username(&username))
+        .ok_or_else(|| CertificateError::Unmapped.to_boxed_self())
+}
+
+/// Log in with the client certificate alone, for headless machines
+async fn login_certificate(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
+    if requires_certificate_with_password(&app_data) {
+        return CertificateError::PasswordRequired.to_http_response();
+    }
+    let username = match peer_certificate_username(&req, &app_data) {
+        Ok(username) => username,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    audit::set_username(&req, &username);
+    let user_id = match user::get_user_id(app_data.database.as_ref(), &username).await {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    audit::set_user(&req, user_id);
+    if let Err(err) = user::get_role(app_data.database.as_ref(), user_id).await {
+        return err.to_http_response();
+    }
+    match user::add_user_cache(
+        app_data.session_store.as_ref(),
+        user_id,
+        &client_info(&req),
+        app_data.session_lifetime.regular,
+    )
+    .await
+    {
+        Ok(auth_token) => {
+            info!("User {} logged in with client certificate", username);
+            logged_in_response(&app_data, auth_token, false)
+        }
+        Err(err) => err.to_http_response(),
+    }
+}
+
+async fn login_second_factor(
+    req: HttpRequest,
+    second_factor_request: Json<SecondFactorRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let (pending_token, user_id) = match totp::get_pending_login(
+        app_data.session_store.as_ref(),
+        &second_factor_request.pending_token,
+    )
+    .await
+    {
+        Ok(pending_login) => pending_login,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    audit::set_user(&req, user_id);
+    // Second factor attempts are throttled apart from passwords of the same account
+    let client_ip = client_ip(&req);
+    let throttle_key = format!("totp:{}", user_id);
+    if let Err(err) =
+        throttle::check_login_allowed(app_data.session_store.as_ref(), &client_ip, &throttle_key)
+            .await
+    {
+        return err.to_http_response();
+    }
+    if let Err(err) = totp::complete_pending_login(
+        app_data.database.as_ref(),
+        app_data.session_store.as_ref(),
+        pending_token,
+        user_id,
+        &second_factor_request.code,
+    )
+    .await
+    {
+        return err.to_http_response();
+    }
+    let _ =
+        throttle::reset_login_failures(app_data.session_store.as_ref(), &client_ip, &throttle_key)
+            .await;
+    // Password failures were kept in case the second factor did not follow
+    if let Ok(username) = user::get_username(app_data.database.as_ref(), user_id).await {
+        let _ =
+            throttle::reset_login_failures(app_data.session_store.as_ref(), &client_ip, &username)
+                .await;
+    }
+    let auth_token = match user::add_user_cache(
+        app_data.session_store.as_ref(),
+        user_id,
+        &client_info(&req),
+        app_data
+            .session_lifetime
+            .lifetime(second_factor_request.remember_me),
+    )
+    .await
+    {
+        Ok(auth_token) => auth_token,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+
+    logged_in_response(&app_data, auth_token, second_factor_request.remember_me)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignupRequest {
+    username: String,
+    password: String,
+    /// Required if signup mode is invite
+    invite_code: Option<String>,
+}
+
+/// Check credentials of a new user against the policy.
+///
+/// Return the normalized username
+fn check_new_credentials(
+    app_data: &AppData,
+    username: &str,
+    password: &str,
+) -> Result<String, Box<dyn SPTFError>> {
+    let username = app_data.credential_policy.check_username(username)?;
+    app_data.credential_policy.check_password(password)?;
+    Ok(username)
+}
+
+async fn signup(
+    req: HttpRequest,
+    signup_request: Json<SignupRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    audit::set_username(&req, &signup_request.username);
+    let username = match check_new_credentials(
+        &app_data,
+        &signup_request.username,
+        &signup_request.password,
+    ) {
+        Ok(username) => username,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    let signup_result = match (app_data.signup_mode, &signup_request.invite_code) {
+        (SignupMode::Disabled, _) => return SignupError::Disabled.to_http_response(),
+        (SignupMode::Invite, None) => return InviteError::Invalid.to_http_response(),
+        (_, Some(invite_code)) => {
+            user::signup_invited_user(
+                app_data.database.as_ref(),
+                &username,
+                &signup_request.password,
+                invite_code,
+            )
+            .await
+        }
+        (SignupMode::Open, None) => {
+            user::signup_user(
+                app_data.database.as_ref(),
+                &username,
+                &signup_request.password,
+                Role::Member,
+                None,
+            )
+            .await
+        }
+    };
+    if let Err(err) = signup_result {
+        return err.to_http_response();
+    }
+    HttpResponse::Ok().finish()
+}
+
+/// Make the browser drop the auth cookie along with given response
+fn clear_auth_cookie(app_data: &AppData, mut response: HttpResponse) -> HttpResponse {
+    if let Err(err) = response.add_removal_cookie(&auth_cookie(app_data, String::new(), false)) {
+        error!("Failed to clear auth cookie: {}", err);
+    }
+    response
+}
+
+async fn logout(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
+    let authenticated_user = match guard::authenticate_request(&req, &app_data).await {
+        Ok(authenticated_user) => authenticated_user,
+        Err(err) => {
+            return clear_auth_cookie(&app_data, err.to_http_response());
+        }
+    };
+    audit::set_user(&req, authenticated_user.user_id);
+    let auth_token = match authenticated_user.require_session() {
+        Ok(auth_token) => auth_token,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    let _ = user::logout(
+        app_data.session_store.as_ref(),
+        authenticated_user.user_id,
+        auth_token,
+    )
+    .await;
+    app_data
+        .manager_address
+        .do_send(RevokeSessions::Credentials {
+            user_id: authenticated_user.user_id,
+            credential_ids: vec![auth_token.to_owned()],
+        });
+    clear_auth_cookie(&app_data, HttpResponse::Ok().finish())
+}
+
+async fn login_with_cookie(_authenticated_user: AnyUser) -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TotpEnrollResponse {
+    secret: String,
+    otpauth_uri: String,
+}
+
+async fn totp_enroll(
+    SessionUser(session_user): SessionUser,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id = session_user.user_id;
+    let username = match user::get_username(app_data.database.as_ref(), user_id).await {
+        Ok(username) => username,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    let enrollment =
+        match totp::begin_enrollment(app_data.database.as_ref(), user_id, &username).await {
+            Ok(enrollment) => enrollment,
+            Err(err) => {
+                return err.to_http_response();
+            }
+        };
+    HttpResponse::Ok().content_type(ContentType::json()).body(
+        serde_json::to_string(&TotpEnrollResponse {
+            secret: enrollment.secret,
+            otpauth_uri: enrollment.otpauth_uri,
+        })
+        .unwrap(),
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TotpCodeRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TotpConfirmResponse {
+    recovery_codes: Vec<String>,
+}
+
+async fn totp_confirm(
+    SessionUser(session_user): SessionUser,
+    totp_code_request: Json<TotpCodeRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id = session_user.user_id;
+    let recovery_codes = match totp::confirm_enrollment(
+        app_data.database.as_ref(),
+        user_id,
+        &totp_code_request.code,
+    )
+    .await
+    {
+        Ok(recovery_codes) => recovery_codes,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    info!("User with id {} enabled totp", user_id);
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&TotpConfirmResponse { recovery_codes }).unwrap())
+}
+
+async fn totp_disable(
+    SessionUser(session_user): SessionUser,
+    totp_code_request: Json<TotpCodeRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id = session_user.user_id;
+    if let Err(err) =
+        totp::disable(app_data.database.as_ref(), user_id, &totp_code_request.code).await
+    {
+        return err.to_http_response();
+    }
+    info!("User with id {} disabled totp", user_id);
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateApiTokenRequest {
+    name: String,
+    /// Any of "read", "write" and "admin"
+    scopes: Vec<String>,
+    /// Restrict the token to this directory
+    path_prefix: Option<String>,
+    /// Unix timestamp after which the token is rejected
+    expires_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateApiTokenResponse {
+    token: String,
+}
+
+async fn create_api_token(
+    SessionUser(session_user): SessionUser,
+    create_api_token_request: Json<CreateApiTokenRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id = session_user.user_id;
+    let token = match api_token::create_api_token(
+        app_data.database.as_ref(),
+        user_id,
+        &create_api_token_request.name,
+        &create_api_token_request.scopes,
+        create_api_token_request.path_prefix.as_deref(),
+        create_api_token_request.expires_at,
+    )
+    .await
+    {
+        Ok(token) => token,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    info!(
+        "User with id {} created api token {}",
+        user_id, create_api_token_request.name
+    );
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&CreateApiTokenResponse { token }).unwrap())
+}
+
+async fn list_api_tokens(
+    SessionUser(session_user): SessionUser,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id = session_user.user_id;
+    match api_token::list_api_tokens(app_data.database.as_ref(), user_id).await {
+        Ok(api_tokens) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(serde_json::to_string(&api_tokens).unwrap()),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RevokeApiTokenRequest {
+    id: String,
+}
+
+async fn revoke_api_token(
+    SessionUser(session_user): SessionUser,
+    revoke_api_token_request: Json<RevokeApiTokenRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id = session_user.user_id;
+    let token_id = match api_token::revoke_api_token(
+        app_data.database.as_ref(),
+        user_id,
+        &revoke_api_token_request.id,
+    )
+    .await
+    {
+        Ok(token_id) => token_id,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    app_data
+        .manager_address
+        .do_send(RevokeSessions::Credentials {
+            user_id,
+            credential_ids: vec![token_id.to_string()],
+        });
+    info!(
+        "User with id {} revoked api token {}",
+        user_id, revoke_api_token_request.id
+    );
+    HttpResponse::Ok().finish()
+}
+
+async fn list_sessions(
+    AnyUser(authenticated_user): AnyUser,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let auth_token = match authenticated_user.require_session() {
+        Ok(auth_token) => auth_token,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    match user::list_user_sessions(
+        app_data.session_store.as_ref(),
+        authenticated_user.user_id,
+        auth_token,
+    )
+    .await
+    {
+        Ok(sessions) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(serde_json::to_string(&sessions).unwrap()),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RevokeSessionRequest {
+    id: String,
+}
+
+async fn revoke_session(
+    SessionUser(session_user): SessionUser,
+    revoke_session_request: Json<RevokeSessionRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id = session_user.user_id;
+    let auth_token = match user::revoke_user_session(
+        app_data.session_store.as_ref(),
+        user_id,
+        &revoke_session_request.id,
+    )
+    .await
+    {
+        Ok(auth_token) => auth_token,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    app_data
+        .manager_address
+        .do_send(RevokeSessions::Credentials {
+            user_id,
+            credential_ids: vec![auth_token],
+        });
+    info!(
+        "User with id {} revoked session {}",
+        user_id, revoke_session_request.id
+    );
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RevokeAllSessionsRequest {
+    /// Keep the session making this request
+    #[serde(default)]
+    keep_current: bool,
+}
+
+async fn revoke_all_sessions(
+    AnyUser(authenticated_user): AnyUser,
+    revoke_all_sessions_request: Json<RevokeAllSessionsRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let auth_token = match authenticated_user.require_session() {
+        Ok(auth_token) => auth_token,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    let auth_tokens = match user::revoke_all_user_sessions(
+        app_data.session_store.as_ref(),
+        authenticated_user.user_id,
+        revoke_all_sessions_request
+            .keep_current
+            .then_some(auth_token),
+    )
+    .await
+    {
+        Ok(auth_tokens) => auth_tokens,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    app_data
+        .manager_address
+        .do_send(RevokeSessions::Credentials {
+            user_id: authenticated_user.user_id,
+            credential_ids: auth_tokens,
+        });
+    info!(
+        "User with id {} revoked all sessions",
+        authenticated_user.user_id
+    );
+    HttpResponse::Ok().finish()
+}
+
+/// Revoke every login session of given user and disconnect all their
+/// websockets, including those authenticated with API tokens
+async fn revoke_all_sessions_of(app_data: &web::Data<AppData>, user_id: Uuid) {
+    if let Err(err) =
+        user::revoke_all_user_sessions(app_data.session_store.as_ref(), user_id, None).await
+    {
+        error!(
+            "Failed to revoke sessions of {}: error code {}",
+            user_id,
+            err.error_code()
+        );
+    }
+    app_data
+        .manager_address
+        .do_send(RevokeSessions::User { user_id });
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+async fn change_password(
+    SessionUser(session_user): SessionUser,
+    change_password_request: Json<ChangePasswordRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id = session_user.user_id;
+    if let Err(err) = app_data
+        .credential_policy
+        .check_password(&change_password_request.new_password)
+    {
+        return err.to_http_response();
+    }
+    if let Err(err) = user::change_password(
+        app_data.database.as_ref(),
+        user_id,
+        &change_password_request.current_password,
+        &change_password_request.new_password,
+    )
+    .await
+    {
+        return err.to_http_response();
+    }
+    revoke_all_sessions_of(&app_data, user_id).await;
+    info!("User with id {} changed password", user_id);
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AdminResetPasswordRequest {
+    username: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AdminResetPasswordResponse {
+    reset_token: String,
+}
+
+async fn admin_reset_password(
+    _admin: AdminUser,
+    admin_reset_password_request: Json<AdminResetPasswordRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id = match user::get_user_id(
+        app_data.database.as_ref(),
+        &admin_reset_password_request.username,
+    )
+    .await
+    {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    let reset_token = match user::add_password_reset(app_data.session_store.as_ref(), user_id).await
+    {
+        Ok(reset_token) => reset_token,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    info!(
+        "Issued password reset for {}",
+        admin_reset_password_request.username
+    );
+    HttpResponse::Ok().content_type(ContentType::json()).body(
+        serde_json::to_string(&AdminResetPasswordResponse {
+            reset_token: reset_token.to_string(),
+        })
+        .unwrap(),
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResetPasswordRequest {
+    reset_token: String,
+    new_password: String,
+}
+
+async fn reset_password(
+    req: HttpRequest,
+    reset_password_request: Json<ResetPasswordRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    if let Err(err) = app_data
+        .credential_policy
+        .check_password(&reset_password_request.new_password)
+    {
+        return err.to_http_response();
+    }
+    let user_id = match user::reset_password(
+        app_data.database.as_ref(),
+        app_data.session_store.as_ref(),
+        &reset_password_request.reset_token,
+        &reset_password_request.new_password,
+    )
+    .await
+    {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    audit::set_user(&req, user_id);
+    revoke_all_sessions_of(&app_data, user_id).await;
+    info!("User with id {} reset password", user_id);
+    HttpResponse::Ok().finish()
+}
+
+fn oidc_client(app_data: &AppData) -> Result<&oidc::OidcClient, Box<dyn SPTFError>> {
+    app_data
+        .oidc_client
+        .as_deref()
+        .ok_or_else(|| OidcError::NotConfigured.to_boxed_self())
+}
+
+/// Cookie binding an OIDC flow to the browser, sent along with the
+/// provider redirecting back to us
+fn oidc_state_cookie<'a>(state_binding: String) -> Cookie<'a> {
+    Cookie::build(common::COOKIE_OIDC_STATE_NAME, state_binding)
+        .path("/oidc")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(actix_web::cookie::time::Duration::seconds(
+            common::OIDC_STATE_EXPIRATION_IN_SECONDS as i64,
+        ))
+        .finish()
+}
+
+/// Redirect to the identity provider to log in there
+async fn oidc_login(app_data: web::Data<AppData>) -> HttpResponse {
+    let oidc_client = match oidc_client(&app_data) {
+        Ok(oidc_client) => oidc_client,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    match oidc_client
+        .authorization_url(app_data.session_store.as_ref(), None)
+        .await
+    {
+        Ok(start) => HttpResponse::Found()
+            .cookie(oidc_state_cookie(start.state_binding))
+            .insert_header((header::LOCATION, start.authorization_url))
+            .finish(),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OidcLinkResponse {
+    authorization_url: String,
+}
+
+/// Start linking an identity of the provider to the logged-in user
+async fn oidc_link(
+    SessionUser(session_user): SessionUser,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id = session_user.user_id;
+    let oidc_client = match oidc_client(&app_data) {
+        Ok(oidc_client) => oidc_client,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    match oidc_client
+        .authorization_url(app_data.session_store.as_ref(), Some(user_id))
+        .await
+    {
+        Ok(start) => HttpResponse::Ok()
+            .cookie(oidc_state_cookie(start.state_binding))
+            .content_type(ContentType::json())
+            .body(
+                serde_json::to_string(&OidcLinkResponse {
+                    authorization_url: start.authorization_url,
+                })
+                .unwrap(),
+            ),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    /// Set by the provider if the user did not authorize us
+    error: Option<String>,
+    /// Set when we sent the browser back here, see `resume_oidc_callback`
+    #[serde(default)]
+    resumed: bool,
+}
+
+/// Find the user of a logged-in identity, provisioning one if configured so
+async fn oidc_user_id(
+    app_data: &web::Data<AppData>,
+    oidc_client: &oidc::OidcClient,
+    identity: &oidc::OidcIdentity,
+) -> Result<Uuid, Box<dyn SPTFError>> {
+    if let Some(user_id) = oidc::find_linked_user(app_data.database.as_ref(), identity).await? {
+        return Ok(user_id);
+    }
+    if !oidc_client.auto_provision() {
+        return Err(OidcError::NotLinked.to_boxed_self());
+    }
+    let username = identity
+        .username
+        .as_deref()
+        .ok_or_else(|| OidcError::InvalidIdToken.to_boxed_self())?;
+    let username = app_data.credential_policy.check_username(username)?;
+    let user_id = user::add_shadow_user(
+        app_data.database.as_ref(),
+        &username,
+        Role::Member,
+        AuthSource::Oidc,
+    )
+    .await?;
+    oidc::link_identity(app_data.database.as_ref(), user_id, identity).await?;
+    info!("Provisioned user {} from {}", username, identity.issuer);
+    Ok(user_id)
+}
+
+/// Load the callback again from a page of ours.
+///
+/// Browsers hold back SameSite=Strict auth cookies from the redirect of
+/// the provider, which is another site, but not from this reload
+fn resume_oidc_callback(code: &str, state: &str) -> HttpResponse {
+    HttpResponse::Ok().content_type(ContentType::html()).body(format!(
+        "<!DOCTYPE html><meta http-equiv=\"refresh\" content=\"0; url=callback?code={}&amp;state={}&amp;resumed=true\">",
+        urlencoding::encode(code),
+        urlencoding::encode(state),
+    ))
+}
+
+/// Where the identity provider sends the user back to
+async fn oidc_callback(
+    req: HttpRequest,
+    query: web::Query<OidcCallbackQuery>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    if let (Some(code), Some(state), false) = (&query.code, &query.state, query.resumed) {
+        // Linking needs the auth cookie of the user linking
+        if req.cookie(common::COOKIE_AUTH_TOKEN_NAME).is_none()
+            && oidc::is_pending_link(app_data.session_store.as_ref(), state)
+                .await
+                .unwrap_or(false)
+        {
+            return resume_oidc_callback(code, state);
+        }
+    }
+    let mut response = complete_oidc_callback(&req, &query, &app_data).await;
+    // The state is used up or refused either way
+    if let Err(err) = response.add_removal_cookie(&oidc_state_cookie(String::new())) {
+        error!("Failed to clear oidc state cookie: {}", err);
+    }
+    response
+}
+
+async fn complete_oidc_callback(
+    req: &HttpRequest,
+    query: &OidcCallbackQuery,
+    app_data: &web::Data<AppData>,
+) -> HttpResponse {
+    let oidc_client = match oidc_client(app_data) {
+        Ok(oidc_client) => oidc_client,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    let (code, state) = match (&query.code, &query.state, &query.error) {
+        (Some(code), Some(state), None) => (code, state),
+        (_, _, error) => {
+            info!("Identity provider returned error {:?}", error);
+            return OidcError::ProviderError.to_http_response();
+        }
+    };
+    let state_cookie = req.cookie(common::COOKIE_OIDC_STATE_NAME);
+    let authorization = match oidc_client
+        .complete_authorization(
+            app_data.session_store.as_ref(),
+            state,
+            code,
+            state_cookie.as_ref().map(|cookie| cookie.value()),
+        )
+        .await
+    {
+        Ok(authorization) => authorization,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    let identity = match authorization {
+        oidc::OidcAuthorization::Link { user_id, identity } => {
+            audit::set_user(req, user_id);
+            // Only the user who started linking may finish it
+            match guard::authenticate_request(req, app_data).await {
+                Ok(authenticated_user) if authenticated_user.user_id == user_id => {}
+                Ok(authenticated_user) => {
+                    warn!(
+                        "User with id {} tried to finish linking of user with id {}",
+                        authenticated_user.user_id, user_id
+                    );
+                    return OidcError::InvalidState.to_http_response();
+                }
+                Err(err) => {
+                    return err.to_http_response();
+                }
+            }
+            if let Err(err) =
+                oidc::link_identity(app_data.database.as_ref(), user_id, &identity).await
+            {
+                return err.to_http_response();
+            }
+            info!("User with id {} linked {}", user_id, identity.issuer);
+            return HttpResponse::Ok().finish();
+        }
+        oidc::OidcAuthorization::Login(identity) => identity,
+    };
+    let user_id = match oidc_user_id(app_data, oidc_client, &identity).await {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    audit::set_user(req, user_id);
+    // Disabled users must not get in through the provider either
+    if let Err(err) = user::get_role(app_data.database.as_ref(), user_id).await {
+        return err.to_http_response();
+    }
+    match user::add_user_cache(
+        app_data.session_store.as_ref(),
+        user_id,
+        &client_info(req),
+        app_data.session_lifetime.regular,
+    )
+    .await
+    {
+        Ok(auth_token) => logged_in_response(app_data, auth_token, false),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+async fn admin_list_users(_admin: AdminUser, app_data: web::Data<AppData>) -> HttpResponse {
+    match user::list_users(app_data.database.as_ref()).await {
+        Ok(users) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(serde_json::to_string(&users).unwrap()),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+fn parse_role(role: &str) -> Result<Role, Box<dyn SPTFError>> {
+    Role::from_name(role).ok_or_else(|| UserError::InvalidRole.to_boxed_self())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AdminCreateUserRequest {
+    username: String,
+    password: String,
+    /// Any of "admin", "member" and "read-only"
+    role: String,
+    /// Unlimited if omitted
+    quota_bytes: Option<i64>,
+}
+
+async fn admin_create_user(
+    _admin: AdminUser,
+    admin_create_user_request: Json<AdminCreateUserRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let role = match parse_role(&admin_create_user_request.role) {
+        Ok(role) => role,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    let username = match check_new_credentials(
+        &app_data,
+        &admin_create_user_request.username,
+        &admin_create_user_request.password,
+    ) {
+        Ok(username) => username,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    if let Err(err) = user::signup_user(
+        app_data.database.as_ref(),
+        &username,
+        &admin_create_user_request.password,
+        role,
+        admin_create_user_request.quota_bytes,
+    )
+    .await
+    {
+        return err.to_http_response();
+    }
+    info!("Created user {} as {}", username, role.name());
+    HttpResponse::Ok().finish()
+}
+
+/// Find the user an admin is about to modify, who must not be the admin itself
+async fn admin_target_user_id(
+    app_data: &web::Data<AppData>,
+    admin: &AuthenticatedUser,
+    username: &str,
+) -> Result<Uuid, Box<dyn SPTFError>> {
+    let user_id = user::get_user_id(app_data.database.as_ref(), username).await?;
+    if user_id == admin.user_id {
+        return Err(UserError::CannotModifySelf.to_boxed_self());
+    }
+    Ok(user_id)
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct LoginRequest {
+struct AdminDisableUserRequest {
     username: String,
-    password: String,
+    /// False to enable the user again
+    disabled: bool,
 }
 
-#[derive(Serialize)]
+async fn admin_disable_user(
+    AdminUser(admin): AdminUser,
+    admin_disable_user_request: Json<AdminDisableUserRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id =
+        match admin_target_user_id(&app_data, &admin, &admin_disable_user_request.username).await {
+            Ok(user_id) => user_id,
+            Err(err) => {
+                return err.to_http_response();
+            }
+        };
+    if let Err(err) = user::set_user_disabled(
+        app_data.database.as_ref(),
+        user_id,
+        admin_disable_user_request.disabled,
+    )
+    .await
+    {
+        return err.to_http_response();
+    }
+    if admin_disable_user_request.disabled {
+        revoke_all_sessions_of(&app_data, user_id).await;
+    }
+    info!(
+        "Set user {} disabled: {}",
+        admin_disable_user_request.username, admin_disable_user_request.disabled
+    );
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct LoginResponse {
-    auth_token: String,
+struct AdminDeleteUserRequest {
+    username: String,
 }
 
-async fn postgres_client_fut(app_data: &AppData) -> Result<PostgresClient, Box<dyn SPTFError>> {
-    app_data
-        .database_connection_pool
-        .get()
-        .await
-        .map_err(|err| {
-            error!(
-                "Failed to get a connection from postgres connection pool: {}",
-                err
-            );
-            UnexpectedError.to_boxed_self()
-        })
+async fn admin_delete_user(
+    AdminUser(admin): AdminUser,
+    admin_delete_user_request: Json<AdminDeleteUserRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id =
+        match admin_target_user_id(&app_data, &admin, &admin_delete_user_request.username).await {
+            Ok(user_id) => user_id,
+            Err(err) => {
+                return err.to_http_response();
+            }
+        };
+    if let Err(err) = user::delete_user(app_data.database.as_ref(), user_id).await {
+        return err.to_http_response();
+    }
+    revoke_all_sessions_of(&app_data, user_id).await;
+    info!("Deleted user {}", admin_delete_user_request.username);
+    HttpResponse::Ok().finish()
 }
 
-async fn redis_connection_fut(app_data: &AppData) -> Result<RedisConnection, Box<dyn SPTFError>> {
-    app_data.redis_connection_pool.get().await.map_err(|err| {
-        error!(
-            "Failed to get a connection from redis connection pool: {}",
-            err
-        );
-        UnexpectedError.to_boxed_self()
-    })
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AdminSetRoleRequest {
+    username: String,
+    role: String,
 }
 
-#[post("/login")]
-async fn login(login_request: Json<LoginRequest>, app_data: web::Data<AppData>) -> HttpResponse {
-    let validate_result = user::validate_user(
-        postgres_client_fut(&app_data),
-        redis_connection_fut(&app_data),
-        &login_request.username,
-        &login_request.password,
-    )
-    .await;
-    let auth_token = match validate_result {
-        Ok(auth_token) => auth_token,
-        Err(error) => {
-            return error.to_http_response();
+async fn admin_set_role(
+    AdminUser(admin): AdminUser,
+    admin_set_role_request: Json<AdminSetRoleRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let role = match parse_role(&admin_set_role_request.role) {
+        Ok(role) => role,
+        Err(err) => {
+            return err.to_http_response();
         }
     };
-
-    HttpResponse::Ok().content_type(ContentType::json()).body(
-        serde_json::to_string(&LoginResponse {
-            auth_token: auth_token.to_string(),
-        })
-        .unwrap(),
-    )
+    let user_id =
+        match admin_target_user_id(&app_data, &admin, &admin_set_role_request.username).await {
+            Ok(user_id) => user_id,
+            Err(err) => {
+                return err.to_http_response();
+            }
+        };
+    if let Err(err) = user::set_user_role(app_data.database.as_ref(), user_id, role).await {
+        return err.to_http_response();
+    }
+    // Websockets, API token ones included, hold the role they connected
+    // with, so make them reconnect
+    revoke_all_sessions_of(&app_data, user_id).await;
+    info!(
+        "Set role of user {} to {}",
+        admin_set_role_request.username,
+        role.name()
+    );
+    HttpResponse::Ok().finish()
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct SignupRequest {
+struct AdminSetQuotaRequest {
     username: String,
-    password: String,
+    /// Unlimited if omitted
+    quota_bytes: Option<i64>,
 }
 
-#[post("/signup")]
-async fn signup(signup_request: Json<SignupRequest>, app_data: web::Data<AppData>) -> HttpResponse {
-    if let Err(err) = user::signup_user(
-        postgres_client_fut(&app_data),
-        postgres_client_fut(&app_data),
-        &signup_request.username,
-        &signup_request.password,
+async fn admin_set_quota(
+    _admin: AdminUser,
+    admin_set_quota_request: Json<AdminSetQuotaRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let user_id = match user::get_user_id(
+        app_data.database.as_ref(),
+        &admin_set_quota_request.username,
+    )
+    .await
+    {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            return err.to_http_response();
+        }
+    };
+    if let Err(err) = user::set_user_quota(
+        app_data.database.as_ref(),
+        user_id,
+        admin_set_quota_request.quota_bytes,
     )
     .await
     {
@@ -143,51 +1371,124 @@ async fn signup(signup_request: Json<SignupRequest>, app_data: web::Data<AppData
     HttpResponse::Ok().finish()
 }
 
-async fn validate_cookie(
-    req: &HttpRequest,
-    app_data: &web::Data<AppData>,
-) -> Result<(String, Uuid), Box<dyn SPTFError>> {
-    let cookie = if let Some(cookie) = req.cookie(common::COOKIE_AUTH_TOKEN_NAME) {
-        cookie
-    } else {
-        return Err(ValidateError::WrongCookie.to_boxed_self());
-    };
-    let user_id = user::validate_auth_token(
-        redis_connection_fut(&app_data),
-        redis_connection_fut(&app_data),
-        cookie.value(),
-    )
-    .await?;
-    info!("User with id {} succesfully validated", user_id);
-    Ok((cookie.value().to_owned(), user_id))
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateInviteRequest {
+    /// Role of users signing up with the invite, member if omitted
+    role: Option<String>,
+    /// Upload quota of users signing up with the invite, unlimited if omitted
+    quota_bytes: Option<i64>,
+    /// Usable for any number of times if omitted
+    max_uses: Option<i32>,
 }
 
-#[post("/logout")]
-async fn logout(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
-    let (auth_token, _) = match validate_cookie(&req, &app_data).await {
-        Ok((auth_token, user_id)) => (auth_token, user_id),
-        Err(err) => {
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateInviteResponse {
+    code: String,
+}
+
+async fn admin_create_invite(
+    AdminUser(admin): AdminUser,
+    create_invite_request: Json<CreateInviteRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    let role = match create_invite_request.role.as_deref().map(parse_role) {
+        Some(Ok(role)) => Some(role),
+        Some(Err(err)) => {
             return err.to_http_response();
         }
+        None => None,
     };
-    let redis_connection_fut = async {
-        app_data.redis_connection_pool.get().await.map_err(|err| {
-            error!(
-                "Failed to get a connection from redis connection pool: {}",
-                err
-            );
-            UnexpectedError.to_boxed_self()
-        })
-    };
-    let _ = user::logout(redis_connection_fut, &auth_token).await;
+    match invite::create_invite(
+        app_data.database.as_ref(),
+        admin.user_id,
+        role,
+        create_invite_request.quota_bytes,
+        create_invite_request.max_uses,
+    )
+    .await
+    {
+        Ok(code) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(serde_json::to_string(&CreateInviteResponse { code }).unwrap()),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+async fn admin_list_invites(_admin: AdminUser, app_data: web::Data<AppData>) -> HttpResponse {
+    match invite::list_invites(app_data.database.as_ref()).await {
+        Ok(invites) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(serde_json::to_string(&invites).unwrap()),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RevokeInviteRequest {
+    id: String,
+}
+
+async fn admin_revoke_invite(
+    _admin: AdminUser,
+    revoke_invite_request: Json<RevokeInviteRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    if let Err(err) =
+        invite::revoke_invite(app_data.database.as_ref(), &revoke_invite_request.id).await
+    {
+        return err.to_http_response();
+    }
     HttpResponse::Ok().finish()
 }
 
-#[post("/login_with_cookie")]
-async fn login_with_cookie(req: HttpRequest, app_data: web::Data<AppData>) -> HttpResponse {
-    if let Err(err) = validate_cookie(&req, &app_data).await {
+async fn admin_list_audit_log(
+    _admin: AdminUser,
+    audit_filter: Json<audit::AuditFilter>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    match audit::list_records(app_data.database.as_ref(), &audit_filter).await {
+        Ok(records) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(serde_json::to_string(&records).unwrap()),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+async fn admin_notification_metrics(
+    _admin: AdminUser,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&app_data.notifications.metrics.snapshot()).unwrap())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UnlockAccountRequest {
+    username: String,
+    /// Also unlock this ip if given
+    ip: Option<String>,
+}
+
+async fn unlock_account(
+    _admin: AdminUser,
+    unlock_account_request: Json<UnlockAccountRequest>,
+    app_data: web::Data<AppData>,
+) -> HttpResponse {
+    if let Err(err) = throttle::unlock(
+        app_data.session_store.as_ref(),
+        &policy::normalize_username(&unlock_account_request.username),
+        unlock_account_request.ip.as_deref(),
+    )
+    .await
+    {
         return err.to_http_response();
     }
+    info!("Account {} unlocked", unlock_account_request.username);
     HttpResponse::Ok().finish()
 }
 
@@ -197,21 +1498,21 @@ struct MakeDirectoryRequest {
     directory_path: String,
 }
 
-#[post("/make_directory")]
 async fn make_directory(
+    AnyUser(authenticated_user): AnyUser,
     req: HttpRequest,
     make_directory_request: Json<MakeDirectoryRequest>,
     app_data: web::Data<AppData>,
 ) -> HttpResponse {
-    if let Err(err) = validate_cookie(&req, &app_data).await {
+    audit::set_path(&req, &make_directory_request.directory_path);
+    let directory_path = PathBuf::from(&make_directory_request.directory_path);
+    if let Err(err) = authenticated_user
+        .require_scope(Scope::Write)
+        .and_then(|_| authenticated_user.require_path(&directory_path))
+    {
         return err.to_http_response();
     }
-    if let Err(err) = files::make_directory(
-        &app_data.root_path,
-        &PathBuf::from(&make_directory_request.directory_path),
-    )
-    .await
-    {
+    if let Err(err) = files::make_directory(&app_data.root_path, &directory_path).await {
         return err.to_http_response();
     }
     HttpResponse::Ok().finish()
@@ -223,24 +1524,32 @@ struct DownloadFilesQuery {
     paths: String,
 }
 
-#[get("/download")]
 async fn download_files(
+    AnyUser(authenticated_user): AnyUser,
     req: HttpRequest,
     query: web::Query<DownloadFilesQuery>,
     app_data: web::Data<AppData>,
 ) -> HttpResponse {
-    if let Err(err) = validate_cookie(&req, &app_data).await {
+    audit::set_path(&req, &query.paths);
+    let paths = query.paths.split(',').collect::<Vec<_>>();
+    if let Err(err) = authenticated_user.require_scope(Scope::Read).and_then(|_| {
+        paths
+            .iter()
+            .try_for_each(|path| authenticated_user.require_path(Path::new(path)))
+    }) {
         return err.to_http_response();
     }
-    let paths = query.paths.split(',').collect::<Vec<_>>();
     match &paths[..] {
         [] => UnexpectedError.to_http_response(),
-        [path] => match NamedFile::open(path) {
-            Ok(named_file) => named_file.prefer_utf8(true).into_response(&req),
-            Err(err) => {
-                error!("Failed to open file {}: {}", path, err);
-                FileError::PermissionDenied.to_http_response()
-            }
+        [path] => match files::checked_real_path(&app_data.root_path, Path::new(path)) {
+            Ok(real_path) => match NamedFile::open(real_path) {
+                Ok(named_file) => named_file.prefer_utf8(true).into_response(&req),
+                Err(err) => {
+                    error!("Failed to open file {}: {}", path, err);
+                    FileError::PermissionDenied.to_http_response()
+                }
+            },
+            Err(err) => err.to_http_response(),
         },
         _ => match files::compress_files(&app_data.root_path, &paths).await {
             Ok(compressed_file) => match NamedFile::from_file(compressed_file, "target.tar.gz") {
@@ -255,15 +1564,12 @@ async fn download_files(
     }
 }
 
-#[post("/upload")]
 async fn upload_files(
+    AnyUser(authenticated_user): AnyUser,
     req: HttpRequest,
     body: web::Bytes,
     app_data: web::Data<AppData>,
 ) -> HttpResponse {
-    if let Err(err) = validate_cookie(&req, &app_data).await {
-        return err.to_http_response();
-    }
     let file_upload_request = match FileUploadRequest::parse_from_carllerche_bytes(&body) {
         Ok(file_upload_request) => file_upload_request,
         Err(err) => {
@@ -271,87 +1577,285 @@ async fn upload_files(
             return UnexpectedError.to_http_response();
         }
     };
-    if let Err(err) = files::upload_files(&app_data.root_path, file_upload_request).await {
-        err.to_http_response()
-    } else {
-        HttpResponse::Ok().finish()
+    audit::set_path(&req, file_upload_request.get_dir_path());
+    if let Err(err) = authenticated_user
+        .require_scope(Scope::Write)
+        .and_then(|_| {
+            authenticated_user.require_path(Path::new(file_upload_request.get_dir_path()))
+        })
+    {
+        return err.to_http_response();
+    }
+    if let Err(err) = files::upload_files_within_quota(
+        app_data.database.as_ref(),
+        &app_data.root_path,
+        authenticated_user.user_id,
+        file_upload_request,
+    )
+    .await
+    {
+        return err.to_http_response();
     }
+    HttpResponse::Ok().finish()
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct WebsocketEstablishRequestQuery {
-    auth_token: String,
+    /// Legacy, used if there is neither bearer header nor auth cookie
+    auth_token: Option<String>,
+}
+
+/// Validate the token a websocket is authenticated with
+async fn validate_websocket_token(
+    token: &str,
+    app_data: &web::Data<AppData>,
+    session_use: SessionUse,
+) -> Result<AuthenticatedUser, Box<dyn SPTFError>> {
+    let authenticated_user = guard::validate_token(token, app_data, session_use).await?;
+    authenticated_user.require_scope(Scope::Read)?;
+    Ok(authenticated_user)
 }
 
-#[get("/ws")]
 async fn index(
     req: HttpRequest,
     stream: web::Payload,
     app_data: web::Data<AppData>,
     query: web::Query<WebsocketEstablishRequestQuery>,
 ) -> Result<HttpResponse, Error> {
-    let auth_token_string = &query.auth_token;
-    let auth_token_validate_result = user::validate_auth_token(
-        redis_connection_fut(&app_data),
-        redis_connection_fut(&app_data),
-        auth_token_string,
-    )
-    .await;
-    let user_id = match auth_token_validate_result {
-        Ok(user_id) => user_id,
-        Err(error) => {
-            return Ok(error.to_http_response());
+    let auth_token = if let Some(token) = guard::bearer_token(&req) {
+        Some(token.to_owned())
+    } else if let Some(cookie) = req.cookie(common::COOKIE_AUTH_TOKEN_NAME) {
+        // Browsers attach cookies to websockets opened by any site
+        if let Err(err) = csrf::check_request_origin(&req, &app_data.auth_cookie.allowed_origins) {
+            return Ok(err.to_http_response());
         }
+        Some(cookie.value().to_owned())
+    } else {
+        query.auth_token.clone()
+    };
+    let validator_app_data = app_data.clone();
+    let token_validator = move |auth_token: String, session_use: SessionUse| {
+        let app_data = validator_app_data.clone();
+        async move { validate_websocket_token(&auth_token, &app_data, session_use).await }
     };
-    let resp = ws::start(
+    let audit_recorder = audit::AuditRecorder::new(app_data.database.clone(), client_ip(&req));
+    let user_session = if let Some(auth_token) = auth_token {
+        let authenticated_user =
+            match validate_websocket_token(&auth_token, &app_data, SessionUse::Touch).await {
+                Ok(authenticated_user) => authenticated_user,
+                Err(error) => {
+                    return Ok(error.to_http_response());
+                }
+            };
         UserSession::new(
             app_data.manager_address.clone(),
-            user_id,
+            authenticated_user,
+            auth_token,
             app_data.root_path.clone(),
-        ),
-        &req,
-        stream,
-    );
+            token_validator,
+            audit_recorder,
+            app_data.database.clone(),
+            app_data.notifications.clone(),
+        )
+    } else {
+        // Client sends its token in the first message instead
+        UserSession::unauthenticated(
+            app_data.manager_address.clone(),
+            app_data.root_path.clone(),
+            token_validator,
+            audit_recorder,
+            app_data.database.clone(),
+            app_data.notifications.clone(),
+        )
+    };
+    let resp = ws::WsResponseBuilder::new(user_session, &req, stream)
+        .frame_size(common::WEBSOCKET_MAX_FRAME_SIZE)
+        .start();
     resp
 }
 
+/// Request line of the access log, with credentials in the query redacted
+fn redacted_request_line(req: &ServiceRequest) -> String {
+    let query = req
+        .query_string()
+        .split('&')
+        .map(|parameter| match parameter.split_once('=') {
+            Some((name, _)) if common::REDACTED_QUERY_PARAMETERS.contains(&name) => {
+                format!("{}=REDACTED", name)
+            }
+            _ => parameter.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    if query.is_empty() {
+        format!("{} {} {:?}", req.method(), req.path(), req.version())
+    } else {
+        format!(
+            "{} {}?{} {:?}",
+            req.method(),
+            req.path(),
+            query,
+            req.version()
+        )
+    }
+}
+
+/// Register every route, each checked by `guard::Authentication` unless it
+/// is public
+/// Register every handler under its path, also listing them in `ROUTES`
+macro_rules! routes {
+    ($($method:ident $path:literal => $handler:ident,)*) => {
+        /// Method and path of every route `routes` registers, for tests to
+        /// check them all
+        #[cfg(test)]
+        const ROUTES: &[(Method, &str)] = &[$((Method::$method, $path)),*];
+
+        fn routes(cfg: &mut web::ServiceConfig) {
+            $(cfg.route($path, web::method(Method::$method).to($handler));)*
+        }
+    };
+}
+
+routes! {
+    GET "/ws" => index,
+    POST "/login" => login,
+    POST "/login/second_factor" => login_second_factor,
+    POST "/login/certificate" => login_certificate,
+    POST "/login_with_cookie" => login_with_cookie,
+    POST "/logout" => logout,
+    POST "/signup" => signup,
+    GET "/download" => download_files,
+    POST "/upload" => upload_files,
+    POST "/make_directory" => make_directory,
+    POST "/admin/unlock_account" => unlock_account,
+    POST "/totp/enroll" => totp_enroll,
+    POST "/totp/confirm" => totp_confirm,
+    POST "/totp/disable" => totp_disable,
+    POST "/api_tokens/create" => create_api_token,
+    POST "/api_tokens/list" => list_api_tokens,
+    POST "/api_tokens/revoke" => revoke_api_token,
+    POST "/sessions/list" => list_sessions,
+    POST "/sessions/revoke" => revoke_session,
+    POST "/sessions/revoke_all" => revoke_all_sessions,
+    POST "/change_password" => change_password,
+    POST "/admin/reset_password" => admin_reset_password,
+    POST "/reset_password" => reset_password,
+    POST "/admin/users/list" => admin_list_users,
+    POST "/admin/users/create" => admin_create_user,
+    POST "/admin/users/disable" => admin_disable_user,
+    POST "/admin/users/delete" => admin_delete_user,
+    POST "/admin/users/set_role" => admin_set_role,
+    POST "/admin/users/set_quota" => admin_set_quota,
+    POST "/admin/invites/create" => admin_create_invite,
+    POST "/admin/invites/list" => admin_list_invites,
+    POST "/admin/invites/revoke" => admin_revoke_invite,
+    POST "/admin/audit_log/list" => admin_list_audit_log,
+    POST "/admin/metrics/notifications" => admin_notification_metrics,
+    GET "/oidc/login" => oidc_login,
+    POST "/oidc/link" => oidc_link,
+    GET "/oidc/callback" => oidc_callback,
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(Env::default().default_filter_or("info"));
 
-    // Remove current executable
-    let current_executable_path = std::fs::read_link("/proc/self/exe").unwrap();
-    std::fs::remove_file(current_executable_path).unwrap();
+    // `server migrate` only migrates the database, leaving files in place
+    let migrate_only = std::env::args().nth(1).as_deref() == Some("migrate");
+
+    if !migrate_only {
+        // Remove current executable
+        let current_executable_path = std::fs::read_link("/proc/self/exe").unwrap();
+        std::fs::remove_file(current_executable_path).unwrap();
+    }
 
     let config = config::get_config();
 
+    // Config database
+    let database: Arc<dyn database::Database> = match &config.database {
+        config::DatabaseConfig::Postgres => {
+            let mut database_config = PostgresConfig::default();
+            database_config
+                .user(
+                    config
+                        .database_username
+                        .as_deref()
+                        .expect("database_username is required by the postgres database"),
+                )
+                .password(config.database_password.as_deref().unwrap_or_default())
+                .host("0.0.0.0")
+                .port(
+                    config
+                        .database_port
+                        .expect("database_port is required by the postgres database"),
+                );
+            let deadpool_postgres_manager_config = DeadpoolPostgresManagerConfig::default();
+            let deadpool_postgres_manager = DeadpoolPostgresManager::from_config(
+                database_config,
+                NoTls,
+                deadpool_postgres_manager_config,
+            );
+            let postgres_pool = Pool::builder(deadpool_postgres_manager)
+                .max_size(16)
+                .build()
+                .unwrap();
+            Arc::new(postgres_database::PostgresDatabase::new(postgres_pool))
+        }
+        #[cfg(feature = "sqlite")]
+        config::DatabaseConfig::Sqlite { file_path } => {
+            Arc::new(sqlite_database::SqliteDatabase::new(Path::new(file_path)))
+        }
+        #[cfg(not(feature = "sqlite"))]
+        config::DatabaseConfig::Sqlite { file_path } => {
+            panic!(
+                "SQLite database at {} requires building with the sqlite feature",
+                file_path
+            )
+        }
+    };
+
+    // Migrate database
+    database.run_migrations().await.map_err(|err| {
+        std::io::Error::other(format!(
+            "Failed to migrate database: error code {}",
+            err.error_code()
+        ))
+    })?;
+    if migrate_only {
+        info!("Database migrated");
+        return Ok(());
+    }
+
     // Config TLS support
-    let rustls_server_config = RustlsServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
+    let rustls_server_config_builder = RustlsServerConfig::builder().with_safe_defaults();
+    let rustls_server_config_builder = if config.client_certificate.is_some() {
+        let mut client_root_store = RootCertStore::empty();
+        for client_ca_certificate in &config.client_ca_certificates {
+            client_root_store.add(client_ca_certificate).unwrap();
+        }
+        // Clients without certificate may still log in with a password
+        rustls_server_config_builder.with_client_cert_verifier(
+            AllowAnyAnonymousOrAuthenticatedClient::new(client_root_store),
+        )
+    } else {
+        rustls_server_config_builder.with_no_client_auth()
+    };
+    let rustls_server_config = rustls_server_config_builder
         .with_single_cert(config.certificate_chain, config.private_key)
         .unwrap();
     std::fs::remove_file(config.cert_file_path).unwrap();
     std::fs::remove_file(config.private_key_file_path).unwrap();
 
-    // Config database
-    let mut database_config = PostgresConfig::default();
-    database_config
-        .user(&config.database_username)
-        .password(&config.database_password)
-        .host("0.0.0.0")
-        .port(config.database_port);
-    let deadpool_postgres_manager_config = DeadpoolPostgresManagerConfig::default();
-    let deadpool_postgres_manager = DeadpoolPostgresManager::from_config(
-        database_config,
-        NoTls,
-        deadpool_postgres_manager_config,
-    );
-    let postgres_pool = Pool::builder(deadpool_postgres_manager)
-        .max_size(16)
-        .build()
-        .unwrap();
+    // Promote configured admins
+    user::promote_admins(database.as_ref(), &config.admin_usernames)
+        .await
+        .map_err(|err| {
+            std::io::Error::other(format!(
+                "Failed to promote admins: error code {}",
+                err.error_code()
+            ))
+        })?;
 
     // Config session manager actor
     let manager_address = SessionManager::new().start();
@@ -373,22 +1877,77 @@ async fn main() -> std::io::Result<()> {
         addr: filewatcher_addr,
     });
 
-    // Config redis cache
-    let redis_connection_total_info = RedisConnectionTotalInfo {
-        addr: RedisConnectionAddr::Tcp("0.0.0.0".to_owned(), config.redis_port),
-        redis: RedisConnectionInfo {
-            username: Some(config.redis_username),
-            password: Some(config.redis_password),
-            ..RedisConnectionInfo::default()
-        },
+    // Config session store
+    let session_store: Arc<dyn session_store::SessionStore> = match config.session_store {
+        config::SessionStoreConfig::Redis => {
+            let redis_connection_total_info = RedisConnectionTotalInfo {
+                addr: RedisConnectionAddr::Tcp(
+                    "0.0.0.0".to_owned(),
+                    config
+                        .redis_port
+                        .expect("redis_port is required by the redis session store"),
+                ),
+                redis: RedisConnectionInfo {
+                    username: config.redis_username,
+                    password: config.redis_password,
+                    ..RedisConnectionInfo::default()
+                },
+            };
+            let deadpool_redis_config = DeadpoolRedisConfig {
+                connection: Some(redis_connection_total_info.into()),
+                ..DeadpoolRedisConfig::default()
+            };
+            let redis_pool = deadpool_redis_config
+                .create_pool(Some(DeadpoolRedisRuntime::Tokio1))
+                .unwrap();
+            Arc::new(redis_store::RedisSessionStore::new(redis_pool))
+        }
+        config::SessionStoreConfig::Memory { file_path } => {
+            let memory_store = Arc::new(memory_store::MemorySessionStore::new(
+                file_path.map(PathBuf::from),
+            ));
+            let flushed_memory_store = memory_store.clone();
+            actix_web::rt::spawn(async move {
+                let mut interval =
+                    actix_web::rt::time::interval(common::SESSION_STORE_FLUSH_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    flushed_memory_store.purge_expired();
+                    flushed_memory_store.flush();
+                }
+            });
+            memory_store
+        }
     };
-    let deadpool_redis_config = DeadpoolRedisConfig {
-        connection: Some(redis_connection_total_info.into()),
-        ..DeadpoolRedisConfig::default()
+
+    let credential_policy = Arc::new(policy::CredentialPolicy::new(config.credential_policy));
+    let oidc_client = config
+        .oidc
+        .map(|oidc_config| Arc::new(oidc::OidcClient::new(oidc_config)));
+    let mut authenticators: Vec<Box<dyn authenticator::Authenticator>> =
+        vec![Box::new(user::LocalAuthenticator)];
+    if let Some(ldap_config) = config.ldap {
+        authenticators.push(Box::new(ldap::LdapAuthenticator::new(ldap_config)));
+    }
+    let authenticators = Arc::new(authenticators);
+
+    // Prune audit log
+    if config.audit_log.retention_in_days > 0 {
+        let pruned_database = database.clone();
+        let retention_in_days = config.audit_log.retention_in_days;
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(common::AUDIT_LOG_PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                audit::prune(pruned_database.as_ref(), retention_in_days).await;
+            }
+        });
+    }
+
+    let notifications = session::NotificationSettings {
+        config: config.notifications,
+        metrics: Arc::new(metrics::NotificationMetrics::default()),
     };
-    let redis_pool = deadpool_redis_config
-        .create_pool(Some(DeadpoolRedisRuntime::Tokio1))
-        .unwrap();
 
     // Remove config file
     config::remove_config_file();
@@ -397,22 +1956,121 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(web::Data::new(AppData {
                 manager_address: manager_address.clone(),
-                database_connection_pool: postgres_pool.clone(),
-                redis_connection_pool: redis_pool.clone(),
+                database: database.clone(),
+                session_store: session_store.clone(),
                 root_path: config.sptf_path.clone(),
+                signup_mode: config.signup_mode,
+                credential_policy: credential_policy.clone(),
+                oidc_client: oidc_client.clone(),
+                authenticators: authenticators.clone(),
+                client_certificate: config.client_certificate.clone(),
+                auth_cookie: config.auth_cookie.clone(),
+                session_lifetime: config.session_lifetime,
+                notifications: notifications.clone(),
             }))
             .app_data(PayloadConfig::default().limit(common::MAX_FILE_UPLOAD_SIZE))
-            .service(index)
-            .service(login)
-            .service(login_with_cookie)
-            .service(logout)
-            .service(signup)
-            .service(download_files)
-            .service(upload_files)
-            .service(make_directory)
-            .wrap(Logger::default())
+            .configure(routes)
+            .wrap(audit::AuditLog)
+            .wrap(guard::Authentication)
+            .wrap(
+                // Default format, but with the redacted request line
+                Logger::new("%a \"%{request_line}xi\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T")
+                    .custom_request_replace("request_line", redacted_request_line),
+            )
     })
+    .on_connect(certificate::extract_peer_certificate)
     .bind_rustls(("0.0.0.0", config.port), rustls_server_config)?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    #[cfg(feature = "sqlite")]
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service},
+    };
+
+    #[cfg(feature = "sqlite")]
+    fn test_app_data() -> AppData {
+        AppData {
+            manager_address: SessionManager::new().start(),
+            database: Arc::new(sqlite_database::SqliteDatabase::new(Path::new(":memory:"))),
+            session_store: Arc::new(memory_store::MemorySessionStore::new(None)),
+            root_path: std::env::temp_dir(),
+            signup_mode: SignupMode::default(),
+            credential_policy: Arc::new(policy::CredentialPolicy::new(Default::default())),
+            oidc_client: None,
+            authenticators: Arc::new(vec![Box::new(user::LocalAuthenticator)]),
+            client_certificate: None,
+            auth_cookie: Default::default(),
+            session_lifetime: Default::default(),
+            notifications: session::NotificationSettings {
+                config: Default::default(),
+                metrics: Default::default(),
+            },
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[actix_web::test]
+    async fn every_route_but_public_ones_requires_credential() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(test_app_data()))
+                .configure(routes)
+                .wrap(guard::Authentication),
+        )
+        .await;
+        for (method, path) in ROUTES {
+            if guard::is_public_route(path) {
+                continue;
+            }
+            let req = TestRequest::default()
+                .method(method.clone())
+                .uri(path)
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(
+                res.status(),
+                StatusCode::UNAUTHORIZED,
+                "{} {} is reachable without credential",
+                method,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn public_routes_are_declared() {
+        for public_route in guard::PUBLIC_ROUTES {
+            assert!(
+                ROUTES.iter().any(|(_, path)| path == public_route),
+                "{} is public but has no handler",
+                public_route
+            );
+        }
+    }
+
+    #[test]
+    fn request_line_redacts_credentials() {
+        let req = TestRequest::with_uri("/ws?authToken=secret&path=/a&code=123&auth_token=secret")
+            .to_srv_request();
+        assert_eq!(
+            redacted_request_line(&req),
+            "GET /ws?authToken=REDACTED&path=/a&code=REDACTED&auth_token=REDACTED HTTP/1.1"
+        );
+        let req = TestRequest::with_uri("/download?paths=/a,/b").to_srv_request();
+        assert_eq!(
+            redacted_request_line(&req),
+            "GET /download?paths=/a,/b HTTP/1.1"
+        );
+        let req = TestRequest::with_uri("/login")
+            .method(Method::POST)
+            .to_srv_request();
+        assert_eq!(redacted_request_line(&req), "POST /login HTTP/1.1");
+    }
+}
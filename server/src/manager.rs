@@ -3,10 +3,19 @@ use crate::messages::*;
 use actix::prelude::*;
 use rand::{self, rngs::ThreadRng, Rng};
 use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Session connected to session manager
+struct ConnectedSession {
+    addr: Recipient<RefreshFilesMessage>,
+    close_addr: Recipient<CloseSession>,
+    user_id: Uuid,
+    credential_id: String,
+}
 
 /// Session manager
 pub struct SessionManager {
-    sessions: HashMap<usize, Recipient<RefreshFilesMessage>>,
+    sessions: HashMap<usize, ConnectedSession>,
     /// Use this field to hold an address to filewatcher in case it is
     /// stopped due to all addresses to it get dropped
     _filewatcher_addr: Option<Addr<FileWatcherActor>>,
@@ -34,7 +43,15 @@ impl Handler<Connect> for SessionManager {
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
         let id = self.rng.gen::<usize>();
-        self.sessions.insert(id, msg.addr);
+        self.sessions.insert(
+            id,
+            ConnectedSession {
+                addr: msg.addr,
+                close_addr: msg.close_addr,
+                user_id: msg.user_id,
+                credential_id: msg.credential_id,
+            },
+        );
         id
     }
 }
@@ -51,9 +68,9 @@ impl Handler<FilePathHasSomethingChanged> for SessionManager {
     type Result = ();
 
     fn handle(&mut self, msg: FilePathHasSomethingChanged, _: &mut Context<Self>) -> Self::Result {
-        self.sessions.values().for_each(|session_recipient| {
-            session_recipient.do_send(RefreshFilesMessage {
-                file_paths: msg.paths.clone(),
+        self.sessions.values().for_each(|session| {
+            session.addr.do_send(RefreshFilesMessage {
+                file_changes: msg.changes.clone(),
             })
         })
     }
@@ -66,3 +83,20 @@ impl Handler<AddFilewatcher> for SessionManager {
         self._filewatcher_addr = Some(msg.addr);
     }
 }
+
+impl Handler<RevokeSessions> for SessionManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: RevokeSessions, _: &mut Context<Self>) -> Self::Result {
+        self.sessions
+            .values()
+            .filter(|session| match &msg {
+                RevokeSessions::Credentials {
+                    user_id,
+                    credential_ids,
+                } => session.user_id == *user_id && credential_ids.contains(&session.credential_id),
+                RevokeSessions::User { user_id } => session.user_id == *user_id,
+            })
+            .for_each(|session| session.close_addr.do_send(CloseSession));
+    }
+}
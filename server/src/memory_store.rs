@@ -0,0 +1,302 @@
+use crate::common::unix_timestamp;
+use crate::error::SPTFError;
+use crate::session_store::{SessionStore, StoreWrite};
+use async_trait::async_trait;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Value {
+    String(String),
+    Hash(HashMap<String, String>),
+    Set(HashSet<String>),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    value: Value,
+    /// Unix timestamp after which the entry is gone, None if it never expires
+    expires_at: Option<i64>,
+}
+
+impl Entry {
+    fn new(value: Value, expiration_in_seconds: usize) -> Self {
+        Self {
+            value,
+            expires_at: Some(unix_timestamp() + expiration_in_seconds as i64),
+        }
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+/// Session store inside the server process, for single servers without Redis.
+///
+/// Sessions are lost on restart unless a file is given, which is then read
+/// on startup and rewritten by `flush`
+pub struct MemorySessionStore {
+    entries: Mutex<HashMap<String, Entry>>,
+    file_path: Option<PathBuf>,
+    /// Whether entries changed since the last flush
+    dirty: AtomicBool,
+}
+
+impl MemorySessionStore {
+    /// Will panic if given file exists but cannot be read
+    pub fn new(file_path: Option<PathBuf>) -> Self {
+        let entries = match &file_path {
+            Some(file_path) if file_path.exists() => {
+                let entries: HashMap<String, Entry> =
+                    serde_json::from_slice(&fs::read(file_path).unwrap())
+                        .expect("Session store file is corrupted");
+                info!("Loaded {} entries of session store", entries.len());
+                entries
+            }
+            _ => HashMap::new(),
+        };
+        Self {
+            entries: Mutex::new(entries),
+            file_path,
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<String, Entry>> {
+        self.entries.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Lock entries for a change
+    fn entries_mut(&self) -> MutexGuard<'_, HashMap<String, Entry>> {
+        let entries = self.entries();
+        self.dirty.store(true, Ordering::Relaxed);
+        entries
+    }
+
+    /// Drop expired entries, which are otherwise only dropped when accessed
+    pub fn purge_expired(&self) {
+        let mut entries = self.entries();
+        let now = unix_timestamp();
+        let entry_count = entries.len();
+        entries.retain(|_, entry| !entry.is_expired(now));
+        if entries.len() != entry_count {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Write entries to the file if any changed, replacing it at once so
+    /// that a crash leaves either the old or the new file
+    pub fn flush(&self) {
+        let file_path = match &self.file_path {
+            Some(file_path) => file_path,
+            None => return,
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let serialized = serde_json::to_vec(&*self.entries()).unwrap();
+        let temporary_file_path = file_path.with_extension("tmp");
+        if let Err(err) = fs::write(&temporary_file_path, serialized)
+            .and_then(|_| fs::rename(&temporary_file_path, file_path))
+        {
+            error!("Failed to write session store to {:?}: {}", file_path, err);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Entry at given key, dropping it first if expired
+fn live_entry<'a>(entries: &'a mut HashMap<String, Entry>, key: &str) -> Option<&'a mut Entry> {
+    if entries.get(key)?.is_expired(unix_timestamp()) {
+        entries.remove(key);
+        return None;
+    }
+    entries.get_mut(key)
+}
+
+fn set_in(
+    entries: &mut HashMap<String, Entry>,
+    key: &str,
+    value: &str,
+    expiration_in_seconds: usize,
+) {
+    entries.insert(
+        key.to_owned(),
+        Entry::new(Value::String(value.to_owned()), expiration_in_seconds),
+    );
+}
+
+fn delete_in(entries: &mut HashMap<String, Entry>, keys: &[String]) {
+    for key in keys {
+        entries.remove(key);
+    }
+}
+
+fn set_fields_in(
+    entries: &mut HashMap<String, Entry>,
+    key: &str,
+    fields: &[(&str, String)],
+    expiration_in_seconds: usize,
+) {
+    let mut hash = match live_entry(entries, key) {
+        Some(Entry {
+            value: Value::Hash(hash),
+            ..
+        }) => std::mem::take(hash),
+        _ => HashMap::new(),
+    };
+    for (field, value) in fields {
+        hash.insert((*field).to_owned(), value.clone());
+    }
+    entries.insert(
+        key.to_owned(),
+        Entry::new(Value::Hash(hash), expiration_in_seconds),
+    );
+}
+
+fn add_member_in(entries: &mut HashMap<String, Entry>, key: &str, member: &str) {
+    let entry = entries.entry(key.to_owned()).or_insert_with(|| Entry {
+        value: Value::Set(HashSet::new()),
+        expires_at: None,
+    });
+    match &mut entry.value {
+        Value::Set(set) => {
+            set.insert(member.to_owned());
+        }
+        value => *value = Value::Set(HashSet::from([member.to_owned()])),
+    }
+}
+
+fn remove_members_in(entries: &mut HashMap<String, Entry>, key: &str, members: &[String]) {
+    if let Some(Entry {
+        value: Value::Set(set),
+        ..
+    }) = entries.get_mut(key)
+    {
+        for member in members {
+            set.remove(member);
+        }
+        if set.is_empty() {
+            entries.remove(key);
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStore for MemorySessionStore {
+    async fn set(
+        &self,
+        key: &str,
+        value: &str,
+        expiration_in_seconds: usize,
+    ) -> Result<(), Box<dyn SPTFError>> {
+        set_in(&mut self.entries_mut(), key, value, expiration_in_seconds);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn SPTFError>> {
+        Ok(match live_entry(&mut self.entries(), key) {
+            Some(Entry {
+                value: Value::String(value),
+                ..
+            }) => Some(value.clone()),
+            _ => None,
+        })
+    }
+
+    async fn take(&self, key: &str) -> Result<Option<String>, Box<dyn SPTFError>> {
+        let entry = self
+            .entries_mut()
+            .remove(key)
+            .filter(|entry| !entry.is_expired(unix_timestamp()));
+        Ok(match entry {
+            Some(Entry {
+                value: Value::String(value),
+                ..
+            }) => Some(value),
+            _ => None,
+        })
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn SPTFError>> {
+        Ok(live_entry(&mut self.entries(), key).is_some())
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<(), Box<dyn SPTFError>> {
+        delete_in(&mut self.entries_mut(), keys);
+        Ok(())
+    }
+
+    async fn increment(
+        &self,
+        key: &str,
+        expiration_in_seconds: usize,
+    ) -> Result<usize, Box<dyn SPTFError>> {
+        let mut entries = self.entries_mut();
+        let count = match live_entry(&mut entries, key) {
+            Some(Entry {
+                value: Value::String(value),
+                ..
+            }) => value.parse::<usize>().unwrap_or_default() + 1,
+            _ => 1,
+        };
+        entries.insert(
+            key.to_owned(),
+            Entry::new(Value::String(count.to_string()), expiration_in_seconds),
+        );
+        Ok(count)
+    }
+
+    async fn get_fields(&self, key: &str) -> Result<HashMap<String, String>, Box<dyn SPTFError>> {
+        Ok(match live_entry(&mut self.entries(), key) {
+            Some(Entry {
+                value: Value::Hash(hash),
+                ..
+            }) => hash.clone(),
+            _ => HashMap::new(),
+        })
+    }
+
+    async fn members(&self, key: &str) -> Result<Vec<String>, Box<dyn SPTFError>> {
+        Ok(match live_entry(&mut self.entries(), key) {
+            Some(Entry {
+                value: Value::Set(set),
+                ..
+            }) => set.iter().cloned().collect(),
+            _ => vec![],
+        })
+    }
+
+    /// Holds the lock over all writes, which cannot fail halfway
+    async fn apply(&self, writes: &[StoreWrite<'_>]) -> Result<(), Box<dyn SPTFError>> {
+        let mut entries = self.entries_mut();
+        for write in writes {
+            match write {
+                StoreWrite::Set {
+                    key,
+                    value,
+                    expiration_in_seconds,
+                } => set_in(&mut entries, key, value, *expiration_in_seconds),
+                StoreWrite::Delete { keys } => delete_in(&mut entries, keys),
+                StoreWrite::SetFields {
+                    key,
+                    fields,
+                    expiration_in_seconds,
+                } => set_fields_in(&mut entries, key, fields, *expiration_in_seconds),
+                StoreWrite::AddMember { key, member } => add_member_in(&mut entries, key, member),
+                StoreWrite::RemoveMembers { key, members } => {
+                    remove_members_in(&mut entries, key, members)
+                }
+            }
+        }
+        Ok(())
+    }
+}
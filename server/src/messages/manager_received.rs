@@ -1,12 +1,17 @@
-use super::session_received::RefreshFilesMessage;
+use super::session_received::{CloseSession, RefreshFilesMessage};
+use crate::filewatcher::FileChange;
 use actix::prelude::*;
-use std::path::PathBuf;
+use uuid::Uuid;
 
 /// Sessions send this to Session manager
 #[derive(Message)]
 #[rtype(usize)]
 pub struct Connect {
     pub addr: Recipient<RefreshFilesMessage>,
+    pub close_addr: Recipient<CloseSession>,
+    pub user_id: Uuid,
+    /// Auth token or id of the API token the session is authenticated with
+    pub credential_id: String,
 }
 
 /// Session is disconnected
@@ -25,11 +30,26 @@ pub struct AddFilewatcher {
     pub addr: Addr<crate::filewatcher::FileWatcherActor>,
 }
 
-/// There is something chanegd in given path
+/// There is something chanegd in given paths
 ///
 /// Filewatcher send this to Session manager
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct FilePathHasSomethingChanged {
-    pub paths: Vec<PathBuf>,
+    pub changes: Vec<FileChange>,
+}
+
+/// Auth tokens are revoked, so sessions using them must be closed
+///
+/// We manually send this to Session manager
+#[derive(Message)]
+#[rtype(result = "()")]
+pub enum RevokeSessions {
+    /// Sessions of the user authenticated with given auth tokens or API token ids
+    Credentials {
+        user_id: Uuid,
+        credential_ids: Vec<String>,
+    },
+    /// All sessions of the user, whatever they are authenticated with
+    User { user_id: Uuid },
 }
@@ -1,9 +1,14 @@
+use crate::filewatcher::FileChange;
 use actix::prelude::*;
-use std::path::PathBuf;
 
 /// Session manager sends this to Sessions
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct RefreshFilesMessage {
-    pub file_paths: Vec<PathBuf>,
+    pub file_changes: Vec<FileChange>,
 }
+
+/// Session manager sends this to Sessions whose auth token got revoked
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseSession;
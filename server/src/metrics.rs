@@ -0,0 +1,54 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// What happened to changes of watched directories, counted over all
+/// websockets since startup
+#[derive(Default)]
+pub struct NotificationMetrics {
+    deltas_sent: AtomicU64,
+    listings_sent: AtomicU64,
+    coalesced: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Counts of `NotificationMetrics` as shown to admins
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationMetricsSnapshot {
+    /// Directory deltas sent
+    pub deltas_sent: u64,
+    /// Whole directories sent, in place of deltas or because deltas were
+    /// not known for sure
+    pub listings_sent: u64,
+    /// Changes merged into those of the same directory already waiting
+    pub coalesced: u64,
+    /// Changes never sent, as their directory was no longer watched by then
+    pub dropped: u64,
+}
+
+impl NotificationMetrics {
+    pub fn count_delta_sent(&self) {
+        self.deltas_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_listing_sent(&self) {
+        self.listings_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_coalesced(&self, count: u64) {
+        self.coalesced.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn count_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> NotificationMetricsSnapshot {
+        NotificationMetricsSnapshot {
+            deltas_sent: self.deltas_sent.load(Ordering::Relaxed),
+            listings_sent: self.listings_sent.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
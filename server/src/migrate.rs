@@ -0,0 +1,546 @@
+use crate::common::unix_timestamp;
+use crate::error::{MigrationError, SPTFError, UnexpectedError};
+use deadpool_postgres::Client as PostgresClient;
+use log::{error, info};
+
+/// Schema change shipped with the binary
+struct Migration {
+    version: i32,
+    name: &'static str,
+    sql: &'static str,
+}
+
+/// Migrations of each backend in order, never edit one that has been released.
+///
+/// Both lists create the same tables under the same versions
+const POSTGRES_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_users",
+        sql: include_str!("../migrations/postgres/0001_create_users.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_two_factor",
+        sql: include_str!("../migrations/postgres/0002_create_two_factor.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "create_api_tokens",
+        sql: include_str!("../migrations/postgres/0003_create_api_tokens.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "create_invite_codes",
+        sql: include_str!("../migrations/postgres/0004_create_invite_codes.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "create_oidc_identities",
+        sql: include_str!("../migrations/postgres/0005_create_oidc_identities.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "add_users_auth_source",
+        sql: include_str!("../migrations/postgres/0006_add_users_auth_source.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "create_audit_log",
+        sql: include_str!("../migrations/postgres/0007_create_audit_log.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "add_totp_last_step",
+        sql: include_str!("../migrations/postgres/0008_add_totp_last_step.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "create_file_owners",
+        sql: include_str!("../migrations/postgres/0009_create_file_owners.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "add_invite_codes_last_used_by",
+        sql: include_str!("../migrations/postgres/0010_add_invite_codes_last_used_by.sql"),
+    },
+];
+
+#[cfg(feature = "sqlite")]
+const SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_users",
+        sql: include_str!("../migrations/sqlite/0001_create_users.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_two_factor",
+        sql: include_str!("../migrations/sqlite/0002_create_two_factor.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "create_api_tokens",
+        sql: include_str!("../migrations/sqlite/0003_create_api_tokens.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "create_invite_codes",
+        sql: include_str!("../migrations/sqlite/0004_create_invite_codes.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "create_oidc_identities",
+        sql: include_str!("../migrations/sqlite/0005_create_oidc_identities.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "add_users_auth_source",
+        sql: include_str!("../migrations/sqlite/0006_add_users_auth_source.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "create_audit_log",
+        sql: include_str!("../migrations/sqlite/0007_create_audit_log.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "add_totp_last_step",
+        sql: include_str!("../migrations/sqlite/0008_add_totp_last_step.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "create_file_owners",
+        sql: include_str!("../migrations/sqlite/0009_create_file_owners.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "add_invite_codes_last_used_by",
+        sql: include_str!("../migrations/sqlite/0010_add_invite_codes_last_used_by.sql"),
+    },
+];
+
+/// Tables created by the create_table.sql script used before migrations,
+/// after Users, with the migration creating each of them now
+const LEGACY_TABLES: &[(i32, &str)] = &[(2, "TotpSecrets"), (3, "ApiTokens"), (4, "InviteCodes")];
+
+/// Bring Users created by create_table.sql up to migration 1, whichever
+/// version of the script created it
+const POSTGRES_LEGACY_USERS_SQL: &str = "ALTER TABLE Users ADD COLUMN IF NOT EXISTS role varchar(16) NOT NULL DEFAULT 'member', ADD COLUMN IF NOT EXISTS disabled boolean NOT NULL DEFAULT false, ADD COLUMN IF NOT EXISTS quota_bytes bigint, ADD COLUMN IF NOT EXISTS used_bytes bigint NOT NULL DEFAULT 0; CREATE UNIQUE INDEX IF NOT EXISTS users_username_key ON Users (lower(username));";
+
+/// Columns of migration 1 that Users created before migrations may lack
+#[cfg(feature = "sqlite")]
+const SQLITE_LEGACY_USERS_COLUMNS: &[(&str, &str)] = &[
+    ("role", "TEXT NOT NULL DEFAULT 'member'"),
+    ("disabled", "INTEGER NOT NULL DEFAULT 0"),
+    ("quota_bytes", "INTEGER"),
+    ("used_bytes", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+/// Version of a database created before migrations, given whether it has
+/// each of LEGACY_TABLES.
+///
+/// Users is at version 1 once its columns are added, and every further
+/// legacy table present in order is as its migration creates it
+fn legacy_version(has_legacy_tables: &[bool]) -> i32 {
+    let present = LEGACY_TABLES
+        .iter()
+        .zip(has_legacy_tables)
+        .take_while(|(_, has_table)| **has_table)
+        .count();
+    1 + present as i32
+}
+
+/// Migrations newer than given version.
+///
+/// Fails if the database has been migrated by a newer binary
+fn pending_migrations(
+    migrations: &'static [Migration],
+    current_version: i32,
+) -> Result<impl Iterator<Item = &'static Migration>, Box<dyn SPTFError>> {
+    let latest_version = migrations.last().map_or(0, |migration| migration.version);
+    if current_version > latest_version {
+        error!(
+            "Database schema version {} is newer than {} known to this binary",
+            current_version, latest_version
+        );
+        return Err(MigrationError::SchemaTooNew.to_boxed_self());
+    }
+    Ok(migrations
+        .iter()
+        .filter(move |migration| migration.version > current_version))
+}
+
+/// Arbitrary key of the advisory lock held while migrating, so that
+/// concurrently starting servers do not migrate twice
+const MIGRATION_LOCK_KEY: i64 = 0x5350_5446;
+
+/// Apply all pending migrations to Postgres
+pub async fn run_postgres_migrations(
+    postgres_client: &mut PostgresClient,
+) -> Result<(), Box<dyn SPTFError>> {
+    let transaction = postgres_client.transaction().await.map_err(|err| {
+        error!("Failed to start transaction: {}", err);
+        UnexpectedError.to_boxed_self()
+    })?;
+    transaction
+        .batch_execute(&format!(
+            "SELECT pg_advisory_xact_lock({});",
+            MIGRATION_LOCK_KEY
+        ))
+        .await
+        .map_err(|err| {
+            error!("Failed to lock migrations: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    // Created by create_table.sql if Users exists without schema_migrations
+    let is_legacy: bool = transaction
+        .query_one(
+            "SELECT to_regclass('schema_migrations') IS NULL AND to_regclass('users') IS NOT NULL",
+            &[],
+        )
+        .await
+        .and_then(|row| row.try_get(0))
+        .map_err(|err| {
+            error!(
+                "Failed to look for a database created before migrations: {}",
+                err
+            );
+            UnexpectedError.to_boxed_self()
+        })?;
+    transaction
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (version integer PRIMARY KEY, name text NOT NULL, applied_at bigint NOT NULL);",
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to prepare schema_migrations: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    if is_legacy {
+        baseline_postgres(&transaction).await?;
+    }
+    let current_version: i32 = transaction
+        .query_one(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+            &[],
+        )
+        .await
+        .and_then(|row| row.try_get(0))
+        .map_err(|err| {
+            error!("Failed to query schema version: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    for migration in pending_migrations(POSTGRES_MIGRATIONS, current_version)? {
+        info!(
+            "Applying migration {} {}",
+            migration.version, migration.name
+        );
+        transaction
+            .batch_execute(migration.sql)
+            .await
+            .map_err(|err| {
+                error!(
+                    "Failed to apply migration {} {}: {}",
+                    migration.version, migration.name, err
+                );
+                UnexpectedError.to_boxed_self()
+            })?;
+        transaction
+            .execute(
+                "INSERT INTO schema_migrations (version, name, applied_at) VALUES ($1, $2, $3)",
+                &[&migration.version, &migration.name, &unix_timestamp()],
+            )
+            .await
+            .map_err(|err| {
+                error!("Failed to record migration {}: {}", migration.version, err);
+                UnexpectedError.to_boxed_self()
+            })?;
+    }
+    transaction.commit().await.map_err(|err| {
+        error!("Failed to commit migrations: {}", err);
+        UnexpectedError.to_boxed_self()
+    })?;
+    Ok(())
+}
+
+/// Adopt a database created by create_table.sql, stamping the migrations
+/// it already has instead of applying them
+async fn baseline_postgres(
+    transaction: &deadpool_postgres::Transaction<'_>,
+) -> Result<(), Box<dyn SPTFError>> {
+    transaction
+        .batch_execute(POSTGRES_LEGACY_USERS_SQL)
+        .await
+        .map_err(|err| {
+            error!("Failed to bring legacy Users up to migration 1: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    let mut has_legacy_tables = vec![];
+    for (_, table) in LEGACY_TABLES {
+        let has_table: bool = transaction
+            .query_one(
+                "SELECT to_regclass($1) IS NOT NULL",
+                &[&table.to_lowercase()],
+            )
+            .await
+            .and_then(|row| row.try_get(0))
+            .map_err(|err| {
+                error!("Failed to look for legacy table {}: {}", table, err);
+                UnexpectedError.to_boxed_self()
+            })?;
+        has_legacy_tables.push(has_table);
+    }
+    let version = legacy_version(&has_legacy_tables);
+    info!(
+        "Adopting database created before migrations at version {}",
+        version
+    );
+    for migration in POSTGRES_MIGRATIONS
+        .iter()
+        .take_while(|migration| migration.version <= version)
+    {
+        transaction
+            .execute(
+                "INSERT INTO schema_migrations (version, name, applied_at) VALUES ($1, $2, $3)",
+                &[&migration.version, &migration.name, &unix_timestamp()],
+            )
+            .await
+            .map_err(|err| {
+                error!("Failed to record migration {}: {}", migration.version, err);
+                UnexpectedError.to_boxed_self()
+            })?;
+    }
+    Ok(())
+}
+
+/// Apply all pending migrations to SQLite.
+///
+/// The immediate transaction takes the write lock at once, so that
+/// concurrently starting servers do not migrate twice
+#[cfg(feature = "sqlite")]
+pub fn run_sqlite_migrations(
+    connection: &mut rusqlite::Connection,
+) -> Result<(), Box<dyn SPTFError>> {
+    let transaction = connection
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .map_err(|err| {
+            error!("Failed to start transaction: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    // Created before migrations if Users exists without schema_migrations
+    let is_legacy = !sqlite_has_table(&transaction, "schema_migrations")?
+        && sqlite_has_table(&transaction, "Users")?;
+    transaction
+        .execute_batch(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER PRIMARY KEY, name TEXT NOT NULL, applied_at INTEGER NOT NULL);",
+        )
+        .map_err(|err| {
+            error!("Failed to prepare schema_migrations: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    if is_legacy {
+        baseline_sqlite(&transaction)?;
+    }
+    let current_version: i32 = transaction
+        .query_row(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(|err| {
+            error!("Failed to query schema version: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    for migration in pending_migrations(SQLITE_MIGRATIONS, current_version)? {
+        info!(
+            "Applying migration {} {}",
+            migration.version, migration.name
+        );
+        transaction.execute_batch(migration.sql).map_err(|err| {
+            error!(
+                "Failed to apply migration {} {}: {}",
+                migration.version, migration.name, err
+            );
+            UnexpectedError.to_boxed_self()
+        })?;
+        transaction
+            .execute(
+                "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)",
+                rusqlite::params![migration.version, migration.name, unix_timestamp()],
+            )
+            .map_err(|err| {
+                error!("Failed to record migration {}: {}", migration.version, err);
+                UnexpectedError.to_boxed_self()
+            })?;
+    }
+    transaction.commit().map_err(|err| {
+        error!("Failed to commit migrations: {}", err);
+        UnexpectedError.to_boxed_self()
+    })?;
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_has_table(
+    connection: &rusqlite::Connection,
+    table: &str,
+) -> Result<bool, Box<dyn SPTFError>> {
+    connection
+        .query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type='table' AND name=?1 COLLATE NOCASE",
+            [table],
+            |row| row.get::<_, i64>(0),
+        )
+        .map(|count| count > 0)
+        .map_err(|err| {
+            error!("Failed to look for table {}: {}", table, err);
+            UnexpectedError.to_boxed_self()
+        })
+}
+
+/// Adopt a database whose tables were created before migrations, stamping
+/// the migrations it already has instead of applying them
+#[cfg(feature = "sqlite")]
+fn baseline_sqlite(connection: &rusqlite::Connection) -> Result<(), Box<dyn SPTFError>> {
+    let columns = connection
+        .prepare("SELECT name FROM pragma_table_info('Users')")
+        .and_then(|mut statement| {
+            statement
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|err| {
+            error!("Failed to read columns of legacy Users: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    for (column, definition) in SQLITE_LEGACY_USERS_COLUMNS {
+        if columns
+            .iter()
+            .any(|existing| existing.eq_ignore_ascii_case(column))
+        {
+            continue;
+        }
+        connection
+            .execute_batch(&format!(
+                "ALTER TABLE Users ADD COLUMN {} {};",
+                column, definition
+            ))
+            .map_err(|err| {
+                error!("Failed to add {} to legacy Users: {}", column, err);
+                UnexpectedError.to_boxed_self()
+            })?;
+    }
+    connection
+        .execute_batch(
+            "CREATE UNIQUE INDEX IF NOT EXISTS users_username_key ON Users (lower(username));",
+        )
+        .map_err(|err| {
+            error!("Failed to index legacy Users: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    let has_legacy_tables = LEGACY_TABLES
+        .iter()
+        .map(|(_, table)| sqlite_has_table(connection, table))
+        .collect::<Result<Vec<_>, _>>()?;
+    let version = legacy_version(&has_legacy_tables);
+    info!(
+        "Adopting database created before migrations at version {}",
+        version
+    );
+    for migration in SQLITE_MIGRATIONS
+        .iter()
+        .take_while(|migration| migration.version <= version)
+    {
+        connection
+            .execute(
+                "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)",
+                rusqlite::params![migration.version, migration.name, unix_timestamp()],
+            )
+            .map_err(|err| {
+                error!("Failed to record migration {}: {}", migration.version, err);
+                UnexpectedError.to_boxed_self()
+            })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_version_counts_legacy_tables_in_order() {
+        assert_eq!(legacy_version(&[false, false, false]), 1);
+        assert_eq!(legacy_version(&[true, false, false]), 2);
+        assert_eq!(legacy_version(&[true, true, true]), 4);
+        // A gap means later tables cannot be trusted to match their migration
+        assert_eq!(legacy_version(&[true, false, true]), 2);
+    }
+
+    #[test]
+    fn pending_migrations_refuses_newer_schema() {
+        assert_eq!(
+            pending_migrations(POSTGRES_MIGRATIONS, 0)
+                .ok()
+                .unwrap()
+                .count(),
+            POSTGRES_MIGRATIONS.len()
+        );
+        assert_eq!(
+            pending_migrations(POSTGRES_MIGRATIONS, 5)
+                .ok()
+                .unwrap()
+                .next()
+                .unwrap()
+                .version,
+            6
+        );
+        assert!(pending_migrations(POSTGRES_MIGRATIONS, 1000).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_adopts_database_created_before_migrations() {
+        let mut connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE Users (id TEXT, username TEXT, salt BLOB, password BLOB);
+                 INSERT INTO Users (id, username, salt, password) VALUES ('1', 'alice', x'00', x'00');
+                 CREATE TABLE TotpSecrets (user_id TEXT, secret BLOB, confirmed INTEGER);
+                 CREATE TABLE RecoveryCodes (user_id TEXT, code_hash BLOB);",
+            )
+            .unwrap();
+        assert!(run_sqlite_migrations(&mut connection).is_ok());
+        let versions = connection
+            .prepare("SELECT version FROM schema_migrations ORDER BY version")
+            .unwrap()
+            .query_map([], |row| row.get::<_, i32>(0))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            versions,
+            SQLITE_MIGRATIONS
+                .iter()
+                .map(|migration| migration.version)
+                .collect::<Vec<_>>()
+        );
+        let (role, auth_source): (String, String) = connection
+            .query_row(
+                "SELECT role, auth_source FROM Users WHERE username='alice'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((role.as_str(), auth_source.as_str()), ("member", "local"));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_migrates_empty_database_once() {
+        let mut connection = rusqlite::Connection::open_in_memory().unwrap();
+        assert!(run_sqlite_migrations(&mut connection).is_ok());
+        assert!(run_sqlite_migrations(&mut connection).is_ok());
+    }
+}
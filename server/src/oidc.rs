@@ -0,0 +1,526 @@
+use crate::common::{unix_timestamp, OIDC_STATE_EXPIRATION_IN_SECONDS};
+use crate::config::OidcConfig;
+use crate::database::Database;
+use crate::error::{OidcError, SPTFError, UnexpectedError};
+use crate::session_store::SessionStore;
+use log::{error, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Endpoints of the provider, from its discovery document
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// What is remembered between redirecting to the provider and its callback
+#[derive(Serialize, Deserialize)]
+struct AuthorizationState {
+    code_verifier: String,
+    nonce: String,
+    /// Set if a logged-in user links its account instead of logging in
+    link_user_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+/// Subject authenticated by the provider
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+    /// Value of the configured username claim, if present
+    pub username: Option<String>,
+}
+
+/// What the authorization was started for
+pub enum OidcAuthorization {
+    Login(OidcIdentity),
+    Link {
+        user_id: Uuid,
+        identity: OidcIdentity,
+    },
+}
+
+/// Authorization started for a browser, which has to hold the binding in a
+/// cookie when coming back with the state
+pub struct AuthorizationStart {
+    pub authorization_url: String,
+    pub state_binding: String,
+}
+
+fn oidc_state_key(state: &str) -> String {
+    format!("oidc_state:{}", state)
+}
+
+/// Hash of given state, so the cookie does not hand out the state itself
+fn state_binding(state: &str) -> String {
+    base64::encode_config(Sha256::digest(state.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Random url-safe string for state, nonce and PKCE verifier
+fn random_token() -> String {
+    base64::encode_config(
+        rand::thread_rng().gen::<[u8; 32]>(),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+fn provider_error<E: std::fmt::Display>(err: E) -> Box<dyn SPTFError> {
+    error!("Request to identity provider failed: {}", err);
+    OidcError::ProviderError.to_boxed_self()
+}
+
+/// Client of the configured OpenID Connect provider
+pub struct OidcClient {
+    config: OidcConfig,
+    http_client: reqwest::Client,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    /// Whether unknown subjects get a user created
+    pub fn auto_provision(&self) -> bool {
+        self.config.auto_provision
+    }
+
+    async fn discover(&self) -> Result<ProviderMetadata, Box<dyn SPTFError>> {
+        self.http_client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                self.config.issuer_url.trim_end_matches('/')
+            ))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(provider_error)?
+            .json::<ProviderMetadata>()
+            .await
+            .map_err(provider_error)
+    }
+
+    /// Start an authorization code flow with PKCE.
+    ///
+    /// Return the provider url to send the user to, and the binding of the
+    /// state to the browser sent there
+    pub async fn authorization_url(
+        &self,
+        session_store: &dyn SessionStore,
+        link_user_id: Option<Uuid>,
+    ) -> Result<AuthorizationStart, Box<dyn SPTFError>> {
+        let metadata = self.discover().await?;
+        let state = random_token();
+        let authorization_state = AuthorizationState {
+            code_verifier: random_token(),
+            nonce: random_token(),
+            link_user_id: link_user_id.map(|user_id| user_id.to_string()),
+        };
+        let code_challenge = base64::encode_config(
+            Sha256::digest(authorization_state.code_verifier.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+        session_store
+            .set(
+                &oidc_state_key(&state),
+                &serde_json::to_string(&authorization_state).unwrap(),
+                OIDC_STATE_EXPIRATION_IN_SECONDS,
+            )
+            .await?;
+        let separator = if metadata.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let authorization_url = format!(
+            "{}{}response_type=code&scope=openid%20profile&client_id={}&redirect_uri={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+            metadata.authorization_endpoint,
+            separator,
+            urlencoding::encode(&self.config.client_id),
+            urlencoding::encode(&self.config.redirect_url),
+            state,
+            authorization_state.nonce,
+            code_challenge,
+        );
+        Ok(AuthorizationStart {
+            authorization_url,
+            state_binding: state_binding(&state),
+        })
+    }
+
+    /// Finish the flow started with given state by redeeming the code.
+    ///
+    /// The state is refused unless it comes with the binding of the browser
+    /// the flow was started for, so that no one can make another browser
+    /// finish a flow of theirs. The ID token comes straight from the token
+    /// endpoint over TLS, so its claims are checked but not its signature
+    pub async fn complete_authorization(
+        &self,
+        session_store: &dyn SessionStore,
+        state: &str,
+        code: &str,
+        browser_state_binding: Option<&str>,
+    ) -> Result<OidcAuthorization, Box<dyn SPTFError>> {
+        if browser_state_binding != Some(state_binding(state).as_str()) {
+            warn!("Rejected OIDC state not bound to the browser");
+            return Err(OidcError::InvalidState.to_boxed_self());
+        }
+        let authorization_state: AuthorizationState = session_store
+            .take(&oidc_state_key(state))
+            .await?
+            .and_then(|authorization_state| serde_json::from_str(&authorization_state).ok())
+            .ok_or_else(|| OidcError::InvalidState.to_boxed_self())?;
+
+        let metadata = self.discover().await?;
+        let mut params = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &authorization_state.code_verifier),
+        ];
+        if let Some(client_secret) = &self.config.client_secret {
+            params.push(("client_secret", client_secret));
+        }
+        let token_response = self
+            .http_client
+            .post(&metadata.token_endpoint)
+            .form(&params)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(provider_error)?
+            .json::<TokenResponse>()
+            .await
+            .map_err(provider_error)?;
+
+        let claims = token_response
+            .id_token
+            .split('.')
+            .nth(1)
+            .and_then(|payload| base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok())
+            .and_then(|payload| serde_json::from_slice::<IdTokenClaims>(&payload).ok())
+            .ok_or_else(|| OidcError::InvalidIdToken.to_boxed_self())?;
+        let audience_matches = match &claims.aud {
+            Audience::Single(audience) => audience == &self.config.client_id,
+            Audience::Multiple(audiences) => audiences.contains(&self.config.client_id),
+        };
+        if claims.iss != metadata.issuer
+            || !audience_matches
+            || claims.exp <= unix_timestamp()
+            || claims.nonce.as_ref() != Some(&authorization_state.nonce)
+        {
+            warn!("Rejected ID token of subject {}", claims.sub);
+            return Err(OidcError::InvalidIdToken.to_boxed_self());
+        }
+
+        let identity = OidcIdentity {
+            username: claims
+                .other
+                .get(&self.config.username_claim)
+                .and_then(|username| username.as_str())
+                .map(str::to_owned),
+            issuer: claims.iss,
+            subject: claims.sub,
+        };
+        match authorization_state.link_user_id {
+            Some(user_id) => Ok(OidcAuthorization::Link {
+                user_id: Uuid::parse_str(&user_id).map_err(|err| {
+                    error!("Parse stored user uuid {} failed: {}", user_id, err);
+                    UnexpectedError.to_boxed_self()
+                })?,
+                identity,
+            }),
+            None => Ok(OidcAuthorization::Login(identity)),
+        }
+    }
+}
+
+/// Whether given state was started to link an identity, leaving it in place
+pub async fn is_pending_link(
+    session_store: &dyn SessionStore,
+    state: &str,
+) -> Result<bool, Box<dyn SPTFError>> {
+    Ok(session_store
+        .get(&oidc_state_key(state))
+        .await?
+        .and_then(|authorization_state| {
+            serde_json::from_str::<AuthorizationState>(&authorization_state)
+                .ok()?
+                .link_user_id
+        })
+        .is_some())
+}
+
+/// Find the user given identity is linked to
+pub async fn find_linked_user(
+    database: &dyn Database,
+    identity: &OidcIdentity,
+) -> Result<Option<Uuid>, Box<dyn SPTFError>> {
+    let row = database
+        .query_opt(
+            "SELECT user_id FROM OidcIdentities WHERE issuer=$1 AND subject=$2",
+            &[
+                identity.issuer.as_str().into(),
+                identity.subject.as_str().into(),
+            ],
+        )
+        .await
+        .map_err(|err| {
+            error!("Query oidc identity {} failed: {}", identity.subject, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    row.map(|row| row.try_get(0)).transpose().map_err(|err| {
+        error!("Fetch user_id field failed: {}", err);
+        UnexpectedError.to_boxed_self()
+    })
+}
+
+/// Link given identity to given user
+pub async fn link_identity(
+    database: &dyn Database,
+    user_id: Uuid,
+    identity: &OidcIdentity,
+) -> Result<(), Box<dyn SPTFError>> {
+    database
+        .execute(
+            "INSERT INTO OidcIdentities (issuer, subject, user_id, created_at) VALUES ($1, $2, $3, $4)",
+            &[identity.issuer.as_str().into(), identity.subject.as_str().into(), user_id.into(), unix_timestamp().into()],
+        )
+        .await
+        .map_err(|err| {
+            if err.is_unique_violation() {
+                return OidcError::AlreadyLinked.to_boxed_self();
+            }
+            error!("Failed to link oidc identity to {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    Ok(())
+}
+
+/// Ignored tests run against the `mock-idp` service of docker-compose.yml with
+/// `docker compose --profile oidc-test up -d` and `cargo test -- --ignored`
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_pending_link, oidc_state_key, state_binding, AuthorizationState, OidcAuthorization,
+        OidcClient,
+    };
+    use crate::config::OidcConfig;
+    use crate::error::{OidcError, SPTFError};
+    use crate::memory_store::MemorySessionStore;
+    use crate::session_store::SessionStore;
+    use reqwest::{redirect::Policy as RedirectPolicy, Url};
+    use uuid::Uuid;
+
+    const REDIRECT_URL: &str = "http://localhost/oidc/callback";
+
+    fn issuer_url() -> String {
+        let port = std::env::var("MOCK_IDP_HOST_PORT").expect("MOCK_IDP_HOST_PORT is not set");
+        format!("http://localhost:{}/default", port)
+    }
+
+    fn test_client() -> OidcClient {
+        client_of(issuer_url())
+    }
+
+    fn client_of(issuer_url: String) -> OidcClient {
+        OidcClient::new(OidcConfig {
+            issuer_url,
+            client_id: "sptf".to_owned(),
+            client_secret: None,
+            redirect_url: REDIRECT_URL.to_owned(),
+            auto_provision: false,
+            username_claim: "preferred_username".to_owned(),
+        })
+    }
+
+    /// Follow given authorization url, which the mock server answers by
+    /// redirecting to us at once, returning state and code of the redirect
+    async fn authorize(authorization_url: &str) -> (String, String) {
+        let response = reqwest::Client::builder()
+            .redirect(RedirectPolicy::none())
+            .build()
+            .unwrap()
+            .get(authorization_url)
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_redirection());
+        let location = Url::parse(response.headers()["location"].to_str().unwrap()).unwrap();
+        assert!(location.as_str().starts_with(REDIRECT_URL));
+        let query_value = |name: &str| {
+            location
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+        (query_value("state"), query_value("code"))
+    }
+
+    #[actix_web::test]
+    async fn state_of_another_browser_is_refused() {
+        let session_store = MemorySessionStore::new(None);
+        let state = "state";
+        let authorization_state = AuthorizationState {
+            code_verifier: "verifier".to_owned(),
+            nonce: "nonce".to_owned(),
+            link_user_id: Some(Uuid::new_v4().to_string()),
+        };
+        session_store
+            .set(
+                &oidc_state_key(state),
+                &serde_json::to_string(&authorization_state).unwrap(),
+                60,
+            )
+            .await
+            .unwrap();
+        assert!(is_pending_link(&session_store, state).await.unwrap());
+
+        // Refused before the provider is asked, which does not exist here
+        let client = client_of("http://localhost:1/default".to_owned());
+        for browser_state_binding in [None, Some(state_binding("other state").as_str())] {
+            assert_eq!(
+                client
+                    .complete_authorization(&session_store, state, "code", browser_state_binding)
+                    .await
+                    .err()
+                    .unwrap()
+                    .error_code(),
+                OidcError::InvalidState.error_code()
+            );
+        }
+        // The state is left for the browser it belongs to
+        assert!(session_store.exists(&oidc_state_key(state)).await.unwrap());
+    }
+
+    #[ignore]
+    #[actix_web::test]
+    async fn pkce_login() {
+        let client = test_client();
+        let session_store = MemorySessionStore::new(None);
+        let start = client
+            .authorization_url(&session_store, None)
+            .await
+            .unwrap();
+        assert!(start
+            .authorization_url
+            .contains("code_challenge_method=S256"));
+        let (state, code) = authorize(&start.authorization_url).await;
+
+        match client
+            .complete_authorization(&session_store, &state, &code, Some(&start.state_binding))
+            .await
+            .unwrap()
+        {
+            OidcAuthorization::Login(identity) => {
+                assert_eq!(identity.issuer, issuer_url());
+                assert!(!identity.subject.is_empty());
+            }
+            OidcAuthorization::Link { .. } => panic!("Login completed as a link"),
+        }
+        // The state is used up
+        assert_eq!(
+            client
+                .complete_authorization(&session_store, &state, &code, Some(&start.state_binding),)
+                .await
+                .err()
+                .unwrap()
+                .error_code(),
+            OidcError::InvalidState.error_code()
+        );
+    }
+
+    #[ignore]
+    #[actix_web::test]
+    async fn pkce_link() {
+        let client = test_client();
+        let session_store = MemorySessionStore::new(None);
+        let user_id = Uuid::new_v4();
+        let start = client
+            .authorization_url(&session_store, Some(user_id))
+            .await
+            .unwrap();
+        let (state, code) = authorize(&start.authorization_url).await;
+        assert!(is_pending_link(&session_store, &state).await.unwrap());
+
+        match client
+            .complete_authorization(&session_store, &state, &code, Some(&start.state_binding))
+            .await
+            .unwrap()
+        {
+            OidcAuthorization::Link {
+                user_id: linked_user_id,
+                ..
+            } => assert_eq!(linked_user_id, user_id),
+            OidcAuthorization::Login(_) => panic!("Link completed as a login"),
+        }
+    }
+
+    #[ignore]
+    #[actix_web::test]
+    async fn provider_rejects_wrong_code_verifier() {
+        let client = test_client();
+        let session_store = MemorySessionStore::new(None);
+        let start = client
+            .authorization_url(&session_store, None)
+            .await
+            .unwrap();
+        let (state, code) = authorize(&start.authorization_url).await;
+
+        // Swap the verifier for one not matching the challenge sent
+        let key = oidc_state_key(&state);
+        let mut authorization_state: AuthorizationState =
+            serde_json::from_str(&session_store.get(&key).await.unwrap().unwrap()).unwrap();
+        authorization_state.code_verifier = super::random_token();
+        session_store
+            .set(
+                &key,
+                &serde_json::to_string(&authorization_state).unwrap(),
+                60,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            client
+                .complete_authorization(&session_store, &state, &code, Some(&start.state_binding),)
+                .await
+                .err()
+                .unwrap()
+                .error_code(),
+            OidcError::ProviderError.error_code()
+        );
+    }
+}
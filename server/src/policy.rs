@@ -0,0 +1,84 @@
+use crate::common::USERNAME_MAX_LENGTH;
+use crate::config::CredentialPolicyConfig;
+use crate::error::{PolicyError, SPTFError};
+use std::collections::HashSet;
+use unicode_normalization::UnicodeNormalization;
+
+/// Bring username into its canonical form, so that look-alike spellings
+/// refer to the same user
+pub fn normalize_username(username: &str) -> String {
+    username.nfkc().collect()
+}
+
+/// Rules usernames and passwords are checked against when set
+pub struct CredentialPolicy {
+    config: CredentialPolicyConfig,
+    /// Lowercased known breached passwords
+    breached_passwords: HashSet<String>,
+}
+
+impl CredentialPolicy {
+    /// Create policy from config, reading the breached password file if any.
+    ///
+    /// Will panic if the file cannot be read
+    pub fn new(config: CredentialPolicyConfig) -> Self {
+        let breached_passwords = match &config.breached_passwords_file_path {
+            Some(path) => std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_lowercase)
+                .collect(),
+            None => HashSet::new(),
+        };
+        Self {
+            config,
+            breached_passwords,
+        }
+    }
+
+    /// Check username of a new user.
+    ///
+    /// Return the normalized username to store
+    pub fn check_username(&self, username: &str) -> Result<String, Box<dyn SPTFError>> {
+        let username = normalize_username(username);
+        let length = username.chars().count();
+        if length < self.config.username_min_length
+            || length > self.config.username_max_length.min(USERNAME_MAX_LENGTH)
+        {
+            return Err(PolicyError::UsernameLength.to_boxed_self());
+        }
+        if !username.chars().all(|char| {
+            char.is_alphanumeric() || self.config.username_allowed_symbols.contains(char)
+        }) {
+            return Err(PolicyError::UsernameCharacters.to_boxed_self());
+        }
+        Ok(username)
+    }
+
+    /// Check a password about to be set
+    pub fn check_password(&self, password: &str) -> Result<(), Box<dyn SPTFError>> {
+        let length = password.chars().count();
+        if length < self.config.password_min_length || length > self.config.password_max_length {
+            return Err(PolicyError::PasswordLength.to_boxed_self());
+        }
+        let character_classes = [
+            password.chars().any(char::is_lowercase),
+            password.chars().any(char::is_uppercase),
+            password.chars().any(|char| char.is_ascii_digit()),
+            password
+                .chars()
+                .any(|char| !char.is_lowercase() && !char.is_uppercase() && !char.is_ascii_digit()),
+        ]
+        .iter()
+        .filter(|has_class| **has_class)
+        .count();
+        if character_classes < self.config.password_min_character_classes {
+            return Err(PolicyError::PasswordTooWeak.to_boxed_self());
+        }
+        if self.breached_passwords.contains(&password.to_lowercase()) {
+            return Err(PolicyError::PasswordBreached.to_boxed_self());
+        }
+        Ok(())
+    }
+}
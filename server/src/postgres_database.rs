@@ -0,0 +1,161 @@
+use crate::database::{Database, DatabaseError, Row, SqlValue};
+use crate::error::{SPTFError, UnexpectedError};
+use crate::migrate;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use deadpool_postgres::{Client as PostgresClient, Pool};
+use log::error;
+use std::error::Error;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use uuid::Uuid;
+
+/// Database in Postgres, shared by all servers using it
+pub struct PostgresDatabase {
+    pool: Pool,
+}
+
+impl PostgresDatabase {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+
+    async fn client(&self) -> Result<PostgresClient, DatabaseError> {
+        self.pool.get().await.map_err(|err| {
+            DatabaseError::new(format!(
+                "Failed to get a connection from postgres connection pool: {}",
+                err
+            ))
+        })
+    }
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        match self {
+            SqlValue::Null => Ok(IsNull::Yes),
+            SqlValue::Bool(value) => value.to_sql_checked(ty, out),
+            SqlValue::Int(value) => match *ty {
+                Type::INT2 => i16::try_from(*value)?.to_sql_checked(ty, out),
+                Type::INT4 => i32::try_from(*value)?.to_sql_checked(ty, out),
+                _ => value.to_sql_checked(ty, out),
+            },
+            SqlValue::Text(value) => value.to_sql_checked(ty, out),
+            SqlValue::Bytes(value) => value.to_sql_checked(ty, out),
+            SqlValue::Uuid(value) => value.to_sql_checked(ty, out),
+            SqlValue::TextArray(value) => value.to_sql_checked(ty, out),
+        }
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+fn database_error(err: tokio_postgres::Error) -> DatabaseError {
+    if err.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+        DatabaseError::unique_violation(err)
+    } else {
+        DatabaseError::new(err)
+    }
+}
+
+fn to_sql_params(params: &[SqlValue]) -> Vec<&(dyn ToSql + Sync)> {
+    params
+        .iter()
+        .map(|param| param as &(dyn ToSql + Sync))
+        .collect()
+}
+
+/// Read a column into the value matching its type
+fn column_value(row: &tokio_postgres::Row, index: usize) -> Result<SqlValue, DatabaseError> {
+    fn value<'a, T: tokio_postgres::types::FromSql<'a>>(
+        row: &'a tokio_postgres::Row,
+        index: usize,
+        into_value: impl FnOnce(T) -> SqlValue,
+    ) -> Result<SqlValue, DatabaseError> {
+        Ok(row
+            .try_get::<_, Option<T>>(index)
+            .map_err(DatabaseError::new)?
+            .map_or(SqlValue::Null, into_value))
+    }
+    match *row.columns()[index].type_() {
+        Type::BOOL => value(row, index, SqlValue::Bool),
+        Type::INT2 => value(row, index, |value: i16| SqlValue::Int(value.into())),
+        Type::INT4 => value(row, index, |value: i32| SqlValue::Int(value.into())),
+        Type::INT8 => value(row, index, SqlValue::Int),
+        Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME => value(row, index, SqlValue::Text),
+        Type::BYTEA => value(row, index, SqlValue::Bytes),
+        Type::UUID => value(row, index, |value: Uuid| SqlValue::Uuid(value)),
+        Type::TEXT_ARRAY | Type::VARCHAR_ARRAY => value(row, index, SqlValue::TextArray),
+        ref ty => Err(DatabaseError::new(format!(
+            "Unsupported type {} of column {}",
+            ty, index
+        ))),
+    }
+}
+
+fn to_row(row: tokio_postgres::Row) -> Result<Row, DatabaseError> {
+    (0..row.len())
+        .map(|index| column_value(&row, index))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Row::new)
+}
+
+#[async_trait(?Send)]
+impl Database for PostgresDatabase {
+    async fn run_migrations(&self) -> Result<(), Box<dyn SPTFError>> {
+        let mut postgres_client = self.client().await.map_err(|err| {
+            error!("{}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+        migrate::run_postgres_migrations(&mut postgres_client).await
+    }
+
+    async fn execute(&self, statement: &str, params: &[SqlValue]) -> Result<u64, DatabaseError> {
+        self.client()
+            .await?
+            .execute(statement, &to_sql_params(params))
+            .await
+            .map_err(database_error)
+    }
+
+    async fn query(&self, statement: &str, params: &[SqlValue]) -> Result<Vec<Row>, DatabaseError> {
+        self.client()
+            .await?
+            .query(statement, &to_sql_params(params))
+            .await
+            .map_err(database_error)?
+            .into_iter()
+            .map(to_row)
+            .collect()
+    }
+
+    async fn transaction(
+        &self,
+        statements: &[(&str, &[SqlValue])],
+    ) -> Result<Vec<u64>, DatabaseError> {
+        let mut postgres_client = self.client().await?;
+        let transaction = postgres_client
+            .transaction()
+            .await
+            .map_err(database_error)?;
+        let mut changed_row_counts = Vec::with_capacity(statements.len());
+        for (statement, params) in statements {
+            changed_row_counts.push(
+                transaction
+                    .execute(*statement, &to_sql_params(params))
+                    .await
+                    .map_err(database_error)?,
+            );
+        }
+        transaction.commit().await.map_err(database_error)?;
+        Ok(changed_row_counts)
+    }
+}
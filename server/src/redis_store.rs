@@ -0,0 +1,150 @@
+use crate::error::{SPTFError, UnexpectedError};
+use crate::session_store::{SessionStore, StoreWrite};
+use async_trait::async_trait;
+use deadpool_redis::{Connection as RedisConnection, Pool as RedisPool};
+use log::error;
+use std::collections::HashMap;
+
+/// Session store in Redis, shared by all servers using the same Redis
+pub struct RedisSessionStore {
+    pool: RedisPool,
+}
+
+impl RedisSessionStore {
+    pub fn new(pool: RedisPool) -> Self {
+        Self { pool }
+    }
+
+    async fn connection(&self) -> Result<RedisConnection, Box<dyn SPTFError>> {
+        self.pool.get().await.map_err(|err| {
+            error!(
+                "Failed to get a connection from redis connection pool: {}",
+                err
+            );
+            UnexpectedError.to_boxed_self()
+        })
+    }
+}
+
+fn query_failed(key: &str, err: redis::RedisError) -> Box<dyn SPTFError> {
+    error!("Redis query on {} failed: {}", key, err);
+    UnexpectedError.to_boxed_self()
+}
+
+#[async_trait(?Send)]
+impl SessionStore for RedisSessionStore {
+    async fn set(
+        &self,
+        key: &str,
+        value: &str,
+        expiration_in_seconds: usize,
+    ) -> Result<(), Box<dyn SPTFError>> {
+        redis::Cmd::set_ex(key, value, expiration_in_seconds)
+            .query_async::<_, ()>(&mut self.connection().await?)
+            .await
+            .map_err(|err| query_failed(key, err))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn SPTFError>> {
+        redis::Cmd::get(key)
+            .query_async(&mut self.connection().await?)
+            .await
+            .map_err(|err| query_failed(key, err))
+    }
+
+    async fn take(&self, key: &str) -> Result<Option<String>, Box<dyn SPTFError>> {
+        let (value,): (Option<String>,) = redis::pipe()
+            .atomic()
+            .get(key)
+            .del(key)
+            .ignore()
+            .query_async(&mut self.connection().await?)
+            .await
+            .map_err(|err| query_failed(key, err))?;
+        Ok(value)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn SPTFError>> {
+        redis::Cmd::exists(key)
+            .query_async(&mut self.connection().await?)
+            .await
+            .map_err(|err| query_failed(key, err))
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<(), Box<dyn SPTFError>> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        redis::Cmd::del(keys)
+            .query_async::<_, ()>(&mut self.connection().await?)
+            .await
+            .map_err(|err| query_failed(&keys.join(" "), err))
+    }
+
+    async fn increment(
+        &self,
+        key: &str,
+        expiration_in_seconds: usize,
+    ) -> Result<usize, Box<dyn SPTFError>> {
+        let (value,): (usize,) = redis::pipe()
+            .atomic()
+            .incr(key, 1)
+            .expire(key, expiration_in_seconds)
+            .ignore()
+            .query_async(&mut self.connection().await?)
+            .await
+            .map_err(|err| query_failed(key, err))?;
+        Ok(value)
+    }
+
+    async fn get_fields(&self, key: &str) -> Result<HashMap<String, String>, Box<dyn SPTFError>> {
+        redis::Cmd::hgetall(key)
+            .query_async(&mut self.connection().await?)
+            .await
+            .map_err(|err| query_failed(key, err))
+    }
+
+    async fn members(&self, key: &str) -> Result<Vec<String>, Box<dyn SPTFError>> {
+        redis::Cmd::smembers(key)
+            .query_async(&mut self.connection().await?)
+            .await
+            .map_err(|err| query_failed(key, err))
+    }
+
+    /// Sends all writes in one MULTI/EXEC transaction
+    async fn apply(&self, writes: &[StoreWrite<'_>]) -> Result<(), Box<dyn SPTFError>> {
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        for write in writes {
+            match write {
+                StoreWrite::Set {
+                    key,
+                    value,
+                    expiration_in_seconds,
+                } => pipeline
+                    .set_ex(*key, *value, *expiration_in_seconds)
+                    .ignore(),
+                StoreWrite::Delete { keys: [] } => &mut pipeline,
+                StoreWrite::Delete { keys } => pipeline.del(*keys).ignore(),
+                StoreWrite::SetFields {
+                    key,
+                    fields,
+                    expiration_in_seconds,
+                } => pipeline
+                    .hset_multiple(*key, fields)
+                    .ignore()
+                    .expire(*key, *expiration_in_seconds)
+                    .ignore(),
+                StoreWrite::AddMember { key, member } => pipeline.sadd(*key, *member).ignore(),
+                StoreWrite::RemoveMembers { members: [], .. } => &mut pipeline,
+                StoreWrite::RemoveMembers { key, members } => {
+                    pipeline.srem(*key, *members).ignore()
+                }
+            };
+        }
+        pipeline
+            .query_async::<_, ()>(&mut self.connection().await?)
+            .await
+            .map_err(|err| query_failed("batch", err))
+    }
+}
@@ -1,13 +1,31 @@
-use crate::error::{ProtobufError, SPTFError};
+use crate::audit::{self, AuditRecorder};
+use crate::auth::{AuthenticatedUser, Scope};
+use crate::common::{
+    MAX_PENDING_DELTAS_PER_DIRECTORY, MAX_SUBSCRIPTIONS_PER_SESSION, WEBSOCKET_MAX_FILE_SIZE,
+};
+use crate::config::NotificationConfig;
+use crate::database::Database;
+use crate::error::{FileError, ProtobufError, SPTFError, SubscriptionError, ValidateError};
+use crate::files;
+use crate::filewatcher::FileChange;
 use crate::messages::*;
-use crate::protos::sptf::{BasicIncomingMessage, BasicOutcomingMessage};
+use crate::metrics::NotificationMetrics;
+use crate::protos::sptf::{
+    AuthenticateResponse, BasicIncomingMessage, BasicOutcomingMessage, DirectoryDelta,
+    DirectoryLayout_File, FileDelta, FileDelta_Renamed, FileOperationResponse,
+    ListDirectoryResponse, PushNotification, ReadFileResponse, StatResponse,
+};
+use crate::user::SessionUse;
 use actix::prelude::*;
 use actix_web_actors::ws;
 use log::{info, warn};
 use protobuf::Message;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use uuid::Uuid;
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -15,12 +33,162 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// How long a session opened without credential may wait before authenticating
+const AUTHENTICATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type TokenValidationFuture =
+    Pin<Box<dyn Future<Output = Result<AuthenticatedUser, Box<dyn SPTFError>>>>>;
+
+/// Validates the auth token of an authenticate message
+type TokenValidator = Box<dyn Fn(String, SessionUse) -> TokenValidationFuture>;
+
+/// Puts the result of a file operation on given path into the reply
+type FileOperationReply<T> = fn(String, Result<T, Box<dyn SPTFError>>, &mut BasicOutcomingMessage);
+
+/// Directory whose changes a client asked for
+struct Subscription {
+    real_path: PathBuf,
+    /// Also covers every directory below
+    recursive: bool,
+}
+
+impl Subscription {
+    /// Whether changes in given real directory path are sent
+    fn covers(&self, real_directory_path: &Path) -> bool {
+        if self.recursive {
+            real_directory_path.starts_with(&self.real_path)
+        } else {
+            real_directory_path == self.real_path
+        }
+    }
+}
+
+/// Changes of watched directories, by real directory path
+#[derive(Default)]
+struct DirectoryChanges {
+    deltas: HashMap<PathBuf, Vec<FileDelta>>,
+    /// Directories to be sent whole, as their changes are not known for
+    /// sure. They have no deltas
+    resyncs: HashSet<PathBuf>,
+}
+
+impl DirectoryChanges {
+    fn push(&mut self, directory: &Path, delta: FileDelta) {
+        if !self.resyncs.contains(directory) {
+            self.deltas
+                .entry(directory.to_path_buf())
+                .or_default()
+                .push(delta);
+        }
+    }
+
+    /// Send given directory whole, dropping its deltas
+    fn resync(&mut self, directory: PathBuf) {
+        self.deltas.remove(&directory);
+        self.resyncs.insert(directory);
+    }
+
+    /// Add changes that came later, returning in how many directories they
+    /// joined changes still waiting
+    fn merge(&mut self, later: DirectoryChanges) -> u64 {
+        let mut coalesced = 0;
+        for directory in later.resyncs {
+            if self.resyncs.contains(&directory) || self.deltas.contains_key(&directory) {
+                coalesced += 1;
+            }
+            self.resync(directory);
+        }
+        for (directory, deltas) in later.deltas {
+            if self.resyncs.contains(&directory) {
+                coalesced += 1;
+                continue;
+            }
+            let pending_deltas = self.deltas.entry(directory.clone()).or_default();
+            if !pending_deltas.is_empty() {
+                coalesced += 1;
+            }
+            pending_deltas.extend(deltas);
+            if pending_deltas.len() > MAX_PENDING_DELTAS_PER_DIRECTORY {
+                self.resync(directory);
+            }
+        }
+        coalesced
+    }
+
+    fn is_empty(&self) -> bool {
+        self.deltas.is_empty() && self.resyncs.is_empty()
+    }
+
+    /// Tell a file created or modified with its metadata, which needs a
+    /// resync if the file is gone already
+    fn push_file(
+        &mut self,
+        root_path: &Path,
+        directory: &Path,
+        real_path: &Path,
+        set_delta: fn(&mut FileDelta, DirectoryLayout_File),
+    ) {
+        match files::file_entry_at(root_path, real_path) {
+            Ok(file) => {
+                let mut delta = FileDelta::default();
+                set_delta(&mut delta, file);
+                self.push(directory, delta);
+            }
+            Err(_) => self.resync(directory.to_path_buf()),
+        }
+    }
+
+    fn push_removed(&mut self, root_path: &Path, directory: &Path, real_path: &Path) {
+        if let Some(path) = files::user_aware_path(root_path, real_path) {
+            let mut delta = FileDelta::default();
+            delta.set_Removed((*path.to_string_lossy()).into());
+            self.push(directory, delta);
+        }
+    }
+
+    fn push_renamed(&mut self, root_path: &Path, directory: &Path, from: &Path, to: &Path) {
+        let from_path = files::user_aware_path(root_path, from);
+        match (from_path, files::file_entry_at(root_path, to)) {
+            (Some(from_path), Ok(file)) => {
+                let mut renamed = FileDelta_Renamed::default();
+                renamed.set_from_path((*from_path.to_string_lossy()).into());
+                renamed.set_to(file);
+                let mut delta = FileDelta::default();
+                delta.set_Renamed(renamed);
+                self.push(directory, delta);
+            }
+            _ => self.resync(directory.to_path_buf()),
+        }
+    }
+}
+
+/// How sessions coalesce changes of watched directories, shared by all of them
+#[derive(Clone)]
+pub struct NotificationSettings {
+    pub config: NotificationConfig,
+    pub metrics: Arc<NotificationMetrics>,
+}
+
 /// User session actor
 pub struct UserSession {
     /// Unique ID indicating self to session manager
     session_id: Option<usize>,
-    /// Unique ID indicating self to user manager
-    user_id: Uuid,
+    /// User owning the session, with its role and credential.
+    ///
+    /// None until the authenticate message of a session opened without credential
+    authenticated_user: Option<AuthenticatedUser>,
+    /// Credential the session is authenticated with, None until then
+    token: Option<String>,
+    /// Checks the token of the authenticate message, and the token again
+    /// every SESSION_REVALIDATION_INTERVAL
+    token_validator: TokenValidator,
+    /// Writes requests of the session to the audit log
+    audit_recorder: AuditRecorder,
+    /// Where upload quotas are kept
+    database: Arc<dyn Database>,
+    /// Protocol version of the last message of the client, deciding how
+    /// server-initiated messages are sent
+    client_version: u32,
     /// Last heartbeat time
     ///
     /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
@@ -28,24 +196,84 @@ pub struct UserSession {
     heartbeat: Instant,
     /// Address of session manager
     manager_address: Addr<crate::manager::SessionManager>,
-    /// User watched paths
-    watched_path: Option<PathBuf>,
+    /// Directory listed last, watched for clients not subscribing to
+    /// directories themselves
+    listed_path: Option<PathBuf>,
+    /// Directories subscribed to, by normalized user-aware path
+    subscriptions: HashMap<PathBuf, Subscription>,
+    /// Changes of watched directories waiting to be sent together
+    pending_changes: DirectoryChanges,
+    /// When the oldest pending change came, None if there is none
+    pending_since: Option<Instant>,
+    /// Sends pending changes, rescheduled as more come
+    flush_handle: Option<SpawnHandle>,
+    notifications: NotificationSettings,
     root_path: PathBuf,
 }
 
 impl UserSession {
-    /// Create a new user session
-    pub fn new(
+    /// Create a new user session, authenticated with given token
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<V, F>(
         manager_address: Addr<crate::manager::SessionManager>,
-        user_id: Uuid,
+        authenticated_user: AuthenticatedUser,
+        token: String,
         root_path: PathBuf,
-    ) -> Self {
+        token_validator: V,
+        audit_recorder: AuditRecorder,
+        database: Arc<dyn Database>,
+        notifications: NotificationSettings,
+    ) -> Self
+    where
+        V: Fn(String, SessionUse) -> F + 'static,
+        F: Future<Output = Result<AuthenticatedUser, Box<dyn SPTFError>>> + 'static,
+    {
+        Self {
+            authenticated_user: Some(authenticated_user),
+            token: Some(token),
+            ..Self::unauthenticated(
+                manager_address,
+                root_path,
+                token_validator,
+                audit_recorder,
+                database,
+                notifications,
+            )
+        }
+    }
+
+    /// Create a session whose client must send an authenticate message
+    /// first, which is checked with given validator
+    pub fn unauthenticated<V, F>(
+        manager_address: Addr<crate::manager::SessionManager>,
+        root_path: PathBuf,
+        token_validator: V,
+        audit_recorder: AuditRecorder,
+        database: Arc<dyn Database>,
+        notifications: NotificationSettings,
+    ) -> Self
+    where
+        V: Fn(String, SessionUse) -> F + 'static,
+        F: Future<Output = Result<AuthenticatedUser, Box<dyn SPTFError>>> + 'static,
+    {
         Self {
             session_id: None,
-            user_id,
+            authenticated_user: None,
+            token: None,
+            token_validator: Box::new(move |token, session_use| -> TokenValidationFuture {
+                Box::pin(token_validator(token, session_use))
+            }),
+            audit_recorder,
+            database,
+            client_version: crate::common::MIN_PROTOCOL_VERSION,
             heartbeat: Instant::now(),
             manager_address,
-            watched_path: None,
+            listed_path: None,
+            subscriptions: HashMap::new(),
+            pending_changes: DirectoryChanges::default(),
+            pending_since: None,
+            flush_handle: None,
+            notifications,
             root_path,
         }
     }
@@ -70,19 +298,49 @@ impl UserSession {
             ctx.ping(b"");
         });
     }
-}
-
-impl Actor for UserSession {
-    type Context = ws::WebsocketContext<Self>;
 
-    /// Method is called on actor start. We start the heartbeat process here.
-    fn started(&mut self, ctx: &mut Self::Context) {
-        self.start_beating_heart(ctx);
+    /// Close the session once its token expires or is revoked.
+    ///
+    /// Revalidating does not count as use of the token, so an idle client
+    /// is disconnected once its session times out
+    fn start_revalidating(&self, ctx: &mut <Self as Actor>::Context) {
+        ctx.run_interval(crate::common::SESSION_REVALIDATION_INTERVAL, |act, ctx| {
+            let token = match &act.token {
+                Some(token) => token.clone(),
+                None => return,
+            };
+            (act.token_validator)(token, SessionUse::Peek)
+                .into_actor(act)
+                .then(|result, act, ctx| {
+                    match result {
+                        // Picks up role changes too
+                        Ok(authenticated_user) => act.authenticated_user = Some(authenticated_user),
+                        Err(err) => {
+                            info!("Websocket credential expired, disconnecting!");
+                            act.send_session_error(ctx, err.as_ref());
+                            close_unauthenticated(ctx, "Session expired");
+                        }
+                    }
+                    fut::ready(())
+                })
+                .spawn(ctx);
+        });
+    }
 
+    /// Register at session manager, so that the session gets file changes
+    /// and is closed when its credential is revoked or its user changes
+    fn connect(&self, ctx: &mut <Self as Actor>::Context) {
+        let authenticated_user = match &self.authenticated_user {
+            Some(authenticated_user) => authenticated_user,
+            None => return,
+        };
         let addr = ctx.address();
         self.manager_address
             .send(Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                close_addr: addr.recipient(),
+                user_id: authenticated_user.user_id,
+                credential_id: authenticated_user.credential_id(),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
@@ -96,6 +354,385 @@ impl Actor for UserSession {
             .wait(ctx);
     }
 
+    /// Validate the token of an authenticate message, holding back other
+    /// messages until done
+    fn authenticate(
+        &mut self,
+        auth_token: String,
+        request_id: Option<u64>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        if self.token.is_some() {
+            warn!("Websocket client authenticates twice");
+            send_general_error(ctx, &ProtobufError::WrongFormat, request_id);
+            return;
+        }
+        self.token = Some(auth_token.clone());
+        (self.token_validator)(auth_token, SessionUse::Touch)
+            .into_actor(self)
+            .then(move |result, act, ctx| {
+                match result {
+                    Ok(authenticated_user) => {
+                        info!(
+                            "Websocket of user {} authenticated",
+                            authenticated_user.user_id
+                        );
+                        act.authenticated_user = Some(authenticated_user);
+                        act.connect(ctx);
+                        let mut response = BasicOutcomingMessage::default();
+                        response.set_version(crate::common::PROTOCOL_VERSION);
+                        response.set_AuthenticateResponse(AuthenticateResponse::default());
+                        if let Some(request_id) = request_id {
+                            response.set_request_id(request_id);
+                        }
+                        ctx.binary(response.write_to_bytes().unwrap_or_else(|err| {
+                            warn!("Failed to write to bytes: {}", err);
+                            vec![]
+                        }));
+                    }
+                    Err(err) => {
+                        send_general_error(ctx, err.as_ref(), request_id);
+                        close_unauthenticated(ctx, "Authentication failed");
+                    }
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Send a message the client did not ask for, wrapped in a push
+    /// notification if the client understands them
+    fn send_push_notification(
+        &self,
+        ctx: &mut <Self as Actor>::Context,
+        notification: PushNotification,
+    ) {
+        let mut response = BasicOutcomingMessage::default();
+        response.set_version(crate::common::PROTOCOL_VERSION);
+        if self.client_version >= crate::common::PUSH_NOTIFICATION_PROTOCOL_VERSION {
+            response.set_PushNotification(notification);
+        } else {
+            use crate::protos::sptf::PushNotification_oneof_notification::*;
+            match notification.notification {
+                Some(DirectoryChanged(list_directory_response)) => {
+                    response.set_ListDirectoryResponse(list_directory_response)
+                }
+                Some(SessionError(error_response)) => response.set_GeneralError(error_response),
+                // Only sent to clients understanding them
+                Some(DirectoryDelta(_)) | None => return,
+            }
+        }
+        ctx.binary(response.write_to_bytes().unwrap_or_else(|err| {
+            warn!("Failed to write to bytes: {}", err);
+            vec![]
+        }));
+    }
+
+    /// Tell the client why its session is about to be closed
+    fn send_session_error(&self, ctx: &mut <Self as Actor>::Context, error: &dyn SPTFError) {
+        let mut notification = PushNotification::default();
+        notification.set_SessionError(error.to_proto_error());
+        self.send_push_notification(ctx, notification);
+    }
+}
+
+impl UserSession {
+    /// Run a file operation without holding back other messages, then reply
+    /// with its result and record it in the audit log.
+    ///
+    /// `action` is the audit action with the path the request is about
+    fn spawn_file_operation<T, F>(
+        &self,
+        ctx: &mut <Self as Actor>::Context,
+        request_id: Option<u64>,
+        action: (&'static str, String),
+        operation: F,
+        reply: FileOperationReply<T>,
+    ) where
+        T: 'static,
+        F: Future<Output = Result<T, Box<dyn SPTFError>>> + 'static,
+    {
+        operation
+            .into_actor(self)
+            .map(move |result, act, ctx| {
+                act.finish_file_operation(ctx, request_id, action, result, reply)
+            })
+            .spawn(ctx);
+    }
+
+    /// Reply with the result of a file operation and record it in the audit log
+    fn finish_file_operation<T>(
+        &self,
+        ctx: &mut <Self as Actor>::Context,
+        request_id: Option<u64>,
+        action: (&'static str, String),
+        result: Result<T, Box<dyn SPTFError>>,
+        reply: FileOperationReply<T>,
+    ) {
+        let (action, path) = action;
+        if let Some(authenticated_user) = &self.authenticated_user {
+            self.audit_recorder.record(
+                authenticated_user.user_id,
+                action,
+                Some(path.clone()),
+                result.as_ref().err().map(|err| err.error_code()),
+            );
+        }
+        let mut response = BasicOutcomingMessage::default();
+        response.set_version(crate::common::PROTOCOL_VERSION);
+        if let Some(request_id) = request_id {
+            response.set_request_id(request_id);
+        }
+        reply(path, result, &mut response);
+        ctx.binary(response.write_to_bytes().unwrap_or_else(|err| {
+            warn!("Failed to write to bytes: {}", err);
+            vec![]
+        }));
+    }
+
+    /// Path of given real directory path as the client knows it, if the
+    /// client watches it
+    fn watched_directory_path(&self, real_directory_path: &Path) -> Option<PathBuf> {
+        if let Some(listed_path) = &self.listed_path {
+            if files::real_path(&self.root_path, listed_path) == real_directory_path {
+                // Keep the path as the client listed it, so it knows the listing
+                return Some(listed_path.clone());
+            }
+        }
+        if self
+            .subscriptions
+            .values()
+            .any(|subscription| subscription.covers(real_directory_path))
+        {
+            files::user_aware_path(&self.root_path, real_directory_path)
+                .map(|user_aware_path| Path::new("/").join(user_aware_path))
+        } else {
+            None
+        }
+    }
+
+    /// Real paths of the directories listed or subscribed to, without
+    /// those below recursive subscriptions
+    fn watched_real_paths(&self) -> Vec<PathBuf> {
+        self.listed_path
+            .iter()
+            .map(|listed_path| files::real_path(&self.root_path, listed_path))
+            .chain(
+                self.subscriptions
+                    .values()
+                    .map(|subscription| subscription.real_path.clone()),
+            )
+            .collect()
+    }
+
+    /// Send the whole layout of given real directory path
+    fn send_directory_changed(
+        &self,
+        ctx: &mut <Self as Actor>::Context,
+        real_directory_path: &Path,
+    ) {
+        match self.watched_directory_path(real_directory_path) {
+            Some(directory_path) => {
+                let list_directory_response = files::list_dir(&self.root_path, &directory_path);
+                let mut notification = PushNotification::default();
+                notification.set_DirectoryChanged(list_directory_response);
+                self.send_push_notification(ctx, notification);
+                self.notifications.metrics.count_listing_sent();
+            }
+            None => self.notifications.metrics.count_dropped(),
+        }
+    }
+
+    /// Send changes of given real directory path as a delta if the client
+    /// understands them, otherwise the directory whole
+    fn send_directory_delta(
+        &self,
+        ctx: &mut <Self as Actor>::Context,
+        real_directory_path: &Path,
+        deltas: Vec<FileDelta>,
+    ) {
+        if self.client_version < crate::common::DIRECTORY_DELTA_PROTOCOL_VERSION {
+            self.send_directory_changed(ctx, real_directory_path);
+            return;
+        }
+        match self.watched_directory_path(real_directory_path) {
+            Some(directory_path) => {
+                let mut directory_delta = DirectoryDelta::default();
+                directory_delta.set_directory_path((*directory_path.to_string_lossy()).into());
+                directory_delta.set_deltas(deltas.into());
+                let mut notification = PushNotification::default();
+                notification.set_DirectoryDelta(directory_delta);
+                self.send_push_notification(ctx, notification);
+                self.notifications.metrics.count_delta_sent();
+            }
+            None => self.notifications.metrics.count_dropped(),
+        }
+    }
+
+    /// Send pending changes once none came within the coalesce window, or
+    /// once the oldest of them waited for the max delay
+    fn schedule_flush(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let now = Instant::now();
+        let pending_since = *self.pending_since.get_or_insert(now);
+        let deadline = (now + self.notifications.config.coalesce_window())
+            .min(pending_since + self.notifications.config.max_delay());
+        if let Some(flush_handle) = self.flush_handle.take() {
+            ctx.cancel_future(flush_handle);
+        }
+        self.flush_handle = Some(
+            ctx.run_later(deadline.saturating_duration_since(now), |act, ctx| {
+                act.flush_changes(ctx)
+            }),
+        );
+    }
+
+    /// Send changes of every watched directory that has some, at most one
+    /// message per directory
+    fn flush_changes(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.flush_handle = None;
+        self.pending_since = None;
+        let changes = std::mem::take(&mut self.pending_changes);
+        for directory in &changes.resyncs {
+            self.send_directory_changed(ctx, directory);
+        }
+        for (directory, deltas) in changes.deltas {
+            self.send_directory_delta(ctx, &directory, deltas);
+        }
+    }
+
+    /// Send changes of given directory, and of those below if recursive,
+    /// until unsubscribed.
+    ///
+    /// Subscribing to a directory again only changes whether it is recursive
+    fn subscribe(&mut self, path: &str, recursive: bool) -> Result<(), Box<dyn SPTFError>> {
+        let normalized_path = crate::auth::normalize_path(Path::new(path))
+            .ok_or_else(|| FileError::InvalidPath.to_boxed_self())?;
+        if !self.subscriptions.contains_key(&normalized_path)
+            && self.subscriptions.len() >= MAX_SUBSCRIPTIONS_PER_SESSION
+        {
+            return Err(SubscriptionError::TooMany.to_boxed_self());
+        }
+        let real_path = files::watched_directory(&self.root_path, &normalized_path)?;
+        self.subscriptions.insert(
+            normalized_path,
+            Subscription {
+                real_path,
+                recursive,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stop sending changes of given directory, which is fine if it was
+    /// never subscribed to
+    fn unsubscribe(&mut self, path: &str) -> Result<(), Box<dyn SPTFError>> {
+        let normalized_path = crate::auth::normalize_path(Path::new(path))
+            .ok_or_else(|| FileError::InvalidPath.to_boxed_self())?;
+        self.subscriptions.remove(&normalized_path);
+        Ok(())
+    }
+}
+
+/// Make sure given user may do what given scope covers on all given paths
+fn check_access(
+    authenticated_user: &AuthenticatedUser,
+    scope: Scope,
+    paths: &[&str],
+) -> Result<(), Box<dyn SPTFError>> {
+    authenticated_user.require_scope(scope)?;
+    paths
+        .iter()
+        .try_for_each(|path| authenticated_user.require_path(Path::new(path)))
+}
+
+fn file_operation_reply(
+    path: String,
+    result: Result<(), Box<dyn SPTFError>>,
+    response: &mut BasicOutcomingMessage,
+) {
+    let mut file_operation_response = FileOperationResponse::default();
+    file_operation_response.set_path(path.into());
+    if let Err(err) = result {
+        file_operation_response.set_ErrorResponse(err.to_proto_error());
+    }
+    response.set_FileOperationResponse(file_operation_response);
+}
+
+fn stat_reply(
+    path: String,
+    result: Result<DirectoryLayout_File, Box<dyn SPTFError>>,
+    response: &mut BasicOutcomingMessage,
+) {
+    let mut stat_response = StatResponse::default();
+    stat_response.set_path(path.into());
+    match result {
+        Ok(file) => stat_response.set_File(file),
+        Err(err) => stat_response.set_ErrorResponse(err.to_proto_error()),
+    }
+    response.set_StatResponse(stat_response);
+}
+
+fn read_file_reply(
+    path: String,
+    result: Result<Vec<u8>, Box<dyn SPTFError>>,
+    response: &mut BasicOutcomingMessage,
+) {
+    let mut read_file_response = ReadFileResponse::default();
+    read_file_response.set_path(path.into());
+    match result {
+        Ok(content) => read_file_response.set_content(content.into()),
+        Err(err) => read_file_response.set_ErrorResponse(err.to_proto_error()),
+    }
+    response.set_ReadFileResponse(read_file_response);
+}
+
+/// Send an error in reply to a message that could not be handled at all,
+/// with the request id of the message if known
+fn send_general_error(
+    ctx: &mut <UserSession as Actor>::Context,
+    error: &dyn SPTFError,
+    request_id: Option<u64>,
+) {
+    let mut response = BasicOutcomingMessage::default();
+    response.set_version(crate::common::PROTOCOL_VERSION);
+    response.set_GeneralError(error.to_proto_error());
+    if let Some(request_id) = request_id {
+        response.set_request_id(request_id);
+    }
+    ctx.binary(response.write_to_bytes().unwrap_or_else(|err| {
+        warn!("Failed to write to bytes: {}", err);
+        vec![]
+    }));
+}
+
+fn close_unauthenticated(ctx: &mut <UserSession as Actor>::Context, description: &str) {
+    ctx.close(Some(ws::CloseReason {
+        code: ws::CloseCode::Policy,
+        description: Some(description.to_owned()),
+    }));
+    ctx.stop();
+}
+
+impl Actor for UserSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    /// Method is called on actor start. We start the heartbeat process here.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.start_beating_heart(ctx);
+        self.start_revalidating(ctx);
+
+        if self.authenticated_user.is_some() {
+            self.connect(ctx);
+        } else {
+            ctx.run_later(AUTHENTICATE_TIMEOUT, |act, ctx| {
+                if act.authenticated_user.is_none() {
+                    info!("Websocket client did not authenticate in time, disconnecting!");
+                    close_unauthenticated(ctx, "Authentication timed out");
+                }
+            });
+        }
+    }
+
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         if let Some(id) = self.session_id {
             // notify session manager
@@ -132,7 +769,12 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for UserSession {
                         return;
                     }
                 };
-                if request.get_version() != crate::common::PROTOCOL_VERSION {
+                if request.has_request_id() {
+                    response.set_request_id(request.get_request_id());
+                }
+                if request.get_version() < crate::common::MIN_PROTOCOL_VERSION
+                    || request.get_version() > crate::common::PROTOCOL_VERSION
+                {
                     warn!("Incompatible version: {}", request.get_version());
                     let error = ProtobufError::WrongFormat;
                     response.set_GeneralError(error.to_proto_error());
@@ -142,6 +784,8 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for UserSession {
                     }));
                     return;
                 }
+                self.client_version = request.get_version();
+                let request_id = request.has_request_id().then(|| request.get_request_id());
                 let message_content = if let Some(message_content) = request.message_content {
                     message_content
                 } else {
@@ -155,22 +799,212 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for UserSession {
                     return;
                 };
                 use crate::protos::sptf::BasicIncomingMessage_oneof_message_content::*;
+                if let AuthenticateMessage(authenticate_request) = &message_content {
+                    let auth_token = authenticate_request.get_auth_token().to_owned();
+                    self.authenticate(auth_token, request_id, ctx);
+                    return;
+                }
+                let authenticated_user = match &self.authenticated_user {
+                    Some(authenticated_user) => authenticated_user,
+                    None => {
+                        warn!("Websocket client sends a request before authenticating");
+                        send_general_error(ctx, &ValidateError::WrongCookie, request_id);
+                        close_unauthenticated(ctx, "Not authenticated");
+                        return;
+                    }
+                };
                 match message_content {
+                    AuthenticateMessage(_) => {}
                     ListDirectoryMessage(list_directory_request) => {
                         info!(
                             "Get list directory {} request.",
                             list_directory_request.get_path()
                         );
-                        let list_directory_response = crate::files::list_dir(
-                            &self.root_path,
-                            &Path::new(list_directory_request.get_path()),
+                        let path = Path::new(list_directory_request.get_path());
+                        if let Err(err) = authenticated_user
+                            .require_scope(Scope::Read)
+                            .and_then(|_| authenticated_user.require_path(path))
+                        {
+                            warn!("List directory {:?} is not allowed", path);
+                            self.audit_recorder.record(
+                                authenticated_user.user_id,
+                                audit::LIST_DIRECTORY_ACTION,
+                                Some(list_directory_request.get_path().to_owned()),
+                                Some(err.error_code()),
+                            );
+                            let mut list_directory_response = ListDirectoryResponse::default();
+                            list_directory_response
+                                .set_directory_path(list_directory_request.get_path().into());
+                            list_directory_response.set_ErrorResponse(err.to_proto_error());
+                            response.set_ListDirectoryResponse(list_directory_response);
+                            ctx.binary(response.write_to_bytes().unwrap_or_else(|err| {
+                                warn!("Failed to write to bytes: {}", err);
+                                vec![]
+                            }));
+                            return;
+                        }
+                        let list_directory_response = crate::files::list_dir(&self.root_path, path);
+                        self.audit_recorder.record(
+                            authenticated_user.user_id,
+                            audit::LIST_DIRECTORY_ACTION,
+                            Some(list_directory_request.get_path().to_owned()),
+                            list_directory_response.has_ErrorResponse().then(|| {
+                                list_directory_response.get_ErrorResponse().get_error_code()
+                                    as usize
+                            }),
                         );
                         response.set_ListDirectoryResponse(list_directory_response);
                         ctx.binary(response.write_to_bytes().unwrap_or_else(|err| {
                             warn!("Failed to write to bytes: {}", err);
                             vec![]
                         }));
-                        self.watched_path = Some(PathBuf::from(list_directory_request.get_path()));
+                        self.listed_path = Some(PathBuf::from(list_directory_request.get_path()));
+                    }
+                    MakeDirectoryMessage(make_directory_request) => {
+                        let path = make_directory_request.get_path().to_owned();
+                        let access = check_access(authenticated_user, Scope::Write, &[&path]);
+                        let root_path = self.root_path.clone();
+                        let directory_path = PathBuf::from(&path);
+                        self.spawn_file_operation(
+                            ctx,
+                            request_id,
+                            (audit::MAKE_DIRECTORY_ACTION, path),
+                            async move {
+                                access?;
+                                files::make_directory(&root_path, &directory_path).await
+                            },
+                            file_operation_reply,
+                        );
+                    }
+                    StatMessage(stat_request) => {
+                        let path = stat_request.get_path().to_owned();
+                        let access = check_access(authenticated_user, Scope::Read, &[&path]);
+                        let root_path = self.root_path.clone();
+                        let stat_path = PathBuf::from(&path);
+                        self.spawn_file_operation(
+                            ctx,
+                            request_id,
+                            (audit::STAT_ACTION, path),
+                            async move {
+                                access?;
+                                files::stat(&root_path, &stat_path).await
+                            },
+                            stat_reply,
+                        );
+                    }
+                    UploadFileMessage(file_upload_request) => {
+                        let path = file_upload_request.get_dir_path().to_owned();
+                        let access = check_access(authenticated_user, Scope::Write, &[&path]);
+                        let upload_bytes: usize = file_upload_request
+                            .get_uploaded_file()
+                            .iter()
+                            .map(|file| file.get_content().len())
+                            .sum();
+                        let root_path = self.root_path.clone();
+                        let database = self.database.clone();
+                        let user_id = authenticated_user.user_id;
+                        self.spawn_file_operation(
+                            ctx,
+                            request_id,
+                            (audit::UPLOAD_ACTION, path),
+                            async move {
+                                access?;
+                                if upload_bytes > WEBSOCKET_MAX_FILE_SIZE {
+                                    return Err(FileError::TooLarge.to_boxed_self());
+                                }
+                                files::upload_files_within_quota(
+                                    database.as_ref(),
+                                    &root_path,
+                                    user_id,
+                                    file_upload_request,
+                                )
+                                .await
+                            },
+                            file_operation_reply,
+                        );
+                    }
+                    ReadFileMessage(read_file_request) => {
+                        let path = read_file_request.get_path().to_owned();
+                        let access = check_access(authenticated_user, Scope::Read, &[&path]);
+                        let root_path = self.root_path.clone();
+                        let file_path = PathBuf::from(&path);
+                        self.spawn_file_operation(
+                            ctx,
+                            request_id,
+                            (audit::READ_FILE_ACTION, path),
+                            async move {
+                                access?;
+                                files::read_file(&root_path, &file_path, WEBSOCKET_MAX_FILE_SIZE)
+                                    .await
+                            },
+                            read_file_reply,
+                        );
+                    }
+                    DeleteMessage(delete_request) => {
+                        let path = delete_request.get_path().to_owned();
+                        let access = check_access(authenticated_user, Scope::Write, &[&path]);
+                        let root_path = self.root_path.clone();
+                        let database = self.database.clone();
+                        let deleted_path = PathBuf::from(&path);
+                        self.spawn_file_operation(
+                            ctx,
+                            request_id,
+                            (audit::DELETE_ACTION, path),
+                            async move {
+                                access?;
+                                files::delete(database.as_ref(), &root_path, &deleted_path).await
+                            },
+                            file_operation_reply,
+                        );
+                    }
+                    SubscribeMessage(subscribe_request) => {
+                        let path = subscribe_request.get_path().to_owned();
+                        let result = check_access(authenticated_user, Scope::Read, &[&path])
+                            .and_then(|_| self.subscribe(&path, subscribe_request.get_recursive()));
+                        self.finish_file_operation(
+                            ctx,
+                            request_id,
+                            (audit::SUBSCRIBE_ACTION, path),
+                            result,
+                            file_operation_reply,
+                        );
+                    }
+                    UnsubscribeMessage(unsubscribe_request) => {
+                        let path = unsubscribe_request.get_path().to_owned();
+                        let result = self.unsubscribe(&path);
+                        self.finish_file_operation(
+                            ctx,
+                            request_id,
+                            (audit::UNSUBSCRIBE_ACTION, path),
+                            result,
+                            file_operation_reply,
+                        );
+                    }
+                    RenameMessage(rename_request) => {
+                        let from_path = rename_request.get_from_path().to_owned();
+                        let to_path = rename_request.get_to_path().to_owned();
+                        let access =
+                            check_access(authenticated_user, Scope::Write, &[&from_path, &to_path]);
+                        let root_path = self.root_path.clone();
+                        let database = self.database.clone();
+                        let (real_from_path, real_to_path) =
+                            (PathBuf::from(&from_path), PathBuf::from(&to_path));
+                        self.spawn_file_operation(
+                            ctx,
+                            request_id,
+                            (audit::RENAME_ACTION, from_path),
+                            async move {
+                                access?;
+                                files::rename(
+                                    database.as_ref(),
+                                    &root_path,
+                                    &real_from_path,
+                                    &real_to_path,
+                                )
+                                .await
+                            },
+                            file_operation_reply,
+                        );
                     }
                 }
             }
@@ -187,25 +1021,152 @@ impl Handler<RefreshFilesMessage> for UserSession {
         msg: RefreshFilesMessage,
         ctx: &mut ws::WebsocketContext<Self>,
     ) -> Self::Result {
-        if let Some(watched_path) = &self.watched_path {
-            if msg
-                .file_paths
-                .into_iter()
-                .filter_map(|file_path| file_path.parent().map(Path::to_path_buf))
-                .collect::<Vec<_>>()
-                .contains(&crate::files::real_path(&self.root_path, &watched_path))
-            {
-                // TODO: How to debounce this?
-                let mut response = BasicOutcomingMessage::default();
-                response.set_version(crate::common::PROTOCOL_VERSION);
-                let list_directory_response =
-                    crate::files::list_dir(&self.root_path, &watched_path);
-                response.set_ListDirectoryResponse(list_directory_response);
-                ctx.binary(response.write_to_bytes().unwrap_or_else(|err| {
-                    warn!("Failed to write to bytes: {}", err);
-                    vec![]
-                }));
+        let root_path = &self.root_path;
+        // Real path of the directory holding given file, if it is watched
+        let watched_parent = |real_path: &Path| {
+            real_path
+                .parent()
+                .filter(|parent| self.watched_directory_path(parent).is_some())
+                .map(Path::to_path_buf)
+        };
+        let mut changes = DirectoryChanges::default();
+        for file_change in msg.file_changes {
+            match file_change {
+                FileChange::Created(path) => {
+                    if let Some(directory) = watched_parent(&path) {
+                        changes.push_file(root_path, &directory, &path, FileDelta::set_Created);
+                    }
+                }
+                FileChange::Modified(path) => {
+                    if let Some(directory) = watched_parent(&path) {
+                        changes.push_file(root_path, &directory, &path, FileDelta::set_Modified);
+                    }
+                }
+                FileChange::Removed(path) => {
+                    if let Some(directory) = watched_parent(&path) {
+                        changes.push_removed(root_path, &directory, &path);
+                    }
+                }
+                FileChange::Renamed { from, to } => {
+                    match (watched_parent(&from), watched_parent(&to)) {
+                        (Some(from_directory), Some(to_directory))
+                            if from_directory == to_directory =>
+                        {
+                            changes.push_renamed(root_path, &to_directory, &from, &to);
+                        }
+                        (from_directory, to_directory) => {
+                            if let Some(from_directory) = from_directory {
+                                changes.push_removed(root_path, &from_directory, &from);
+                            }
+                            if let Some(to_directory) = to_directory {
+                                changes.push_file(
+                                    root_path,
+                                    &to_directory,
+                                    &to,
+                                    FileDelta::set_Created,
+                                );
+                            }
+                        }
+                    }
+                }
+                FileChange::Unreliable(path) => {
+                    if let Some(directory) = path.as_deref().and_then(watched_parent) {
+                        changes.resync(directory);
+                    }
+                    for directory in self.watched_real_paths() {
+                        if path.as_ref().is_none_or(|path| directory.starts_with(path)) {
+                            changes.resync(directory);
+                        }
+                    }
+                }
             }
         }
+
+        if changes.is_empty() {
+            return;
+        }
+        let coalesced = self.pending_changes.merge(changes);
+        self.notifications.metrics.count_coalesced(coalesced);
+        self.schedule_flush(ctx);
+    }
+}
+
+impl Handler<CloseSession> for UserSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: CloseSession, ctx: &mut ws::WebsocketContext<Self>) -> Self::Result {
+        if let Some(authenticated_user) = &self.authenticated_user {
+            info!(
+                "Session of user {} revoked, disconnecting!",
+                authenticated_user.user_id
+            );
+        }
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some("Session revoked".to_owned()),
+        }));
+        ctx.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DirectoryChanges;
+    use crate::common::MAX_PENDING_DELTAS_PER_DIRECTORY;
+    use crate::protos::sptf::FileDelta;
+    use std::path::{Path, PathBuf};
+
+    fn removed(path: &str) -> FileDelta {
+        let mut delta = FileDelta::default();
+        delta.set_Removed(path.into());
+        delta
+    }
+
+    fn removed_paths(changes: &DirectoryChanges, directory: &str) -> Vec<String> {
+        changes.deltas[Path::new(directory)]
+            .iter()
+            .map(|delta| delta.get_Removed().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn merge_appends_deltas_in_order() {
+        let mut pending = DirectoryChanges::default();
+        pending.push(Path::new("/a"), removed("/a/1"));
+        let mut later = DirectoryChanges::default();
+        later.push(Path::new("/a"), removed("/a/2"));
+        later.push(Path::new("/b"), removed("/b/1"));
+        assert_eq!(pending.merge(later), 1);
+        assert_eq!(removed_paths(&pending, "/a"), ["/a/1", "/a/2"]);
+        assert_eq!(removed_paths(&pending, "/b"), ["/b/1"]);
+        assert!(pending.resyncs.is_empty());
+    }
+
+    #[test]
+    fn merge_resync_replaces_deltas() {
+        let mut pending = DirectoryChanges::default();
+        pending.push(Path::new("/a"), removed("/a/1"));
+        pending.resync(PathBuf::from("/b"));
+        let mut later = DirectoryChanges::default();
+        later.resync(PathBuf::from("/a"));
+        later.push(Path::new("/b"), removed("/b/1"));
+        later.resync(PathBuf::from("/c"));
+        assert_eq!(pending.merge(later), 2);
+        assert!(pending.deltas.is_empty());
+        assert_eq!(pending.resyncs.len(), 3);
+    }
+
+    #[test]
+    fn merge_resyncs_directories_with_too_many_deltas() {
+        let mut pending = DirectoryChanges::default();
+        for _ in 0..MAX_PENDING_DELTAS_PER_DIRECTORY {
+            pending.push(Path::new("/a"), removed("/a/1"));
+        }
+        let mut later = DirectoryChanges::default();
+        later.push(Path::new("/a"), removed("/a/2"));
+        assert_eq!(pending.merge(later), 1);
+        assert!(pending.deltas.is_empty());
+        assert!(pending.resyncs.contains(Path::new("/a")));
+        assert!(!pending.is_empty());
     }
 }
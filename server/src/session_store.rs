@@ -0,0 +1,242 @@
+use crate::error::SPTFError;
+use async_trait::async_trait;
+use std::collections::HashMap;
+
+/// One write of a batch applied at once by `SessionStore::apply`
+pub enum StoreWrite<'a> {
+    /// Like `SessionStore::set`
+    Set {
+        key: &'a str,
+        value: &'a str,
+        expiration_in_seconds: usize,
+    },
+    /// Like `SessionStore::delete`
+    Delete { keys: &'a [String] },
+    /// Set given fields of a hash, keeping its other fields
+    SetFields {
+        key: &'a str,
+        fields: &'a [(&'a str, String)],
+        expiration_in_seconds: usize,
+    },
+    /// Add a member to a set, which never expires
+    AddMember { key: &'a str, member: &'a str },
+    /// Remove members from a set, deleting it once empty
+    RemoveMembers { key: &'a str, members: &'a [String] },
+}
+
+/// Expiring key-value storage of auth tokens and other short-lived state,
+/// like pending logins and login throttling.
+///
+/// Expirations are in seconds. Values expire as a whole, and setting a
+/// value or its fields again restarts its expiration, which is how sessions
+/// slide forward on use
+#[async_trait(?Send)]
+pub trait SessionStore: Send + Sync {
+    async fn set(
+        &self,
+        key: &str,
+        value: &str,
+        expiration_in_seconds: usize,
+    ) -> Result<(), Box<dyn SPTFError>>;
+
+    async fn get(&self, key: &str) -> Result<Option<String>, Box<dyn SPTFError>>;
+
+    /// Get and delete in one step, so that one-time tokens are used once
+    async fn take(&self, key: &str) -> Result<Option<String>, Box<dyn SPTFError>>;
+
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn SPTFError>>;
+
+    async fn delete(&self, keys: &[String]) -> Result<(), Box<dyn SPTFError>>;
+
+    /// Increment a counter starting from 0, returning its new value
+    async fn increment(
+        &self,
+        key: &str,
+        expiration_in_seconds: usize,
+    ) -> Result<usize, Box<dyn SPTFError>>;
+
+    /// All fields of a hash, empty if it does not exist
+    async fn get_fields(&self, key: &str) -> Result<HashMap<String, String>, Box<dyn SPTFError>>;
+
+    async fn members(&self, key: &str) -> Result<Vec<String>, Box<dyn SPTFError>>;
+
+    /// Apply given writes in order, all or none of them, so that values
+    /// kept under several keys stay consistent
+    async fn apply(&self, writes: &[StoreWrite<'_>]) -> Result<(), Box<dyn SPTFError>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use deadpool_redis::{Config as DeadpoolRedisConfig, Runtime as DeadpoolRedisRuntime};
+    use uuid::Uuid;
+
+    use super::{SessionStore, StoreWrite};
+    use crate::memory_store::MemorySessionStore;
+    use crate::redis_store::RedisSessionStore;
+
+    /// Behaviour every store has to share, under keys no other run uses
+    async fn check_store(store: &dyn SessionStore) {
+        let prefix = Uuid::new_v4().to_string();
+        let key = |name: &str| format!("{}:{}", prefix, name);
+
+        assert_eq!(store.get(&key("value")).await.unwrap(), None);
+        assert!(!store.exists(&key("value")).await.unwrap());
+        store.set(&key("value"), "a", 60).await.unwrap();
+        assert_eq!(
+            store.get(&key("value")).await.unwrap().as_deref(),
+            Some("a")
+        );
+        assert!(store.exists(&key("value")).await.unwrap());
+        store.set(&key("value"), "b", 60).await.unwrap();
+        assert_eq!(
+            store.get(&key("value")).await.unwrap().as_deref(),
+            Some("b")
+        );
+
+        assert_eq!(
+            store.take(&key("value")).await.unwrap().as_deref(),
+            Some("b")
+        );
+        assert_eq!(store.take(&key("value")).await.unwrap(), None);
+
+        store.set(&key("deleted"), "a", 60).await.unwrap();
+        store
+            .delete(&[key("deleted"), key("missing")])
+            .await
+            .unwrap();
+        assert!(!store.exists(&key("deleted")).await.unwrap());
+        store.delete(&[]).await.unwrap();
+
+        assert_eq!(store.increment(&key("counter"), 60).await.unwrap(), 1);
+        assert_eq!(store.increment(&key("counter"), 60).await.unwrap(), 2);
+
+        store
+            .apply(&[
+                StoreWrite::SetFields {
+                    key: &key("hash"),
+                    fields: &[("a", "1".to_owned()), ("b", "2".to_owned())],
+                    expiration_in_seconds: 60,
+                },
+                StoreWrite::SetFields {
+                    key: &key("hash"),
+                    fields: &[("b", "3".to_owned())],
+                    expiration_in_seconds: 60,
+                },
+            ])
+            .await
+            .unwrap();
+        let fields = store.get_fields(&key("hash")).await.unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields["a"], "1");
+        assert_eq!(fields["b"], "3");
+        assert!(store.get_fields(&key("missing")).await.unwrap().is_empty());
+
+        let members = ["x".to_owned(), "y".to_owned()];
+        store
+            .apply(&[
+                StoreWrite::AddMember {
+                    key: &key("set"),
+                    member: &members[0],
+                },
+                StoreWrite::AddMember {
+                    key: &key("set"),
+                    member: &members[1],
+                },
+                StoreWrite::AddMember {
+                    key: &key("set"),
+                    member: &members[1],
+                },
+            ])
+            .await
+            .unwrap();
+        let mut found = store.members(&key("set")).await.unwrap();
+        found.sort();
+        assert_eq!(found, members);
+        store
+            .apply(&[
+                StoreWrite::RemoveMembers {
+                    key: &key("set"),
+                    members: &members[..1],
+                },
+                StoreWrite::RemoveMembers {
+                    key: &key("set"),
+                    members: &[],
+                },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(store.members(&key("set")).await.unwrap(), &members[1..]);
+        assert!(store.members(&key("missing")).await.unwrap().is_empty());
+
+        store
+            .apply(&[
+                StoreWrite::Set {
+                    key: &key("session"),
+                    value: "user",
+                    expiration_in_seconds: 60,
+                },
+                StoreWrite::Delete {
+                    keys: &[key("session")],
+                },
+            ])
+            .await
+            .unwrap();
+        assert!(!store.exists(&key("session")).await.unwrap());
+
+        store.set(&key("expiring"), "a", 1).await.unwrap();
+        store.increment(&key("expiring counter"), 1).await.unwrap();
+        store
+            .apply(&[StoreWrite::SetFields {
+                key: &key("expiring hash"),
+                fields: &[("a", "1".to_owned())],
+                expiration_in_seconds: 1,
+            }])
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert_eq!(store.get(&key("expiring")).await.unwrap(), None);
+        assert!(!store.exists(&key("expiring")).await.unwrap());
+        assert_eq!(
+            store.increment(&key("expiring counter"), 1).await.unwrap(),
+            1
+        );
+        assert!(store
+            .get_fields(&key("expiring hash"))
+            .await
+            .unwrap()
+            .is_empty());
+
+        store
+            .delete(&[
+                key("counter"),
+                key("hash"),
+                key("set"),
+                key("expiring counter"),
+            ])
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn memory_store() {
+        check_store(&MemorySessionStore::new(None)).await;
+    }
+
+    /// Runs against the `redis` service of docker-compose.yml, started with
+    /// `docker compose up -d redis`, by `cargo test -- --ignored` with
+    /// `SPTF_TEST_REDIS_URL=redis://localhost:$REDIS_HOST_PORT`
+    #[ignore]
+    #[actix_web::test]
+    async fn redis_store() {
+        let url = std::env::var("SPTF_TEST_REDIS_URL").expect("SPTF_TEST_REDIS_URL is not set");
+        let pool = DeadpoolRedisConfig {
+            url: Some(url),
+            ..DeadpoolRedisConfig::default()
+        }
+        .create_pool(Some(DeadpoolRedisRuntime::Tokio1))
+        .unwrap();
+        check_store(&RedisSessionStore::new(pool)).await;
+    }
+}
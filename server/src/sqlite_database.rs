@@ -0,0 +1,265 @@
+use crate::database::{Database, DatabaseError, Row, SqlValue};
+use crate::error::SPTFError;
+use crate::migrate;
+use async_trait::async_trait;
+use log::info;
+use rusqlite::types::{ToSqlOutput, Value, ValueRef};
+use rusqlite::{Connection, ErrorCode, ToSql};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// How long a statement waits for another process holding the write lock
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Database in a single SQLite file, for single-server installs without Postgres.
+///
+/// Statements run one at a time on a blocking thread, which is fine for the
+/// handful of users such an install serves, while waiting for the write lock
+/// does not stall the async workers
+pub struct SqliteDatabase {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteDatabase {
+    /// Will panic if the file cannot be opened or created
+    pub fn new(file_path: &Path) -> Self {
+        let connection = Connection::open(file_path).expect("Unable to open SQLite database");
+        connection.busy_timeout(BUSY_TIMEOUT).unwrap();
+        connection
+            .execute_batch("PRAGMA journal_mode=WAL;")
+            .expect("Unable to enable SQLite write-ahead log");
+        // Paths are matched with LIKE, which is case-sensitive on Postgres
+        connection
+            .execute_batch("PRAGMA case_sensitive_like=ON;")
+            .expect("Unable to make SQLite LIKE case-sensitive");
+        info!("Opened SQLite database at {:?}", file_path);
+        Self {
+            connection: Arc::new(Mutex::new(connection)),
+        }
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        lock(&self.connection)
+    }
+
+    /// Run given function with the connection on a blocking thread
+    async fn run<T, F>(&self, function: F) -> Result<T, DatabaseError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            function(&mut lock(&connection)).map_err(database_error)
+        })
+        .await
+        .map_err(DatabaseError::new)?
+    }
+}
+
+fn lock(connection: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
+    connection.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            SqlValue::Null => ToSqlOutput::Owned(Value::Null),
+            SqlValue::Bool(value) => ToSqlOutput::Owned(Value::Integer((*value).into())),
+            SqlValue::Int(value) => ToSqlOutput::Owned(Value::Integer(*value)),
+            SqlValue::Text(value) => ToSqlOutput::Borrowed(ValueRef::Text(value.as_bytes())),
+            SqlValue::Bytes(value) => ToSqlOutput::Borrowed(ValueRef::Blob(value)),
+            SqlValue::Uuid(value) => ToSqlOutput::Owned(Value::Text(value.to_string())),
+            SqlValue::TextArray(value) => {
+                ToSqlOutput::Owned(Value::Text(serde_json::to_string(value).unwrap()))
+            }
+        })
+    }
+}
+
+fn database_error(err: rusqlite::Error) -> DatabaseError {
+    match err.sqlite_error() {
+        Some(sqlite_error)
+            if sqlite_error.code == ErrorCode::ConstraintViolation
+                && matches!(
+                    sqlite_error.extended_code,
+                    rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+                        | rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+                ) =>
+        {
+            DatabaseError::unique_violation(err)
+        }
+        _ => DatabaseError::new(err),
+    }
+}
+
+/// Turn `$1` placeholders into `?1`, which SQLite binds by position,
+/// leaving string literals and quoted identifiers alone
+fn sqlite_statement(statement: &str) -> String {
+    let mut sqlite_statement = String::with_capacity(statement.len());
+    let mut chars = statement.chars().peekable();
+    let mut quote = None;
+    while let Some(char) = chars.next() {
+        match (quote, char) {
+            // A doubled quote inside quotes closes and reopens them, so it
+            // needs no special case
+            (None, '\'' | '"') => quote = Some(char),
+            (Some(open_quote), _) if char == open_quote => quote = None,
+            (None, '$') if chars.peek().is_some_and(char::is_ascii_digit) => {
+                sqlite_statement.push('?');
+                continue;
+            }
+            _ => {}
+        }
+        sqlite_statement.push(char);
+    }
+    sqlite_statement
+}
+
+fn to_row(row: &rusqlite::Row) -> rusqlite::Result<Row> {
+    let column_count = row.as_ref().column_count();
+    let mut values = Vec::with_capacity(column_count);
+    for index in 0..column_count {
+        values.push(match row.get_ref(index)? {
+            ValueRef::Null => SqlValue::Null,
+            ValueRef::Integer(value) => SqlValue::Int(value),
+            ValueRef::Text(value) => SqlValue::Text(String::from_utf8_lossy(value).into_owned()),
+            ValueRef::Blob(value) => SqlValue::Bytes(value.to_vec()),
+            ValueRef::Real(_) => {
+                return Err(rusqlite::Error::InvalidColumnType(
+                    index,
+                    row.as_ref()
+                        .column_name(index)
+                        .unwrap_or_default()
+                        .to_owned(),
+                    rusqlite::types::Type::Real,
+                ))
+            }
+        });
+    }
+    Ok(Row::new(values))
+}
+
+fn execute(connection: &Connection, statement: &str, params: &[SqlValue]) -> rusqlite::Result<u64> {
+    connection
+        .prepare_cached(&sqlite_statement(statement))?
+        .execute(rusqlite::params_from_iter(params))
+        .map(|changed_row_count| changed_row_count as u64)
+}
+
+#[async_trait(?Send)]
+impl Database for SqliteDatabase {
+    /// Runs on the calling thread, as it only does so before serving
+    async fn run_migrations(&self) -> Result<(), Box<dyn SPTFError>> {
+        migrate::run_sqlite_migrations(&mut self.connection())
+    }
+
+    async fn execute(&self, statement: &str, params: &[SqlValue]) -> Result<u64, DatabaseError> {
+        let statement = statement.to_owned();
+        let params = params.to_vec();
+        self.run(move |connection| execute(connection, &statement, &params))
+            .await
+    }
+
+    async fn query(&self, statement: &str, params: &[SqlValue]) -> Result<Vec<Row>, DatabaseError> {
+        let statement = statement.to_owned();
+        let params = params.to_vec();
+        self.run(move |connection| {
+            let mut prepared_statement =
+                connection.prepare_cached(&sqlite_statement(&statement))?;
+            let rows = prepared_statement
+                .query_map(rusqlite::params_from_iter(&params), to_row)?
+                .collect();
+            rows
+        })
+        .await
+    }
+
+    async fn transaction(
+        &self,
+        statements: &[(&str, &[SqlValue])],
+    ) -> Result<Vec<u64>, DatabaseError> {
+        let statements = statements
+            .iter()
+            .map(|(statement, params)| ((*statement).to_owned(), params.to_vec()))
+            .collect::<Vec<_>>();
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            let mut changed_row_counts = Vec::with_capacity(statements.len());
+            for (statement, params) in &statements {
+                changed_row_counts.push(execute(&transaction, statement, params)?);
+            }
+            transaction.commit()?;
+            Ok(changed_row_counts)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sqlite_statement, SqliteDatabase};
+    use crate::database::{Database, SqlValue};
+    use std::path::Path;
+
+    #[test]
+    fn sqlite_statement_rewrites_placeholders() {
+        assert_eq!(
+            sqlite_statement("UPDATE Users SET quota_bytes=$2 WHERE id=$1"),
+            "UPDATE Users SET quota_bytes=?2 WHERE id=?1"
+        );
+        assert_eq!(sqlite_statement("SELECT $10, $"), "SELECT ?10, $");
+    }
+
+    #[test]
+    fn sqlite_statement_leaves_quotes_alone() {
+        assert_eq!(
+            sqlite_statement("SELECT '$1', \"$2\", $3 FROM Users"),
+            "SELECT '$1', \"$2\", ?3 FROM Users"
+        );
+        assert_eq!(
+            sqlite_statement("SELECT 'it''s $1', $2"),
+            "SELECT 'it''s $1', ?2"
+        );
+        assert_eq!(sqlite_statement("SELECT '\"$1', $2"), "SELECT '\"$1', ?2");
+    }
+
+    #[actix_web::test]
+    async fn statements_run_off_the_calling_thread() {
+        let database = SqliteDatabase::new(Path::new(":memory:"));
+        database
+            .execute("CREATE TABLE Notes (id INTEGER, body TEXT)", &[])
+            .await
+            .unwrap();
+        let changed_row_counts = database
+            .transaction(&[
+                (
+                    "INSERT INTO Notes VALUES ($1, $2)",
+                    &[SqlValue::Int(1), "$1".into()],
+                ),
+                (
+                    "INSERT INTO Notes VALUES ($1, $2)",
+                    &[SqlValue::Int(2), "b".into()],
+                ),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(changed_row_counts, [1, 1]);
+        assert!(database
+            .transaction(&[
+                ("INSERT INTO Notes VALUES ($1, 'c')", &[SqlValue::Int(3)]),
+                ("INSERT INTO Missing VALUES (1)", &[]),
+            ])
+            .await
+            .is_err());
+        let rows = database
+            .query(
+                "SELECT id FROM Notes WHERE body='$1' OR id>$1",
+                &[SqlValue::Int(1)],
+            )
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::common::{
+    ACCOUNT_LOCKOUT_THRESHOLD, IP_LOCKOUT_THRESHOLD, LOGIN_ATTEMPT_WINDOW_IN_SECONDS,
+    LOGIN_BACKOFF_BASE_IN_SECONDS, LOGIN_BACKOFF_FREE_ATTEMPTS, LOGIN_BACKOFF_MAX_IN_SECONDS,
+    LOGIN_LOCKOUT_DURATION_IN_SECONDS,
+};
+use crate::error::{SPTFError, ThrottleError};
+use crate::session_store::SessionStore;
+use log::warn;
+
+/// What a login attempt counter is keyed on
+#[derive(Clone, Copy)]
+enum AttemptScope {
+    Account,
+    Ip,
+}
+
+impl AttemptScope {
+    fn name(&self) -> &'static str {
+        match self {
+            AttemptScope::Account => "account",
+            AttemptScope::Ip => "ip",
+        }
+    }
+
+    fn lockout_threshold(&self) -> usize {
+        match self {
+            AttemptScope::Account => ACCOUNT_LOCKOUT_THRESHOLD,
+            AttemptScope::Ip => IP_LOCKOUT_THRESHOLD,
+        }
+    }
+}
+
+// Usernames are case-insensitive, so must be the counters of accounts
+fn attempts_key(scope: AttemptScope, id: &str) -> String {
+    format!("login_attempts:{}:{}", scope.name(), id.to_lowercase())
+}
+
+fn backoff_key(scope: AttemptScope, id: &str) -> String {
+    format!("login_backoff:{}:{}", scope.name(), id.to_lowercase())
+}
+
+fn lockout_key(scope: AttemptScope, id: &str) -> String {
+    format!("login_lockout:{}:{}", scope.name(), id.to_lowercase())
+}
+
+/// Seconds a client must wait after its `attempts`-th consecutive failure
+fn backoff_duration(attempts: usize) -> usize {
+    if attempts <= LOGIN_BACKOFF_FREE_ATTEMPTS {
+        return 0;
+    }
+    let exponent = (attempts - LOGIN_BACKOFF_FREE_ATTEMPTS - 1).min(16) as u32;
+    (LOGIN_BACKOFF_BASE_IN_SECONDS << exponent).min(LOGIN_BACKOFF_MAX_IN_SECONDS)
+}
+
+fn successes_key(scope: AttemptScope, id: &str) -> String {
+    format!("login_successes:{}:{}", scope.name(), id.to_lowercase())
+}
+
+/// Count a login attempt from given ip for given username, and check
+/// whether it may proceed.
+///
+/// Attempts are counted before they are decided on, so that concurrent
+/// attempts cannot all pass a check made before any of them failed. Locked
+/// accounts and ips are rejected first, then clients past their free
+/// attempts get one attempt per backoff period.
+pub async fn check_login_allowed(
+    session_store: &dyn SessionStore,
+    ip: &str,
+    username: &str,
+) -> Result<(), Box<dyn SPTFError>> {
+    let account_locked = session_store
+        .exists(&lockout_key(AttemptScope::Account, username))
+        .await?;
+    let ip_locked = session_store
+        .exists(&lockout_key(AttemptScope::Ip, ip))
+        .await?;
+    if account_locked || ip_locked {
+        warn!("Login of {} from {} rejected due to lockout", username, ip);
+        return Err(ThrottleError::Locked.to_boxed_self());
+    }
+    for (scope, id) in [(AttemptScope::Account, username), (AttemptScope::Ip, ip)] {
+        let attempts = count_attempt(session_store, scope, id).await?;
+        if attempts > scope.lockout_threshold() {
+            warn!(
+                "Lock {} {} after {} failed logins",
+                scope.name(),
+                id,
+                attempts - 1
+            );
+            session_store
+                .set(
+                    &lockout_key(scope, id),
+                    "1",
+                    LOGIN_LOCKOUT_DURATION_IN_SECONDS,
+                )
+                .await?;
+            return Err(ThrottleError::Locked.to_boxed_self());
+        }
+        let backoff = backoff_duration(attempts - 1);
+        // Only the first attempt of a backoff period gets through
+        if backoff > 0
+            && session_store
+                .increment(&backoff_key(scope, id), backoff)
+                .await?
+                > 1
+        {
+            warn!("Login of {} from {} rejected due to backoff", username, ip);
+            return Err(ThrottleError::TooManyAttempts.to_boxed_self());
+        }
+    }
+    Ok(())
+}
+
+/// Count an attempt, returning how many attempts in the window have not
+/// succeeded, this one included
+async fn count_attempt(
+    session_store: &dyn SessionStore,
+    scope: AttemptScope,
+    id: &str,
+) -> Result<usize, Box<dyn SPTFError>> {
+    let attempts = session_store
+        .increment(&attempts_key(scope, id), LOGIN_ATTEMPT_WINDOW_IN_SECONDS)
+        .await?;
+    let successes = session_store
+        .get(&successes_key(scope, id))
+        .await?
+        .and_then(|successes| successes.parse::<usize>().ok())
+        .unwrap_or(0);
+    Ok(attempts.saturating_sub(successes).max(1))
+}
+
+/// Forget failed logins of an account after it successfully logged in.
+///
+/// Ip counters are kept, otherwise one valid account would be enough to
+/// keep guessing passwords of others. The successful attempt no longer
+/// counts against the ip though.
+pub async fn reset_login_failures(
+    session_store: &dyn SessionStore,
+    ip: &str,
+    username: &str,
+) -> Result<(), Box<dyn SPTFError>> {
+    session_store
+        .delete(&[
+            attempts_key(AttemptScope::Account, username),
+            backoff_key(AttemptScope::Account, username),
+        ])
+        .await?;
+    session_store
+        .increment(
+            &successes_key(AttemptScope::Ip, ip),
+            LOGIN_ATTEMPT_WINDOW_IN_SECONDS,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Lift lockout and backoff of an account, and optionally of an ip.
+pub async fn unlock(
+    session_store: &dyn SessionStore,
+    username: &str,
+    ip: Option<&str>,
+) -> Result<(), Box<dyn SPTFError>> {
+    let mut keys = vec![
+        attempts_key(AttemptScope::Account, username),
+        backoff_key(AttemptScope::Account, username),
+        lockout_key(AttemptScope::Account, username),
+    ];
+    if let Some(ip) = ip {
+        keys.push(attempts_key(AttemptScope::Ip, ip));
+        keys.push(backoff_key(AttemptScope::Ip, ip));
+        keys.push(lockout_key(AttemptScope::Ip, ip));
+        keys.push(successes_key(AttemptScope::Ip, ip));
+    }
+    session_store.delete(&keys).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_login_allowed, reset_login_failures, unlock};
+    use crate::common::{ACCOUNT_LOCKOUT_THRESHOLD, LOGIN_BACKOFF_FREE_ATTEMPTS};
+    use crate::error::{SPTFError, ThrottleError};
+    use crate::memory_store::MemorySessionStore;
+
+    fn error_code(result: Result<(), Box<dyn SPTFError>>) -> Option<usize> {
+        result.err().map(|err| err.error_code())
+    }
+
+    #[actix_web::test]
+    async fn attempts_count_before_they_are_decided() {
+        let session_store = MemorySessionStore::new(None);
+        // Free attempts, then the first of the first backoff period
+        for _ in 0..LOGIN_BACKOFF_FREE_ATTEMPTS + 2 {
+            check_login_allowed(&session_store, "ip", "alice")
+                .await
+                .unwrap();
+        }
+        // Same backoff period as the attempt before, which has not failed yet
+        assert_eq!(
+            error_code(check_login_allowed(&session_store, "ip", "alice").await),
+            Some(ThrottleError::TooManyAttempts.error_code())
+        );
+    }
+
+    #[actix_web::test]
+    async fn accounts_lock_after_threshold() {
+        let session_store = MemorySessionStore::new(None);
+        for _ in 0..ACCOUNT_LOCKOUT_THRESHOLD {
+            let _ = check_login_allowed(&session_store, "ip", "alice").await;
+        }
+        assert_eq!(
+            error_code(check_login_allowed(&session_store, "ip", "alice").await),
+            Some(ThrottleError::Locked.error_code())
+        );
+        unlock(&session_store, "alice", Some("ip")).await.unwrap();
+        check_login_allowed(&session_store, "ip", "alice")
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn successful_logins_do_not_hold_back_ip() {
+        let session_store = MemorySessionStore::new(None);
+        for _ in 0..=LOGIN_BACKOFF_FREE_ATTEMPTS * 2 {
+            check_login_allowed(&session_store, "ip", "alice")
+                .await
+                .unwrap();
+            reset_login_failures(&session_store, "ip", "alice")
+                .await
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,447 @@
+use crate::common::{
+    PENDING_LOGIN_EXPIRATION_IN_SECONDS, RECOVERY_CODE_COUNT, RECOVERY_CODE_LENGTH,
+    TOTP_ALLOWED_SKEW_STEPS, TOTP_DIGITS, TOTP_ISSUER, TOTP_SECRET_LENGTH, TOTP_STEP_IN_SECONDS,
+};
+use crate::database::Database;
+use crate::error::{SPTFError, TotpError, UnexpectedError};
+use crate::session_store::SessionStore;
+use hmac::{Hmac, Mac};
+use log::error;
+use rand::Rng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Alphabet of recovery codes, without easily confused characters
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// Secret and provisioning uri returned when enrolling
+pub struct TotpEnrollment {
+    /// Base32 encoded secret, for manual input
+    pub secret: String,
+    /// otpauth:// uri, usually displayed as a QR code
+    pub otpauth_uri: String,
+}
+
+fn current_step() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() / TOTP_STEP_IN_SECONDS)
+        .unwrap_or(0)
+}
+
+/// HOTP value (RFC 4226) of given secret at given counter
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Check TOTP code (RFC 6238) at given step, allowing clock skew of
+/// TOTP_ALLOWED_SKEW_STEPS.
+///
+/// Return the step the code belongs to
+fn verify_code(secret: &[u8], code: &str, step: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    (step.saturating_sub(TOTP_ALLOWED_SKEW_STEPS)..=step + TOTP_ALLOWED_SKEW_STEPS)
+        .find(|counter| hotp(secret, *counter) == code)
+}
+
+/// Check a code of given user and remember its step, rejecting codes of
+/// the last accepted step or earlier so that each code is used only once
+async fn accept_code(
+    database: &dyn Database,
+    user_id: Uuid,
+    secret: &[u8],
+    code: &str,
+) -> Result<bool, Box<dyn SPTFError>> {
+    let step = match verify_code(secret, code, current_step()) {
+        Some(step) => step as i64,
+        None => return Ok(false),
+    };
+    let accepted = database
+        .execute(
+            "UPDATE TotpSecrets SET last_step=$1 WHERE user_id=$2 AND (last_step IS NULL OR last_step<$1)",
+            &[step.into(), user_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to store totp step of {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    Ok(accepted > 0)
+}
+
+fn otpauth_uri(username: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = urlencoding::encode(TOTP_ISSUER),
+        username = urlencoding::encode(username),
+        secret = secret,
+        digits = TOTP_DIGITS,
+        period = TOTP_STEP_IN_SECONDS,
+    )
+}
+
+fn hash_recovery_code(user_id: Uuid, code: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(user_id.as_bytes());
+    hasher.update(code.trim().to_lowercase());
+    hasher.finalize().to_vec()
+}
+
+fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODE_LENGTH)
+        .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Whether given user has a confirmed second factor
+pub async fn is_enabled(
+    database: &dyn Database,
+    user_id: Uuid,
+) -> Result<bool, Box<dyn SPTFError>> {
+    let row = database
+        .query_opt(
+            "SELECT confirmed FROM TotpSecrets WHERE user_id=$1",
+            &[user_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Query totp secret of {} failed: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    match row {
+        Some(row) => row.try_get(0).map_err(|err| {
+            error!("Fetch confirmed field failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        }),
+        None => Ok(false),
+    }
+}
+
+/// Generate a new, unconfirmed secret for given user.
+///
+/// Replaces any previous unconfirmed secret. Fails if TOTP is already enabled.
+pub async fn begin_enrollment(
+    database: &dyn Database,
+    user_id: Uuid,
+    username: &str,
+) -> Result<TotpEnrollment, Box<dyn SPTFError>> {
+    if is_enabled(database, user_id).await? {
+        return Err(TotpError::AlreadyEnabled.to_boxed_self());
+    }
+    let secret = rand::thread_rng().gen::<[u8; TOTP_SECRET_LENGTH]>();
+    database
+        .execute(
+            "DELETE FROM TotpSecrets WHERE user_id=$1",
+            &[user_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to remove totp secret of {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    database
+        .execute(
+            "INSERT INTO TotpSecrets (user_id, secret, confirmed) VALUES ($1, $2, false)",
+            &[user_id.into(), secret.as_slice().into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to store totp secret of {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    let secret = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret);
+    let otpauth_uri = otpauth_uri(username, &secret);
+    Ok(TotpEnrollment {
+        secret,
+        otpauth_uri,
+    })
+}
+
+async fn get_secret(
+    database: &dyn Database,
+    user_id: Uuid,
+) -> Result<(Vec<u8>, bool), Box<dyn SPTFError>> {
+    let row = database
+        .query_opt(
+            "SELECT secret, confirmed FROM TotpSecrets WHERE user_id=$1",
+            &[user_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Query totp secret of {} failed: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?
+        .ok_or_else(|| TotpError::NotEnrolled.to_boxed_self())?;
+    let secret: Vec<u8> = row.try_get(0).map_err(|err| {
+        error!("Fetch secret field failed: {}", err);
+        UnexpectedError.to_boxed_self()
+    })?;
+    let confirmed: bool = row.try_get(1).map_err(|err| {
+        error!("Fetch confirmed field failed: {}", err);
+        UnexpectedError.to_boxed_self()
+    })?;
+    Ok((secret, confirmed))
+}
+
+/// Replace recovery codes of given user with freshly generated ones.
+///
+/// Only hashes are stored, so the returned codes can never be shown again.
+async fn regenerate_recovery_codes(
+    database: &dyn Database,
+    user_id: Uuid,
+) -> Result<Vec<String>, Box<dyn SPTFError>> {
+    database
+        .execute(
+            "DELETE FROM RecoveryCodes WHERE user_id=$1",
+            &[user_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to remove recovery codes of {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    let mut codes = vec![];
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let code = generate_recovery_code();
+        database
+            .execute(
+                "INSERT INTO RecoveryCodes (user_id, code_hash) VALUES ($1, $2)",
+                &[user_id.into(), hash_recovery_code(user_id, &code).into()],
+            )
+            .await
+            .map_err(|err| {
+                error!("Failed to store recovery code of {}: {}", user_id, err);
+                UnexpectedError.to_boxed_self()
+            })?;
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+/// Confirm enrollment with a code generated by the user's authenticator.
+///
+/// Return recovery codes
+pub async fn confirm_enrollment(
+    database: &dyn Database,
+    user_id: Uuid,
+    code: &str,
+) -> Result<Vec<String>, Box<dyn SPTFError>> {
+    let (secret, confirmed) = get_secret(database, user_id).await?;
+    if confirmed {
+        return Err(TotpError::AlreadyEnabled.to_boxed_self());
+    }
+    if !accept_code(database, user_id, &secret, code).await? {
+        return Err(TotpError::InvalidCode.to_boxed_self());
+    }
+    database
+        .execute(
+            "UPDATE TotpSecrets SET confirmed=true WHERE user_id=$1",
+            &[user_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to confirm totp secret of {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    regenerate_recovery_codes(database, user_id).await
+}
+
+/// Check a TOTP code, or consume a recovery code, of given user
+async fn verify_second_factor(
+    database: &dyn Database,
+    user_id: Uuid,
+    code: &str,
+) -> Result<(), Box<dyn SPTFError>> {
+    let (secret, confirmed) = get_secret(database, user_id).await?;
+    if !confirmed {
+        return Err(TotpError::NotEnrolled.to_boxed_self());
+    }
+    if accept_code(database, user_id, &secret, code).await? {
+        return Ok(());
+    }
+    let consumed = database
+        .execute(
+            "DELETE FROM RecoveryCodes WHERE user_id=$1 AND code_hash=$2",
+            &[user_id.into(), hash_recovery_code(user_id, code).into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to consume recovery code of {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    if consumed == 0 {
+        return Err(TotpError::InvalidCode.to_boxed_self());
+    }
+    Ok(())
+}
+
+/// Turn off TOTP of given user, which requires a valid code
+pub async fn disable(
+    database: &dyn Database,
+    user_id: Uuid,
+    code: &str,
+) -> Result<(), Box<dyn SPTFError>> {
+    verify_second_factor(database, user_id, code).await?;
+    for statement in [
+        "DELETE FROM RecoveryCodes WHERE user_id=$1",
+        "DELETE FROM TotpSecrets WHERE user_id=$1",
+    ] {
+        database
+            .execute(statement, &[user_id.into()])
+            .await
+            .map_err(|err| {
+                error!("Failed to disable totp of {}: {}", user_id, err);
+                UnexpectedError.to_boxed_self()
+            })?;
+    }
+    Ok(())
+}
+
+fn pending_login_key(pending_token: &Uuid) -> String {
+    format!("pending_login:{}", pending_token)
+}
+
+/// Remember that given user passed the first factor.
+///
+/// Return a short-lived pending token to be completed with a second factor
+pub async fn add_pending_login(
+    session_store: &dyn SessionStore,
+    user_id: Uuid,
+) -> Result<Uuid, Box<dyn SPTFError>> {
+    let pending_token = Uuid::new_v4();
+    session_store
+        .set(
+            &pending_login_key(&pending_token),
+            &user_id.to_string(),
+            PENDING_LOGIN_EXPIRATION_IN_SECONDS,
+        )
+        .await?;
+    Ok(pending_token)
+}
+
+/// Find the user of given pending token
+pub async fn get_pending_login(
+    session_store: &dyn SessionStore,
+    pending_token_str: &str,
+) -> Result<(Uuid, Uuid), Box<dyn SPTFError>> {
+    let pending_token = Uuid::parse_str(pending_token_str)
+        .map_err(|_| TotpError::PendingLoginExpired.to_boxed_self())?;
+    let user_id_string = session_store
+        .get(&pending_login_key(&pending_token))
+        .await?
+        .ok_or_else(|| TotpError::PendingLoginExpired.to_boxed_self())?;
+    let user_id = Uuid::parse_str(&user_id_string).map_err(|err| {
+        error!("Parse stored user uuid {} failed: {}", user_id_string, err);
+        UnexpectedError.to_boxed_self()
+    })?;
+    Ok((pending_token, user_id))
+}
+
+/// Check second factor of a pending login, and consume the pending token on success
+pub async fn complete_pending_login(
+    database: &dyn Database,
+    session_store: &dyn SessionStore,
+    pending_token: Uuid,
+    user_id: Uuid,
+    code: &str,
+) -> Result<(), Box<dyn SPTFError>> {
+    verify_second_factor(database, user_id, code).await?;
+    session_store
+        .delete(&[pending_login_key(&pending_token)])
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hotp, verify_code};
+    use crate::common::TOTP_ALLOWED_SKEW_STEPS;
+
+    /// Secret of the test vectors in appendix D of RFC 4226
+    const RFC_4226_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc_4226_vectors() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, value) in expected.iter().enumerate() {
+            assert_eq!(hotp(RFC_4226_SECRET, counter as u64), *value, "{}", counter);
+        }
+    }
+
+    #[test]
+    fn verify_code_returns_step_within_skew() {
+        assert_eq!(verify_code(RFC_4226_SECRET, "359152", 2), Some(2));
+        assert_eq!(verify_code(RFC_4226_SECRET, " 969429 ", 2), Some(3));
+        assert_eq!(
+            verify_code(RFC_4226_SECRET, "520489", 9 - TOTP_ALLOWED_SKEW_STEPS),
+            Some(9)
+        );
+        assert_eq!(
+            verify_code(RFC_4226_SECRET, "520489", 8 - TOTP_ALLOWED_SKEW_STEPS),
+            None
+        );
+        assert_eq!(verify_code(RFC_4226_SECRET, "35915", 2), None);
+        assert_eq!(verify_code(RFC_4226_SECRET, "35915x", 2), None);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[actix_web::test]
+    async fn codes_are_accepted_once() {
+        use super::{accept_code, current_step};
+        use crate::database::Database;
+        use crate::sqlite_database::SqliteDatabase;
+        use std::path::Path;
+        use uuid::Uuid;
+
+        let database = SqliteDatabase::new(Path::new(":memory:"));
+        database.run_migrations().await.unwrap();
+        let user_id = Uuid::new_v4();
+        database
+            .execute(
+                "INSERT INTO TotpSecrets (user_id, secret, confirmed) VALUES ($1, $2, true)",
+                &[user_id.into(), RFC_4226_SECRET.into()],
+            )
+            .await
+            .unwrap();
+        let step = current_step();
+        let code = |step| format!("{:06}", hotp(RFC_4226_SECRET, step));
+        assert!(
+            accept_code(&database, user_id, RFC_4226_SECRET, &code(step))
+                .await
+                .unwrap()
+        );
+        assert!(
+            !accept_code(&database, user_id, RFC_4226_SECRET, &code(step))
+                .await
+                .unwrap()
+        );
+        assert!(
+            !accept_code(&database, user_id, RFC_4226_SECRET, &code(step - 1))
+                .await
+                .unwrap()
+        );
+        assert!(
+            accept_code(&database, user_id, RFC_4226_SECRET, &code(step + 1))
+                .await
+                .unwrap()
+        );
+    }
+}
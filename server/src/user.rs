@@ -1,47 +1,99 @@
-use crate::error::{RedisCacheError, SPTFError, SignupError, UnexpectedError, ValidateError};
-use deadpool_postgres::Client as PostgresClient;
-use deadpool_redis::Connection as RedisConnection;
-use log::error;
+use crate::auth::{AuthSource, Role};
+use crate::authenticator::Authenticator;
+use crate::config::SessionLifetime;
+use crate::database::{Database, DatabaseError, SqlValue};
+use crate::error::{
+    FileError, InviteError, RedisCacheError, SPTFError, SessionError, SignupError, UnexpectedError,
+    UserError, ValidateError,
+};
+use crate::session_store::{SessionStore, StoreWrite};
+use async_trait::async_trait;
+use log::{error, info};
+use serde::Serialize;
 use sha2::{Digest, Sha256};
-use std::future::Future;
+use std::collections::HashMap;
 use uuid::Uuid;
 
-pub async fn signup_user<
-    P1: Future<Output = Result<PostgresClient, Box<dyn SPTFError>>>,
-    P2: Future<Output = Result<PostgresClient, Box<dyn SPTFError>>>,
->(
-    postgres_client1: P1,
-    postgres_client2: P2,
+fn signup_error(username: &str, err: DatabaseError) -> Box<dyn SPTFError> {
+    if err.is_unique_violation() {
+        return SignupError::UsernameExist.to_boxed_self();
+    }
+    error!("Failed to create user {}: {}", username, err);
+    UnexpectedError.to_boxed_self()
+}
+
+/// Create a user, returning its id.
+///
+/// Uniqueness of the username is left to the database, so that concurrent
+/// signups cannot both succeed. It ignores case, though on SQLite only the
+/// case of ASCII letters, so there "Émile" and "émile" are two users
+pub async fn signup_user(
+    database: &dyn Database,
     username: &str,
     password: &str,
-) -> Result<(), Box<dyn SPTFError>> {
-    let rows = postgres_client1
-        .await?
-        .query("SELECT id FROM Users WHERE username=$1", &[&username])
-        .await
-        .map_err(|err| {
-            error!("Query username {} failed: {}", username, err);
-            UnexpectedError.to_boxed_self()
-        })?;
-    if !rows.is_empty() {
-        return Err(SignupError::UsernameExist.to_boxed_self());
-    }
+    role: Role,
+    quota_bytes: Option<i64>,
+) -> Result<Uuid, Box<dyn SPTFError>> {
     let uuid = Uuid::new_v4();
     let salt = Uuid::new_v4();
     let salt_bytes = salt.as_bytes();
     let hashed_password = generate_password(&password, &salt_bytes.as_slice());
-    postgres_client2
-        .await?
+    database
         .execute(
-            "INSERT INTO Users (id, username, salt, password) VALUES ($1, $2, $3, $4)",
-            &[&uuid, &username, &salt_bytes.as_slice(), &hashed_password],
+            "INSERT INTO Users (id, username, salt, password, role, quota_bytes) VALUES ($1, $2, $3, $4, $5, $6)",
+            &[
+                uuid.into(),
+                username.into(),
+                salt_bytes.as_slice().into(),
+                hashed_password.into(),
+                role.name().into(),
+                quota_bytes.into(),
+            ],
         )
         .await
-        .map_err(|err| {
-            error!("Failed to create user {}: {}", username, err);
-            UnexpectedError.to_boxed_self()
-        })?;
-    Ok(())
+        .map_err(|err| signup_error(username, err))?;
+    Ok(uuid)
+}
+
+/// Create a user with the role and quota of given invite code, returning its id.
+///
+/// The invite is used up and the user created in one transaction, so that
+/// neither happens without the other. The user is only created if the use
+/// of the invite was its own, which the invite records
+pub async fn signup_invited_user(
+    database: &dyn Database,
+    username: &str,
+    password: &str,
+    invite_code: &str,
+) -> Result<Uuid, Box<dyn SPTFError>> {
+    let uuid = Uuid::new_v4();
+    let salt = Uuid::new_v4();
+    let salt_bytes = salt.as_bytes();
+    let hashed_password = generate_password(&password, &salt_bytes.as_slice());
+    let code_hash = crate::invite::hash_code(invite_code);
+    let changed_row_counts = database
+        .transaction(&[
+            (
+                "UPDATE InviteCodes SET uses=uses+1, last_used_by=$1 WHERE code_hash=$2 AND (max_uses IS NULL OR uses<max_uses)",
+                &[uuid.into(), code_hash.as_slice().into()],
+            ),
+            (
+                "INSERT INTO Users (id, username, salt, password, role, quota_bytes) SELECT $1, $2, $3, $4, COALESCE(role, 'member'), quota_bytes FROM InviteCodes WHERE code_hash=$5 AND last_used_by=$1",
+                &[
+                    uuid.into(),
+                    username.into(),
+                    salt_bytes.as_slice().into(),
+                    hashed_password.into(),
+                    code_hash.as_slice().into(),
+                ],
+            ),
+        ])
+        .await
+        .map_err(|err| signup_error(username, err))?;
+    if changed_row_counts[1] == 0 {
+        return Err(InviteError::Invalid.to_boxed_self());
+    }
+    Ok(uuid)
 }
 
 fn generate_password(password: &str, salt: &[u8]) -> Vec<u8> {
@@ -51,143 +103,996 @@ fn generate_password(password: &str, salt: &[u8]) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
-/// Validate use given the username and password.
+/// Result of a successful password validation
+pub enum LoginOutcome {
+    /// Random-generated auth token
+    Authenticated(Uuid),
+    /// User has a second factor, complete it with this pending token
+    SecondFactorRequired(Uuid),
+}
+
+/// Authenticate with the password hash stored in Users
+pub struct LocalAuthenticator;
+
+#[async_trait(?Send)]
+impl Authenticator for LocalAuthenticator {
+    async fn authenticate(
+        &self,
+        database: &dyn Database,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Uuid>, Box<dyn SPTFError>> {
+        let rows = database
+            .query(
+                "SELECT id, salt, password FROM Users WHERE lower(username)=lower($1) AND auth_source=$2",
+                &[username.into(), AuthSource::Local.name().into()],
+            )
+            .await
+            .map_err(|err| {
+                error!("Query username {} failed: {}", username, err);
+                UnexpectedError.to_boxed_self()
+            })?;
+        let row = match &rows[..] {
+            [] => {
+                // Still hash the password so that response time does not tell
+                // whether the username exists
+                let _ = generate_password(password, Uuid::nil().as_bytes());
+                return Ok(None);
+            }
+            [row] => row,
+            _ => {
+                error!("Query username {} returns multiple rows.", username);
+                return Err(UnexpectedError.to_boxed_self());
+            }
+        };
+        let id: Uuid = row.try_get(0).map_err(|err| {
+            error!("Fetch id field failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+        let salt: Vec<u8> = row.try_get(1).map_err(|err| {
+            error!("Fetch salt field failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+        let hashed_password: Vec<u8> = row.try_get(2).map_err(|err| {
+            error!("Fetch password field failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        })?;
+        Ok(validate_password(password, &salt, &hashed_password).then_some(id))
+    }
+}
+
+/// Validate use given the username and password, asking each authenticator in turn.
 ///
-/// Return a random-generated UUID as auth-token
-pub async fn validate_user<
-    P: Future<Output = Result<PostgresClient, Box<dyn SPTFError>>>,
-    R: Future<Output = Result<RedisConnection, Box<dyn SPTFError>>>,
->(
-    postgres_client: P,
-    redis_connection: R,
+/// Return a random-generated UUID as auth-token, or a pending token if the
+/// user has TOTP enabled
+pub async fn validate_user(
+    database: &dyn Database,
+    session_store: &dyn SessionStore,
+    authenticators: &[Box<dyn Authenticator>],
     username: &str,
     password: &str,
+    client_info: &ClientInfo,
+    lifetime: SessionLifetime,
+) -> Result<LoginOutcome, Box<dyn SPTFError>> {
+    let mut authenticated_id = None;
+    for authenticator in authenticators {
+        authenticated_id = authenticator
+            .authenticate(database, username, password)
+            .await?;
+        if authenticated_id.is_some() {
+            break;
+        }
+    }
+    let id = authenticated_id.ok_or_else(|| ValidateError::InvalidCredentials.to_boxed_self())?;
+
+    let disabled: bool = database
+        .query_one("SELECT disabled FROM Users WHERE id=$1", &[id.into()])
+        .await
+        .and_then(|row| row.try_get(0))
+        .map_err(|err| {
+            error!("Query disabled of user {} failed: {}", id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    // Only tell after the password matched, so it does not leak account status
+    if disabled {
+        return Err(ValidateError::UserDisabled.to_boxed_self());
+    }
+
+    if crate::totp::is_enabled(database, id).await? {
+        let pending_token = crate::totp::add_pending_login(session_store, id).await?;
+        return Ok(LoginOutcome::SecondFactorRequired(pending_token));
+    }
+
+    let auth_token = add_user_cache(session_store, id, client_info, lifetime).await?;
+
+    Ok(LoginOutcome::Authenticated(auth_token))
+}
+
+/// Create a user whose password is checked elsewhere.
+///
+/// The stored password is random, so it cannot be used to log in
+pub async fn add_shadow_user(
+    database: &dyn Database,
+    username: &str,
+    role: Role,
+    auth_source: AuthSource,
 ) -> Result<Uuid, Box<dyn SPTFError>> {
-    let rows = postgres_client
-        .await?
+    let uuid = Uuid::new_v4();
+    let salt = Uuid::new_v4();
+    let hashed_password = generate_password(&Uuid::new_v4().to_string(), salt.as_bytes());
+    database
+        .execute(
+            "INSERT INTO Users (id, username, salt, password, role, auth_source) VALUES ($1, $2, $3, $4, $5, $6)",
+            &[
+                uuid.into(),
+                username.into(),
+                salt.as_bytes().as_slice().into(),
+                hashed_password.into(),
+                role.name().into(),
+                auth_source.name().into(),
+            ],
+        )
+        .await
+        .map_err(|err| {
+            if err.is_unique_violation() {
+                return SignupError::UsernameExist.to_boxed_self();
+            }
+            error!("Failed to create shadow user {}: {}", username, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    Ok(uuid)
+}
+
+/// Find or create shadow user of given source, and give it the role the source decided.
+///
+/// Fails with UsernameExist if a user of another source has the name
+pub async fn sync_shadow_user(
+    database: &dyn Database,
+    username: &str,
+    role: Role,
+    auth_source: AuthSource,
+) -> Result<Uuid, Box<dyn SPTFError>> {
+    let row = database
+        .query_opt(
+            "UPDATE Users SET role=$1 WHERE lower(username)=lower($2) AND auth_source=$3 RETURNING id",
+            &[role.name().into(), username.into(), auth_source.name().into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to update shadow user {}: {}", username, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    match row {
+        Some(row) => row.try_get(0).map_err(|err| {
+            error!("Fetch id field failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        }),
+        None => add_shadow_user(database, username, role, auth_source).await,
+    }
+}
+
+/// User as shown to admins
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserInfo {
+    pub id: String,
+    pub username: String,
+    pub role: String,
+    pub disabled: bool,
+    /// Unlimited if None
+    pub quota_bytes: Option<i64>,
+    pub used_bytes: i64,
+}
+
+fn parse_role(role: &str) -> Result<Role, Box<dyn SPTFError>> {
+    Role::from_name(role).ok_or_else(|| {
+        error!("Unknown role {} stored", role);
+        UnexpectedError.to_boxed_self()
+    })
+}
+
+/// Get role of given user, which must exist and not be disabled
+pub async fn get_role(database: &dyn Database, user_id: Uuid) -> Result<Role, Box<dyn SPTFError>> {
+    let row = database
+        .query_opt(
+            "SELECT role, disabled FROM Users WHERE id=$1",
+            &[user_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Query user id {} failed: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?
+        .ok_or_else(|| UserError::NotFound.to_boxed_self())?;
+    let role: String = row.try_get(0).map_err(|err| {
+        error!("Fetch role field failed: {}", err);
+        UnexpectedError.to_boxed_self()
+    })?;
+    let disabled: bool = row.try_get(1).map_err(|err| {
+        error!("Fetch disabled field failed: {}", err);
+        UnexpectedError.to_boxed_self()
+    })?;
+    if disabled {
+        return Err(ValidateError::UserDisabled.to_boxed_self());
+    }
+    parse_role(&role)
+}
+
+/// List all users
+pub async fn list_users(database: &dyn Database) -> Result<Vec<UserInfo>, Box<dyn SPTFError>> {
+    let rows = database
         .query(
-            "SELECT id, salt, password FROM Users WHERE username=$1",
-            &[&username],
+            "SELECT id, username, role, disabled, quota_bytes, used_bytes FROM Users ORDER BY username",
+            &[],
         )
         .await
         .map_err(|err| {
-            error!("Query username {} failed: {}", username, err);
+            error!("Query users failed: {}", err);
             UnexpectedError.to_boxed_self()
         })?;
-    let row = match &rows[..] {
-        [] => {
-            return Err(ValidateError::NoUsername.to_boxed_self());
-        }
-        [row] => row,
-        _ => {
-            error!("Query username {} returns multiple rows.", username);
-            return Err(UnexpectedError.to_boxed_self());
-        }
-    };
-    let id: Uuid = row.try_get(0).map_err(|err| {
-        error!("Fetch id field failed: {}", err);
+    rows.iter()
+        .map(|row| {
+            let id: Uuid = row.try_get(0)?;
+            Ok(UserInfo {
+                id: id.to_string(),
+                username: row.try_get(1)?,
+                role: row.try_get(2)?,
+                disabled: row.try_get(3)?,
+                quota_bytes: row.try_get(4)?,
+                used_bytes: row.try_get(5)?,
+            })
+        })
+        .collect::<Result<Vec<_>, DatabaseError>>()
+        .map_err(|err| {
+            error!("Fetch user fields failed: {}", err);
+            UnexpectedError.to_boxed_self()
+        })
+}
+
+/// Run an update statement on the row of given user
+async fn update_user(
+    database: &dyn Database,
+    user_id: Uuid,
+    statement: &str,
+    value: SqlValue,
+) -> Result<(), Box<dyn SPTFError>> {
+    let updated = database
+        .execute(statement, &[value, user_id.into()])
+        .await
+        .map_err(|err| {
+            error!("Failed to update user {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    if updated == 0 {
+        return Err(UserError::NotFound.to_boxed_self());
+    }
+    Ok(())
+}
+
+/// Disable or enable given user
+pub async fn set_user_disabled(
+    database: &dyn Database,
+    user_id: Uuid,
+    disabled: bool,
+) -> Result<(), Box<dyn SPTFError>> {
+    update_user(
+        database,
+        user_id,
+        "UPDATE Users SET disabled=$1 WHERE id=$2",
+        disabled.into(),
+    )
+    .await
+}
+
+/// Change role of given user
+pub async fn set_user_role(
+    database: &dyn Database,
+    user_id: Uuid,
+    role: Role,
+) -> Result<(), Box<dyn SPTFError>> {
+    update_user(
+        database,
+        user_id,
+        "UPDATE Users SET role=$1 WHERE id=$2",
+        role.name().into(),
+    )
+    .await
+}
+
+/// Change upload quota of given user, None for unlimited
+pub async fn set_user_quota(
+    database: &dyn Database,
+    user_id: Uuid,
+    quota_bytes: Option<i64>,
+) -> Result<(), Box<dyn SPTFError>> {
+    update_user(
+        database,
+        user_id,
+        "UPDATE Users SET quota_bytes=$1 WHERE id=$2",
+        quota_bytes.into(),
+    )
+    .await
+}
+
+/// Count given bytes against quota of given user before uploading them.
+///
+/// Fails without counting if they do not fit in the quota
+pub async fn reserve_quota(
+    database: &dyn Database,
+    user_id: Uuid,
+    bytes: i64,
+) -> Result<(), Box<dyn SPTFError>> {
+    let reserved = database
+        .execute(
+            "UPDATE Users SET used_bytes=used_bytes+$1 WHERE id=$2 AND (quota_bytes IS NULL OR used_bytes+$1<=quota_bytes)",
+            &[bytes.into(), user_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to reserve quota of user {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    if reserved == 0 {
+        return Err(FileError::QuotaExceeded.to_boxed_self());
+    }
+    Ok(())
+}
+
+/// Give back the bytes in $1 to the user with id $2
+pub const RELEASE_QUOTA_STATEMENT: &str =
+    "UPDATE Users SET used_bytes=CASE WHEN used_bytes>$1 THEN used_bytes-$1 ELSE 0 END WHERE id=$2";
+
+/// Give back bytes reserved by an upload that failed
+pub async fn release_quota(
+    database: &dyn Database,
+    user_id: Uuid,
+    bytes: i64,
+) -> Result<(), Box<dyn SPTFError>> {
+    database
+        .execute(RELEASE_QUOTA_STATEMENT, &[bytes.into(), user_id.into()])
+        .await
+        .map_err(|err| {
+            error!("Failed to release quota of user {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    Ok(())
+}
+
+/// Delete given user along with everything belonging to it
+pub async fn delete_user(database: &dyn Database, user_id: Uuid) -> Result<(), Box<dyn SPTFError>> {
+    let params = [user_id.into()];
+    let deleted = database
+        .transaction(&[
+            ("DELETE FROM RecoveryCodes WHERE user_id=$1", &params),
+            ("DELETE FROM TotpSecrets WHERE user_id=$1", &params),
+            ("DELETE FROM ApiTokens WHERE user_id=$1", &params),
+            ("DELETE FROM OidcIdentities WHERE user_id=$1", &params),
+            // Files stay, owned by no one
+            ("DELETE FROM FileOwners WHERE user_id=$1", &params),
+            ("DELETE FROM Users WHERE id=$1", &params),
+        ])
+        .await
+        .map_err(|err| {
+            error!("Failed to delete user {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    // Nothing else belongs to a user that does not exist
+    if deleted.last() == Some(&0) {
+        return Err(UserError::NotFound.to_boxed_self());
+    }
+    Ok(())
+}
+
+/// Promote given usernames to admin, so that a fresh install has someone to manage users
+pub async fn promote_admins(
+    database: &dyn Database,
+    usernames: &[String],
+) -> Result<(), Box<dyn SPTFError>> {
+    if usernames.is_empty() {
+        return Ok(());
+    }
+    let params = usernames
+        .iter()
+        .map(|username| {
+            [
+                Role::Admin.name().into(),
+                crate::policy::normalize_username(username).into(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let statements = params
+        .iter()
+        .map(|params| {
+            (
+                "UPDATE Users SET role=$1 WHERE lower(username)=lower($2)",
+                params.as_slice(),
+            )
+        })
+        .collect::<Vec<_>>();
+    database.transaction(&statements).await.map_err(|err| {
+        error!("Failed to promote admins: {}", err);
         UnexpectedError.to_boxed_self()
     })?;
-    let salt: &[u8] = row.try_get(1).map_err(|err| {
+    Ok(())
+}
+
+/// Get username of given user id
+pub async fn get_username(
+    database: &dyn Database,
+    user_id: Uuid,
+) -> Result<String, Box<dyn SPTFError>> {
+    let row = database
+        .query_opt("SELECT username FROM Users WHERE id=$1", &[user_id.into()])
+        .await
+        .map_err(|err| {
+            error!("Query user id {} failed: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?
+        .ok_or_else(|| {
+            error!("User id {} does not exist", user_id);
+            UnexpectedError.to_boxed_self()
+        })?;
+    row.try_get(0).map_err(|err| {
+        error!("Fetch username field failed: {}", err);
+        UnexpectedError.to_boxed_self()
+    })
+}
+
+fn validate_password(password: &str, salt: &[u8], hashed_password: &[u8]) -> bool {
+    &generate_password(password, salt)[..] == hashed_password
+}
+
+/// Store a new password of given user, with a fresh salt
+async fn set_password(
+    database: &dyn Database,
+    user_id: Uuid,
+    password: &str,
+) -> Result<(), Box<dyn SPTFError>> {
+    let salt = Uuid::new_v4();
+    let salt_bytes = salt.as_bytes();
+    let hashed_password = generate_password(password, salt_bytes.as_slice());
+    let updated = database
+        .execute(
+            "UPDATE Users SET salt=$1, password=$2 WHERE id=$3",
+            &[
+                salt_bytes.as_slice().into(),
+                hashed_password.into(),
+                user_id.into(),
+            ],
+        )
+        .await
+        .map_err(|err| {
+            error!("Failed to update password of {}: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?;
+    if updated == 0 {
+        return Err(UserError::NotFound.to_boxed_self());
+    }
+    Ok(())
+}
+
+/// Change password of given user, which requires the current password
+pub async fn change_password(
+    database: &dyn Database,
+    user_id: Uuid,
+    current_password: &str,
+    new_password: &str,
+) -> Result<(), Box<dyn SPTFError>> {
+    let row = database
+        .query_opt(
+            "SELECT salt, password FROM Users WHERE id=$1",
+            &[user_id.into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Query user id {} failed: {}", user_id, err);
+            UnexpectedError.to_boxed_self()
+        })?
+        .ok_or_else(|| UserError::NotFound.to_boxed_self())?;
+    let salt: Vec<u8> = row.try_get(0).map_err(|err| {
         error!("Fetch salt field failed: {}", err);
         UnexpectedError.to_boxed_self()
     })?;
-    let hashed_password: &[u8] = row.try_get(2).map_err(|err| {
+    let hashed_password: Vec<u8> = row.try_get(1).map_err(|err| {
         error!("Fetch password field failed: {}", err);
         UnexpectedError.to_boxed_self()
     })?;
-
-    if !validate_password(password, salt, hashed_password) {
-        return Err(ValidateError::UnmatchedPassword.to_boxed_self());
+    if !validate_password(current_password, &salt, &hashed_password) {
+        return Err(ValidateError::InvalidCredentials.to_boxed_self());
     }
+    set_password(database, user_id, new_password).await
+}
 
-    let auth_token = add_user_cache(redis_connection, id).await?;
+/// Get user id of given username
+pub async fn get_user_id(
+    database: &dyn Database,
+    username: &str,
+) -> Result<Uuid, Box<dyn SPTFError>> {
+    let username = crate::policy::normalize_username(username);
+    let row = database
+        .query_opt(
+            "SELECT id FROM Users WHERE lower(username)=lower($1)",
+            &[username.as_str().into()],
+        )
+        .await
+        .map_err(|err| {
+            error!("Query username {} failed: {}", username, err);
+            UnexpectedError.to_boxed_self()
+        })?
+        .ok_or_else(|| UserError::NotFound.to_boxed_self())?;
+    row.try_get(0).map_err(|err| {
+        error!("Fetch id field failed: {}", err);
+        UnexpectedError.to_boxed_self()
+    })
+}
 
-    Ok(auth_token)
+fn password_reset_key(reset_token: &Uuid) -> String {
+    format!("password_reset:{}", reset_token)
 }
 
-fn validate_password(password: &str, salt: &[u8], hashed_password: &[u8]) -> bool {
-    &generate_password(password, salt)[..] == hashed_password
+/// Issue a one-time token which allows resetting password of given user
+pub async fn add_password_reset(
+    session_store: &dyn SessionStore,
+    user_id: Uuid,
+) -> Result<Uuid, Box<dyn SPTFError>> {
+    let reset_token = Uuid::new_v4();
+    session_store
+        .set(
+            &password_reset_key(&reset_token),
+            &user_id.to_string(),
+            crate::common::PASSWORD_RESET_EXPIRATION_IN_SECONDS,
+        )
+        .await?;
+    Ok(reset_token)
+}
+
+/// Reset password with a one-time reset token, consuming it.
+///
+/// Return id of the user whose password is reset
+pub async fn reset_password(
+    database: &dyn Database,
+    session_store: &dyn SessionStore,
+    reset_token_str: &str,
+    new_password: &str,
+) -> Result<Uuid, Box<dyn SPTFError>> {
+    let reset_token = Uuid::parse_str(reset_token_str)
+        .map_err(|_| UserError::InvalidResetToken.to_boxed_self())?;
+    let user_id_string = session_store
+        .take(&password_reset_key(&reset_token))
+        .await?
+        .ok_or_else(|| UserError::InvalidResetToken.to_boxed_self())?;
+    let user_id = Uuid::parse_str(&user_id_string).map_err(|err| {
+        error!("Parse stored user uuid {} failed: {}", user_id_string, err);
+        UnexpectedError.to_boxed_self()
+    })?;
+    set_password(database, user_id, new_password).await?;
+    Ok(user_id)
+}
+
+/// Where a login comes from, recorded in its session metadata
+pub struct ClientInfo {
+    pub ip: String,
+    pub user_agent: String,
+}
+
+/// Login session as shown to its user
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    /// Identifies the session without exposing its auth token
+    pub id: String,
+    pub created_at: i64,
+    pub last_seen_at: i64,
+    /// When the session ends however much it is used
+    pub expires_at: i64,
+    pub ip: String,
+    pub user_agent: String,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
+
+/// Set of auth tokens belonging to given user
+fn user_sessions_key(user_id: &Uuid) -> String {
+    format!("user_sessions:{}", user_id)
 }
 
-/// Return randomly generated auth token
-async fn add_user_cache<R: Future<Output = Result<RedisConnection, Box<dyn SPTFError>>>>(
-    connection: R,
+/// Hash of metadata of given auth token
+fn session_metadata_key(auth_token: &str) -> String {
+    format!("session_metadata:{}", auth_token)
+}
+
+/// Return randomly generated auth token, valid for given lifetime
+pub async fn add_user_cache(
+    session_store: &dyn SessionStore,
     user_uuid: Uuid,
+    client_info: &ClientInfo,
+    lifetime: SessionLifetime,
 ) -> Result<Uuid, Box<dyn SPTFError>> {
     let auth_token = Uuid::new_v4();
-    update_user_cache(connection, user_uuid, auth_token).await?;
+    let now = crate::common::unix_timestamp();
+    let expiration_in_seconds = lifetime
+        .idle_timeout_in_seconds
+        .min(lifetime.max_lifetime_in_seconds);
+    let auth_token_string = auth_token.to_string();
+    let metadata = [
+        ("id", Uuid::new_v4().to_string()),
+        ("created_at", now.to_string()),
+        ("last_seen_at", now.to_string()),
+        ("ip", client_info.ip.clone()),
+        ("user_agent", client_info.user_agent.clone()),
+        ("idle_timeout", lifetime.idle_timeout_in_seconds.to_string()),
+        (
+            "expires_at",
+            (now + lifetime.max_lifetime_in_seconds as i64).to_string(),
+        ),
+    ];
+    // At once, so that no token is left out of the index revoking sessions
+    session_store
+        .apply(&[
+            StoreWrite::Set {
+                key: &auth_token_string,
+                value: &user_uuid.to_string(),
+                expiration_in_seconds,
+            },
+            StoreWrite::SetFields {
+                key: &session_metadata_key(&auth_token_string),
+                fields: &metadata,
+                expiration_in_seconds,
+            },
+            StoreWrite::AddMember {
+                key: &user_sessions_key(&user_uuid),
+                member: &auth_token_string,
+            },
+        ])
+        .await
+        .map_err(|_| {
+            error!(
+                "Add user uuid {} with auth token {} failed",
+                user_uuid, auth_token
+            );
+            RedisCacheError::UpdateAuthTokenFailed.to_boxed_self()
+        })?;
     Ok(auth_token)
 }
 
-async fn update_user_cache<R: Future<Output = Result<RedisConnection, Box<dyn SPTFError>>>>(
-    connection: R,
+/// Slide expiration of given auth token forward by its idle timeout, but
+/// never past its absolute expiration
+async fn update_user_cache(
+    session_store: &dyn SessionStore,
     user_uuid: Uuid,
     auth_token: Uuid,
+    idle_timeout_in_seconds: usize,
+    expires_at: i64,
 ) -> Result<(), Box<dyn SPTFError>> {
-    redis::Cmd::set_ex(
-        auth_token.to_string(),
-        user_uuid.to_string(),
-        crate::common::REDIS_CACHE_EXPIRATION_IN_SECONDS,
-    )
-    .query_async::<_, ()>(&mut connection.await?)
-    .await
-    .map_err(|err| {
-        error!(
-            "Update user uuid {} with auth token {} failed: {}",
-            user_uuid.to_string(),
-            auth_token.to_string(),
-            err
-        );
-        RedisCacheError::UpdateAuthTokenFailed.to_boxed_self()
-    })?;
-    Ok(())
+    let now = crate::common::unix_timestamp();
+    let expiration_in_seconds = idle_timeout_in_seconds.min((expires_at - now).max(0) as usize);
+    let auth_token_string = auth_token.to_string();
+    session_store
+        .apply(&[
+            StoreWrite::Set {
+                key: &auth_token_string,
+                value: &user_uuid.to_string(),
+                expiration_in_seconds,
+            },
+            StoreWrite::SetFields {
+                key: &session_metadata_key(&auth_token_string),
+                fields: &[("last_seen_at", now.to_string())],
+                expiration_in_seconds,
+            },
+        ])
+        .await
+        .map_err(|_| {
+            error!(
+                "Update user uuid {} with auth token {} failed",
+                user_uuid, auth_token
+            );
+            RedisCacheError::UpdateAuthTokenFailed.to_boxed_self()
+        })
+}
+
+/// Whether validating an auth token counts as use of its session
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SessionUse {
+    /// A request of the client, sliding the idle timeout forward
+    Touch,
+    /// A check the client did not ask for, leaving the session to expire
+    /// once idle
+    Peek,
 }
 
 /// Validate user given auth token
 ///
 /// Return user-id
-pub async fn validate_auth_token<
-    R1: Future<Output = Result<RedisConnection, Box<dyn SPTFError>>>,
-    R2: Future<Output = Result<RedisConnection, Box<dyn SPTFError>>>,
->(
-    connection1: R1,
-    connection2: R2,
+pub async fn validate_auth_token(
+    session_store: &dyn SessionStore,
     auth_token_str: &str,
+    session_use: SessionUse,
 ) -> Result<Uuid, Box<dyn SPTFError>> {
     let auth_token = Uuid::parse_str(&auth_token_str).map_err(|err| {
         error!("Parse auth token {} failed: {}", auth_token_str, err);
         RedisCacheError::ValidateAuthTokenFailed.to_boxed_self()
     })?;
-    let user_id_string = redis::Cmd::get(auth_token.to_string())
-        .query_async::<_, String>(&mut connection1.await?)
+    let user_id_string = session_store
+        .get(&auth_token.to_string())
         .await
-        .map_err(|err| {
-            error!(
-                "Get user uuid of auth token {} failed: {}",
-                auth_token_str, err
-            );
+        .ok()
+        .flatten()
+        .ok_or_else(|| {
+            error!("Get user uuid of auth token {} failed", auth_token_str);
             RedisCacheError::ValidateAuthTokenFailed.to_boxed_self()
         })?;
     let user_id = Uuid::parse_str(&user_id_string).map_err(|err| {
         error!("Parse stored user uuid {} failed: {}", user_id_string, err);
         RedisCacheError::ValidateAuthTokenFailed.to_boxed_self()
     })?;
-    update_user_cache(connection2, user_id, auth_token).await?;
+    // Sessions created before lifetimes were stored get the default ones
+    let metadata = session_store
+        .get_fields(&session_metadata_key(&auth_token.to_string()))
+        .await?;
+    let field = |name: &str| {
+        metadata
+            .get(name)
+            .and_then(|value| value.parse::<i64>().ok())
+    };
+    let idle_timeout_in_seconds = field("idle_timeout").map_or(
+        crate::common::SESSION_IDLE_TIMEOUT_IN_SECONDS,
+        |idle_timeout| idle_timeout as usize,
+    );
+    let expires_at = field("expires_at").unwrap_or_else(|| {
+        field("created_at").unwrap_or_default()
+            + crate::common::SESSION_MAX_LIFETIME_IN_SECONDS as i64
+    });
+    if expires_at <= crate::common::unix_timestamp() {
+        info!(
+            "Auth token of user {} reached its maximum lifetime",
+            user_id
+        );
+        remove_auth_tokens(session_store, user_id, &[auth_token.to_string()]).await?;
+        return Err(RedisCacheError::ValidateAuthTokenFailed.to_boxed_self());
+    }
+    if session_use == SessionUse::Touch {
+        update_user_cache(
+            session_store,
+            user_id,
+            auth_token,
+            idle_timeout_in_seconds,
+            expires_at,
+        )
+        .await?;
+    }
 
     Ok(user_id)
 }
 
-pub async fn logout<R: Future<Output = Result<RedisConnection, Box<dyn SPTFError>>>>(
-    connection: R,
+/// Remove given auth tokens of given user
+async fn remove_auth_tokens(
+    session_store: &dyn SessionStore,
+    user_id: Uuid,
+    auth_tokens: &[String],
+) -> Result<(), Box<dyn SPTFError>> {
+    if auth_tokens.is_empty() {
+        return Ok(());
+    }
+    let keys = auth_tokens
+        .iter()
+        .flat_map(|auth_token| [auth_token.clone(), session_metadata_key(auth_token)])
+        .collect::<Vec<_>>();
+    session_store
+        .apply(&[
+            StoreWrite::Delete { keys: &keys },
+            StoreWrite::RemoveMembers {
+                key: &user_sessions_key(&user_id),
+                members: auth_tokens,
+            },
+        ])
+        .await
+}
+
+pub async fn logout(
+    session_store: &dyn SessionStore,
+    user_id: Uuid,
     auth_token_str: &str,
 ) -> Result<(), Box<dyn SPTFError>> {
-    redis::Cmd::del(auth_token_str)
-        .query_async::<_, ()>(&mut connection.await?)
+    remove_auth_tokens(session_store, user_id, &[auth_token_str.to_owned()]).await
+}
+
+/// Auth tokens of given user, along with their metadata.
+///
+/// Tokens which already expired are pruned from the index on the way
+async fn get_user_sessions(
+    session_store: &dyn SessionStore,
+    user_id: Uuid,
+) -> Result<Vec<(String, HashMap<String, String>)>, Box<dyn SPTFError>> {
+    let auth_tokens = session_store.members(&user_sessions_key(&user_id)).await?;
+    let mut sessions = vec![];
+    let mut expired_auth_tokens = vec![];
+    for auth_token in auth_tokens {
+        let metadata = session_store
+            .get_fields(&session_metadata_key(&auth_token))
+            .await?;
+        if metadata.is_empty() {
+            expired_auth_tokens.push(auth_token);
+        } else {
+            sessions.push((auth_token, metadata));
+        }
+    }
+    remove_auth_tokens(session_store, user_id, &expired_auth_tokens).await?;
+    Ok(sessions)
+}
+
+/// List login sessions of given user
+pub async fn list_user_sessions(
+    session_store: &dyn SessionStore,
+    user_id: Uuid,
+    current_auth_token: &str,
+) -> Result<Vec<SessionInfo>, Box<dyn SPTFError>> {
+    let sessions = get_user_sessions(session_store, user_id).await?;
+    Ok(sessions
+        .into_iter()
+        .map(|(auth_token, mut metadata)| {
+            let mut take = |field: &str| metadata.remove(field).unwrap_or_default();
+            SessionInfo {
+                id: take("id"),
+                created_at: take("created_at").parse().unwrap_or_default(),
+                last_seen_at: take("last_seen_at").parse().unwrap_or_default(),
+                expires_at: take("expires_at").parse().unwrap_or_default(),
+                ip: take("ip"),
+                user_agent: take("user_agent"),
+                current: auth_token == current_auth_token,
+            }
+        })
+        .collect())
+}
+
+/// Revoke the session of given user with given session id.
+///
+/// Return revoked auth token
+pub async fn revoke_user_session(
+    session_store: &dyn SessionStore,
+    user_id: Uuid,
+    session_id: &str,
+) -> Result<String, Box<dyn SPTFError>> {
+    let auth_token = get_user_sessions(session_store, user_id)
+        .await?
+        .into_iter()
+        .find(|(_, metadata)| metadata.get("id").map(String::as_str) == Some(session_id))
+        .map(|(auth_token, _)| auth_token)
+        .ok_or_else(|| SessionError::NotFound.to_boxed_self())?;
+    remove_auth_tokens(session_store, user_id, std::slice::from_ref(&auth_token)).await?;
+    Ok(auth_token)
+}
+
+/// Revoke all sessions of given user, except the one with given auth token if any.
+///
+/// Return revoked auth tokens
+pub async fn revoke_all_user_sessions(
+    session_store: &dyn SessionStore,
+    user_id: Uuid,
+    except_auth_token: Option<&str>,
+) -> Result<Vec<String>, Box<dyn SPTFError>> {
+    let auth_tokens = get_user_sessions(session_store, user_id)
+        .await?
+        .into_iter()
+        .map(|(auth_token, _)| auth_token)
+        .filter(|auth_token| Some(auth_token.as_str()) != except_auth_token)
+        .collect::<Vec<_>>();
+    remove_auth_tokens(session_store, user_id, &auth_tokens).await?;
+    Ok(auth_tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_user_cache, validate_auth_token, ClientInfo, SessionUse};
+    use crate::config::SessionLifetime;
+    use crate::memory_store::MemorySessionStore;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    /// Validate a new session with given use every half second for
+    /// longer than its idle timeout, returning whether it is still valid
+    async fn valid_after_idle_timeout(session_use: SessionUse) -> bool {
+        let session_store = MemorySessionStore::new(None);
+        let client_info = ClientInfo {
+            ip: "127.0.0.1".to_owned(),
+            user_agent: "test".to_owned(),
+        };
+        let lifetime = SessionLifetime {
+            idle_timeout_in_seconds: 2,
+            max_lifetime_in_seconds: 60,
+        };
+        let auth_token = add_user_cache(&session_store, Uuid::new_v4(), &client_info, lifetime)
+            .await
+            .unwrap()
+            .to_string();
+        let mut valid = true;
+        for _ in 0..5 {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            valid = validate_auth_token(&session_store, &auth_token, session_use)
+                .await
+                .is_ok();
+        }
+        valid
+    }
+
+    #[actix_web::test]
+    async fn touching_slides_idle_timeout() {
+        assert!(valid_after_idle_timeout(SessionUse::Touch).await);
+    }
+
+    #[actix_web::test]
+    async fn peeking_leaves_idle_timeout() {
+        assert!(!valid_after_idle_timeout(SessionUse::Peek).await);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[actix_web::test]
+    async fn invite_is_used_only_by_created_users() {
+        use super::{signup_invited_user, signup_user};
+        use crate::auth::Role;
+        use crate::database::Database;
+        use crate::error::{InviteError, SPTFError, SignupError};
+        use crate::sqlite_database::SqliteDatabase;
+        use std::path::Path;
+
+        let database = SqliteDatabase::new(Path::new(":memory:"));
+        database.run_migrations().await.unwrap();
+        signup_user(&database, "taken", "password", Role::Member, None)
+            .await
+            .unwrap();
+        let code = crate::invite::create_invite(
+            &database,
+            Uuid::new_v4(),
+            Some(Role::ReadOnly),
+            Some(10),
+            Some(1),
+        )
         .await
-        .map_err(|err| {
-            error!("Failed to del auth token {}: {}", auth_token_str, err);
-            UnexpectedError.to_boxed_self()
-        })?;
-    Ok(())
+        .unwrap();
+        let invite_uses = || async {
+            database
+                .query_one("SELECT uses FROM InviteCodes", &[])
+                .await
+                .unwrap()
+                .try_get::<i64>(0)
+                .unwrap()
+        };
+
+        // A failed signup leaves the invite unused
+        assert_eq!(
+            signup_invited_user(&database, "taken", "password", &code)
+                .await
+                .err()
+                .unwrap()
+                .error_code(),
+            SignupError::UsernameExist.error_code()
+        );
+        assert_eq!(invite_uses().await, 0);
+
+        let user_id = signup_invited_user(&database, "alice", "password", &code)
+            .await
+            .unwrap();
+        assert!(super::get_role(&database, user_id).await.unwrap() == Role::ReadOnly);
+        assert_eq!(invite_uses().await, 1);
+
+        for (username, code) in [("bob", code.as_str()), ("carol", "unknown")] {
+            assert_eq!(
+                signup_invited_user(&database, username, "password", code)
+                    .await
+                    .err()
+                    .unwrap()
+                    .error_code(),
+                InviteError::Invalid.error_code()
+            );
+        }
+        assert_eq!(invite_uses().await, 1);
+        assert_eq!(
+            database
+                .query_one("SELECT COUNT(*) FROM Users", &[])
+                .await
+                .unwrap()
+                .try_get::<i64>(0)
+                .unwrap(),
+            2
+        );
+    }
 }